@@ -1,8 +1,13 @@
+#[cfg(feature = "lpm")]
+use crate::ral::otg_global_lpm;
 use crate::ral::{
     modify_reg, otg_device, otg_global, otg_global_dieptxfx, otg_pwrclk, read_reg, write_reg,
 };
 use crate::transition::{EndpointConfig, EndpointDescriptor};
+use core::cell::Cell;
+use core::future::poll_fn;
 use core::marker::PhantomData;
+use core::task::Poll;
 use critical_section::{CriticalSection, Mutex};
 use embedded_hal::blocking::delay::DelayMs;
 use usb_device::bus::{PollResult, UsbBusAllocator};
@@ -10,6 +15,7 @@ use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::{Result, UsbDirection, UsbError};
 
 use crate::endpoint::{EndpointIn, EndpointOut};
+pub use crate::endpoint_memory::FifoConfig;
 use crate::endpoint_memory::{EndpointBufferState, EndpointMemoryAllocator};
 use crate::target::UsbRegisters;
 use crate::{PhyType, UsbPeripheral};
@@ -19,18 +25,164 @@ pub struct UsbBus<USB> {
     peripheral: USB,
     regs: Mutex<UsbRegisters>,
     allocator: EndpointAllocator<USB>,
+    dual_role: bool,
+    low_power_suspend: Mutex<Cell<bool>>,
+    /// The clocks were restarted, the resume hook has to be called
+    clocks_restarted: Mutex<Cell<bool>>,
+    pending_test_mode: Mutex<Cell<Option<TestMode>>>,
+    /// Sleep event not reported yet
+    #[cfg(feature = "lpm")]
+    lpm_event: Mutex<Cell<Option<LpmEvent>>>,
+    /// The link resumed from a sleep that was already reported
+    #[cfg(feature = "lpm")]
+    lpm_resumed: Mutex<Cell<bool>>,
+    #[cfg(feature = "lpm")]
+    lpm_sleeping: Mutex<Cell<bool>>,
+    /// `on_interrupt` handles the events, `poll` only reports them
+    interrupt_driven: Mutex<Cell<bool>>,
+    latched: Mutex<Cell<LatchedEvents>>,
+    /// A received packet waits in the Rx FIFO for a full endpoint buffer
+    rx_fifo_blocked: Mutex<Cell<bool>>,
+}
+
+/// Events handled by [`UsbBus::on_interrupt`] that were not reported yet.
+#[derive(Copy, Clone, Default)]
+struct LatchedEvents {
+    reset: bool,
+    suspend: bool,
+    resume: bool,
+    ep_out: u16,
+    ep_in_complete: u16,
+    sof: Option<u16>,
+}
+
+/// USB 2.0 electrical test mode, see section 7.1.20 of the USB 2.0 specification.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TestMode {
+    /// Drives a continuous J state.
+    J,
+    /// Drives a continuous K state.
+    K,
+    /// Stays in high-speed receive mode and NAKs every IN token.
+    Se0Nak,
+    /// Repeatedly transmits the test packet, used for eye-diagram measurements.
+    Packet,
+    /// Enables the downstream port of a hub in high-speed mode.
+    ForceEnable,
+}
+
+impl TestMode {
+    /// Returns the test mode for a test selector, the upper byte of `wIndex` in a
+    /// SET_FEATURE(TEST_MODE) request.
+    pub fn from_selector(selector: u8) -> Option<Self> {
+        match selector {
+            1 => Some(TestMode::J),
+            2 => Some(TestMode::K),
+            3 => Some(TestMode::Se0Nak),
+            4 => Some(TestMode::Packet),
+            5 => Some(TestMode::ForceEnable),
+            _ => None,
+        }
+    }
+
+    /// DCTL.TCTL value
+    fn tctl(self) -> u32 {
+        match self {
+            TestMode::J => 0b001,
+            TestMode::K => 0b010,
+            TestMode::Se0Nak => 0b011,
+            TestMode::Packet => 0b100,
+            TestMode::ForceEnable => 0b101,
+        }
+    }
+}
+
+/// Link Power Management event reported by [`UsbBus::poll_lpm`].
+#[cfg(feature = "lpm")]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LpmEvent {
+    /// The host put the link into the L1 sleep state.
+    Sleep {
+        /// Best effort service latency requested by the host (BESL encoding)
+        besl: u8,
+        /// `true` if the host allows remote wakeup from L1
+        remote_wakeup: bool,
+    },
+    /// The link resumed from the L1 sleep state.
+    Resume,
 }
 
 impl<USB: UsbPeripheral> UsbBus<USB> {
     /// Constructs a new USB peripheral driver.
     pub fn new(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
-        let bus = UsbBus {
+        UsbBusAllocator::new(Self::create(
             peripheral,
-            regs: Mutex::new(UsbRegisters::new::<USB>()),
-            allocator: EndpointAllocator::new(ep_memory),
-        };
+            ep_memory,
+            FifoConfig::default(),
+            false,
+        ))
+    }
+
+    /// Constructs a new USB peripheral driver with an explicit FIFO layout.
+    ///
+    /// The endpoint allocation fails if the endpoints don't fit in the given layout.
+    pub fn new_with_fifo_config(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        fifo_config: FifoConfig,
+    ) -> UsbBusAllocator<Self> {
+        UsbBusAllocator::new(Self::create(peripheral, ep_memory, fifo_config, false))
+    }
+
+    /// Constructs a USB peripheral driver for a core that switches roles with the ID pin.
+    ///
+    /// The core is not forced into device mode, and the driver stays idle while the core is
+    /// in host mode. See [`DualRole`](crate::otg::DualRole) for switching roles.
+    pub fn new_dual_role(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
+        UsbBusAllocator::new(Self::create(
+            peripheral,
+            ep_memory,
+            FifoConfig::default(),
+            true,
+        ))
+    }
 
-        UsbBusAllocator::new(bus)
+    /// Constructs a dual-role USB peripheral driver with an explicit FIFO layout.
+    ///
+    /// See [`new_dual_role`](Self::new_dual_role) and
+    /// [`new_with_fifo_config`](Self::new_with_fifo_config).
+    pub fn new_dual_role_with_fifo_config(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        fifo_config: FifoConfig,
+    ) -> UsbBusAllocator<Self> {
+        UsbBusAllocator::new(Self::create(peripheral, ep_memory, fifo_config, true))
+    }
+
+    pub(crate) fn create(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        fifo_config: FifoConfig,
+        dual_role: bool,
+    ) -> Self {
+        UsbBus {
+            peripheral,
+            regs: Mutex::new(UsbRegisters::new::<USB>()),
+            allocator: EndpointAllocator::new(ep_memory, fifo_config),
+            dual_role,
+            low_power_suspend: Mutex::new(Cell::new(false)),
+            clocks_restarted: Mutex::new(Cell::new(false)),
+            pending_test_mode: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "lpm")]
+            lpm_event: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "lpm")]
+            lpm_resumed: Mutex::new(Cell::new(false)),
+            #[cfg(feature = "lpm")]
+            lpm_sleeping: Mutex::new(Cell::new(false)),
+            interrupt_driven: Mutex::new(Cell::new(false)),
+            latched: Mutex::new(Cell::new(LatchedEvents::default())),
+            rx_fifo_blocked: Mutex::new(Cell::new(false)),
+        }
     }
 
     pub fn free(self) -> USB {
@@ -41,16 +193,7 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
         let regs = self.regs.borrow(cs);
 
         // Rx FIFO
-        // This calculation doesn't correspond to one in a Reference Manual.
-        // In fact, the required number of words is higher than indicated in RM.
-        // The following numbers are pessimistic and were figured out empirically.
-        let rx_fifo_size = if USB::HIGH_SPEED {
-            self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
-        } else {
-            // F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
-            // F446 requires 39+ words for the same setup
-            self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
-        };
+        let rx_fifo_size = self.allocator.memory_allocator.rx_fifo_size_words();
         write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size as u32);
         let mut fifo_top = rx_fifo_size;
 
@@ -80,54 +223,1014 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
                 INEPTXSA: fifo_top as u32
             );
 
-            fifo_top += fifo_size;
+            fifo_top += fifo_size;
+        }
+
+        // The layout was checked when the endpoints were allocated
+        let reserved = self.allocator.memory_allocator.reserved_words();
+        debug_assert!(fifo_top as usize + reserved as usize <= USB::FIFO_DEPTH_WORDS);
+
+        // Flush Rx & Tx FIFOs
+        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+
+        for ep in &self.allocator.endpoints_in {
+            if let Some(ep) = ep {
+                // enabling EP TX interrupt
+                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v
+                    | (0x0001 << ep.address().index()));
+
+                ep.configure(cs);
+            }
+        }
+
+        for ep in &self.allocator.endpoints_out {
+            if let Some(ep) = ep {
+                if ep.address().index() == 0 {
+                    // enabling RX interrupt from EP0
+                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | 0x00010000);
+                } else if cfg!(feature = "dma") {
+                    // DMA transfer completion is signaled by the endpoint interrupt
+                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v
+                        | (0x00010000 << ep.address().index()));
+                }
+
+                ep.configure(cs);
+            }
+        }
+    }
+
+    fn deconfigure_all(&self, cs: CriticalSection<'_>) {
+        let regs = self.regs.borrow(cs);
+
+        // disable interrupts
+        modify_reg!(otg_device, regs.device(), DAINTMSK, IEPM: 0, OEPM: 0);
+
+        for ep in &self.allocator.endpoints_in {
+            if let Some(ep) = ep {
+                ep.deconfigure(cs);
+            }
+        }
+
+        for ep in &self.allocator.endpoints_out {
+            if let Some(ep) = ep {
+                ep.deconfigure(cs);
+            }
+        }
+    }
+
+    /// Re-initializes the core in device mode and connects to the host.
+    ///
+    /// This is needed after the core was used as a host by a dual-role application. The device
+    /// state is reset once the host resets the bus.
+    pub fn activate(&self) {
+        self.init();
+    }
+
+    /// Disconnects from the host and disables the endpoints, before the core is used as a host
+    /// by a dual-role application.
+    pub fn deactivate(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // Soft disconnect device
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+            self.deconfigure_all(cs);
+
+            // Only keep watching the connector ID and OTG events
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                CIDSCHGM: self.dual_role as u32,
+                OTGINT: self.dual_role as u32,
+                SRQIM: self.dual_role as u32
+            );
+        });
+    }
+
+    /// Enables stopping the PHY clock and gating the AHB clock of the core while the bus is
+    /// suspended.
+    ///
+    /// The clocks are restarted on resume, reset, or before remote wakeup signaling. The
+    /// [`UsbPeripheral::suspend_low_power`] and [`UsbPeripheral::resume_low_power`] hooks let the
+    /// HAL put the MCU into a low-power mode in between.
+    pub fn set_low_power_suspend(&self, enabled: bool) {
+        critical_section::with(|cs| self.low_power_suspend.borrow(cs).set(enabled));
+    }
+
+    /// Stops the PHY clock, then gates the AHB clock of the core.
+    ///
+    /// The [`UsbPeripheral::suspend_low_power`] hook must be called outside of the critical
+    /// section.
+    fn enter_low_power(&self, regs: UsbRegisters) {
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 1);
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 1);
+    }
+
+    /// Restarts the clocks stopped by a low-power suspend.
+    ///
+    /// The [`UsbPeripheral::resume_low_power`] hook is called by
+    /// [`finish_resume`](Self::finish_resume) outside of the critical section.
+    fn exit_low_power(&self, cs: CriticalSection<'_>, regs: UsbRegisters) {
+        if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK) != 0 {
+            self.clocks_restarted.borrow(cs).set(true);
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 0);
+        }
+    }
+
+    /// Calls the [`UsbPeripheral::resume_low_power`] hook if the clocks were restarted.
+    fn finish_resume(&self) {
+        let restarted =
+            critical_section::with(|cs| self.clocks_restarted.borrow(cs).replace(false));
+        if restarted {
+            self.peripheral.resume_low_power();
+        }
+    }
+
+    /// Configures or deconfigures a single endpoint, for stacks that enable the endpoints of a
+    /// configuration after SET_CONFIGURATION.
+    #[cfg(feature = "embassy")]
+    pub(crate) fn set_endpoint_enabled(&self, ep_addr: EndpointAddress, enabled: bool) {
+        if ep_addr.index() == 0 || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return;
+        }
+
+        critical_section::with(|cs| match ep_addr.direction() {
+            UsbDirection::In => {
+                if let Some(ep) = &self.allocator.endpoints_in[ep_addr.index()] {
+                    if enabled {
+                        ep.configure(cs);
+                    } else {
+                        ep.deconfigure(cs);
+                    }
+                }
+            }
+            UsbDirection::Out => {
+                if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
+                    if enabled {
+                        ep.configure(cs);
+                    } else {
+                        ep.deconfigure(cs);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns `true` if the next packet of EP0 OUT is a SETUP packet.
+    #[cfg(feature = "embassy")]
+    pub(crate) fn setup_pending(&self) -> bool {
+        matches!(&self.allocator.endpoints_out[0], Some(ep) if ep.buffer_state() == EndpointBufferState::DataSetup)
+    }
+
+    /// Handles the pending interrupts of the core and returns the resulting event.
+    fn handle_events(&self, cs: CriticalSection<'_>) -> PollResult {
+        let regs = self.regs.borrow(cs);
+
+        // The core registers are not accessible while the AHB clock is gated
+        let gated = read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK) != 0;
+        if gated {
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+        }
+
+        if self.in_host_mode(*regs) {
+            // The suspend of the device ended with the role switch
+            if gated {
+                self.exit_low_power(cs, *regs);
+            }
+            return PollResult::None;
+        }
+
+        let core_id = read_reg!(otg_global, regs.global(), CID);
+
+        let (wakeup, suspend, enum_done, reset, iep, rxflvl, _oep) = read_reg!(
+            otg_global,
+            regs.global(),
+            GINTSTS,
+            WKUPINT,
+            USBSUSP,
+            ENUMDNE,
+            USBRST,
+            IEPINT,
+            RXFLVL,
+            OEPINT
+        );
+
+        // Leave the low-power suspend when the host resumes or resets the bus
+        if wakeup != 0 || reset != 0 {
+            self.exit_low_power(cs, *regs);
+        } else if gated {
+            // Other pending interrupts are served with the clocks running, they would be raised
+            // again as soon as the interrupt handler returns otherwise
+            let pending = read_reg!(otg_global, regs.global(), GINTSTS)
+                & read_reg!(otg_global, regs.global(), GINTMSK);
+            if pending == 0 {
+                modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 1);
+                return PollResult::None;
+            }
+            self.exit_low_power(cs, *regs);
+        }
+
+        #[cfg(feature = "lpm")]
+        if read_reg!(otg_global_lpm, regs.global(), GINTSTS, LPMINT) != 0 {
+            write_reg!(otg_global_lpm, regs.global(), GINTSTS, LPMINT: 1);
+
+            let (besl, remote_wakeup, sleeping) = read_reg!(
+                otg_global_lpm,
+                regs.global_lpm(),
+                GLPMCFG,
+                BESL,
+                REMWAKE,
+                SLPSTS
+            );
+            if sleeping != 0 {
+                self.lpm_sleeping.borrow(cs).set(true);
+                self.lpm_event.borrow(cs).set(Some(LpmEvent::Sleep {
+                    besl: besl as u8,
+                    remote_wakeup: remote_wakeup != 0,
+                }));
+            }
+        }
+
+        if reset != 0 {
+            write_reg!(otg_global, regs.global(), GINTSTS, USBRST: 1);
+
+            self.pending_test_mode.borrow(cs).set(None);
+            #[cfg(feature = "lpm")]
+            self.lpm_sleeping.borrow(cs).set(false);
+
+            self.deconfigure_all(cs);
+
+            // Flush RX
+            modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
+            while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 1 {}
+        }
+
+        if enum_done != 0 {
+            write_reg!(otg_global, regs.global(), GINTSTS, ENUMDNE: 1);
+
+            let speed = read_reg!(otg_device, regs.device(), DSTS, ENUMSPD);
+
+            // Compute and update TRDT
+            let trdt;
+            match speed {
+                0b00 => {
+                    // High speed
+
+                    // From RM0431 (F72xx), RM0090 (F429), RM0390 (F446)
+                    if self.peripheral.ahb_frequency_hz() >= 30_000_000 {
+                        trdt = 0x9;
+                    } else {
+                        panic!("AHB frequency is too low")
+                    }
+                }
+                0b01 | 0b11 => {
+                    // Full speed
+
+                    // From RM0431 (F72xx), RM0090 (F429)
+                    trdt = match self.peripheral.ahb_frequency_hz() {
+                        0..=14_199_999 => panic!("AHB frequency is too low"),
+                        14_200_000..=14_999_999 => 0xF,
+                        15_000_000..=15_999_999 => 0xE,
+                        16_000_000..=17_199_999 => 0xD,
+                        17_200_000..=18_499_999 => 0xC,
+                        18_500_000..=19_999_999 => 0xB,
+                        20_000_000..=21_799_999 => 0xA,
+                        21_800_000..=23_999_999 => 0x9,
+                        24_000_000..=27_499_999 => 0x8,
+                        27_500_000..=31_999_999 => 0x7, // 27.7..32 in code from CubeIDE
+                        32_000_000..=u32::MAX => 0x6,
+                    };
+                }
+                _ => unimplemented!(),
+            }
+            modify_reg!(otg_global, regs.global(), GUSBCFG, TRDT: trdt);
+
+            PollResult::Reset
+        } else if wakeup != 0 {
+            // Clear the interrupt
+            write_reg!(otg_global, regs.global(), GINTSTS, WKUPINT: 1);
+
+            #[cfg(feature = "lpm")]
+            if self.lpm_sleeping.borrow(cs).replace(false) {
+                // A sleep that was not reported yet is dropped with its resume
+                if self.lpm_event.borrow(cs).take().is_none() {
+                    self.lpm_resumed.borrow(cs).set(true);
+                }
+            }
+
+            PollResult::Resume
+        } else if suspend != 0 {
+            write_reg!(otg_global, regs.global(), GINTSTS, USBSUSP: 1);
+
+            PollResult::Suspend
+        } else {
+            let mut ep_out = 0;
+            let mut ep_in_complete = 0;
+            let mut ep_setup = 0;
+
+            use crate::ral::{endpoint_in, endpoint_out};
+
+            // RXFLVL & IEPINT flags are read-only, there is no need to clear them
+            if rxflvl != 0 {
+                let (epnum, data_size, status) =
+                    read_reg!(otg_global, regs.global(), GRXSTSR, EPNUM, BCNT, PKTSTS);
+                let transfer = status == 0x02
+                    && matches!(&self.allocator.endpoints_out[epnum as usize], Some(ep) if ep.transfer_armed(cs));
+                match status {
+                    0x02 if transfer => {
+                        // OUT received as part of a multi-packet transfer
+                        read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+
+                        // The endpoint stays enabled until the transfer completes
+                        if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
+                            ep.fill_transfer_from_fifo(cs, *regs, data_size as u16);
+                        }
+                    }
+                    0x02 => {
+                        // OUT received
+                        ep_out |= 1 << epnum;
+                    }
+                    0x06 => {
+                        // SETUP received
+                        // flushing TX if something stuck in control endpoint
+                        let ep = regs.endpoint_in(epnum as usize);
+                        if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
+                            modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
+                            while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
+                        }
+                        ep_setup |= 1 << epnum;
+                    }
+                    0x03 | 0x04 => {
+                        // OUT completed | SETUP completed
+                        let ep = &self.allocator.endpoints_out[epnum as usize];
+                        if status == 0x03 && matches!(ep, Some(ep) if ep.complete_transfer(cs)) {
+                            // The multi-packet transfer is reported once
+                            ep_out |= 1 << epnum;
+                        } else if core_id == 0x0000_1200 || core_id == 0x0000_1100 {
+                            // Re-enable the endpoint, F429-like chips only
+                            if let Some(ep) = ep {
+                                ep.enable_next_packet(cs);
+                            } else {
+                                let ep = regs.endpoint_out(epnum as usize);
+                                modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
+                            }
+                        }
+                        read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                    }
+                    _ => {
+                        read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                    }
+                }
+
+                if (status == 0x02 && !transfer) || status == 0x06 {
+                    if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
+                        // The packet waits in the Rx FIFO while all the slots are full
+                        let mut buffer = ep.buffer.borrow_ref_mut(cs);
+                        if !buffer.is_full() {
+                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+
+                            let is_setup = status == 0x06;
+                            buffer
+                                .fill_from_fifo(*regs, data_size as u16, is_setup)
+                                .ok();
+                            drop(buffer);
+
+                            // Re-enable the endpoint, F446-like chips only
+                            if core_id == 0x0000_2000
+                                || core_id == 0x0000_2100
+                                || core_id == 0x0000_2300
+                                || core_id == 0x0000_3000
+                                || core_id == 0x0000_3100
+                            {
+                                ep.enable_next_packet(cs);
+                            }
+                        } else {
+                            self.rx_fifo_blocked.borrow(cs).set(true);
+                        }
+                    }
+                }
+            }
+
+            // Isochronous transactions that missed their (micro)frame
+            let (iso_in, iso_dropped) =
+                read_reg!(otg_global, regs.global(), GINTSTS, IISOIXFR, ISOODRP);
+            #[cfg(feature = "fs")]
+            let iso_out = read_reg!(otg_global, regs.global(), GINTSTS, IPXFR_INCOMPISOOUT);
+            #[cfg(feature = "hs")]
+            let iso_out = read_reg!(otg_global, regs.global(), GINTSTS, PXFR_INCOMPISOOUT);
+
+            if iso_in != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, IISOIXFR: 1);
+
+                // The dropped packet is reported as sent, so the next one can be written
+                for ep in self.allocator.endpoints_in.iter().flatten() {
+                    if ep.handle_incomplete_isochronous(cs) {
+                        ep_in_complete |= 1 << ep.address().index();
+                    }
+                }
+            }
+            if iso_out != 0 {
+                #[cfg(feature = "fs")]
+                write_reg!(otg_global, regs.global(), GINTSTS, IPXFR_INCOMPISOOUT: 1);
+                #[cfg(feature = "hs")]
+                write_reg!(otg_global, regs.global(), GINTSTS, PXFR_INCOMPISOOUT: 1);
+
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    ep.handle_incomplete_isochronous();
+                }
+            }
+            if iso_dropped != 0 {
+                // Lack of Rx FIFO space, the packet is lost
+                write_reg!(otg_global, regs.global(), GINTSTS, ISOODRP: 1);
+            }
+
+            if iep != 0 {
+                let empty_mask = read_reg!(otg_device, regs.device(), DIEPEMPMSK);
+                for ep in self.allocator.endpoints_in.iter().flatten() {
+                    let ep_regs = regs.endpoint_in(ep.address().index());
+                    if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
+                        write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
+                        if ep.handle_transfer_complete(cs) {
+                            ep_in_complete |= 1 << ep.address().index();
+                        }
+
+                        // The status stage of SET_FEATURE(TEST_MODE) is sent
+                        if ep.address().index() == 0 {
+                            if let Some(mode) = self.pending_test_mode.borrow(cs).take() {
+                                modify_reg!(otg_device, regs.device(), DCTL, TCTL: mode.tctl());
+                            }
+                        }
+                    }
+                    // TXFE is read-only, it is masked once the transfer is in the FIFO
+                    if empty_mask & (1 << ep.address().index()) != 0
+                        && read_reg!(endpoint_in, ep_regs, DIEPINT, TXFE) != 0
+                    {
+                        ep.handle_txfifo_empty(cs);
+                    }
+                }
+            }
+
+            // OEPINT is read-only, the endpoint interrupts are cleared by complete_dma()
+            #[cfg(feature = "dma")]
+            if _oep != 0 {
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    if ep.complete_dma(cs) {
+                        // The multi-packet transfer is reported once
+                        ep_out |= 1 << ep.address().index();
+                    }
+                }
+            }
+
+            let (buffered_out, buffered_setup) = self.buffered_packets();
+            ep_out |= buffered_out;
+            ep_setup |= buffered_setup;
+
+            // Wake the tasks waiting for the endpoints
+            for ep in self.allocator.endpoints_in.iter().flatten() {
+                if ep_in_complete & (1 << ep.address().index()) != 0 {
+                    ep.wake();
+                }
+            }
+            for ep in self.allocator.endpoints_out.iter().flatten() {
+                if (ep_out | ep_setup) & (1 << ep.address().index()) != 0 {
+                    ep.wake();
+                }
+            }
+
+            if (ep_in_complete | ep_out | ep_setup) != 0 {
+                PollResult::Data {
+                    ep_out,
+                    ep_in_complete,
+                    ep_setup,
+                }
+            } else {
+                PollResult::None
+            }
+        }
+    }
+
+    /// Returns the endpoints with a packet in their buffer, as OUT and SETUP masks.
+    fn buffered_packets(&self) -> (u16, u16) {
+        let mut ep_out = 0;
+        let mut ep_setup = 0;
+        for ep in self.allocator.endpoints_out.iter().flatten() {
+            match ep.buffer_state() {
+                EndpointBufferState::DataOut => {
+                    ep_out |= 1 << ep.address().index();
+                }
+                EndpointBufferState::DataSetup => {
+                    ep_setup |= 1 << ep.address().index();
+                }
+                EndpointBufferState::Empty => {}
+            }
+        }
+        (ep_out, ep_setup)
+    }
+
+    /// Handles the OTG interrupt, to be called from its handler.
+    ///
+    /// The Rx FIFO is drained into the endpoint buffers and the events are latched until they
+    /// are reported by `poll`, which then no longer accesses the core. The Rx FIFO interrupt is
+    /// masked while a packet waits for a full endpoint buffer, until the buffer is read. In
+    /// dual-role applications, [`DualRole::poll`](crate::otg::DualRole::poll) must be called
+    /// from the handler too.
+    pub fn on_interrupt(&self) {
+        critical_section::with(|cs| {
+            self.interrupt_driven.borrow(cs).set(true);
+
+            let result = self.handle_events(cs);
+            let latched = self.latched.borrow(cs);
+            let mut events = latched.get();
+            match result {
+                PollResult::None => {}
+                PollResult::Reset => {
+                    events = LatchedEvents {
+                        reset: true,
+                        ..LatchedEvents::default()
+                    };
+                }
+                PollResult::Suspend => events.suspend = true,
+                PollResult::Resume => {
+                    // A suspend that was not reported yet is dropped with its resume
+                    events.resume = !events.suspend;
+                    events.suspend = false;
+                }
+                PollResult::Data {
+                    ep_out,
+                    ep_in_complete,
+                    ..
+                } => {
+                    events.ep_out |= ep_out;
+                    events.ep_in_complete |= ep_in_complete;
+                    events.suspend = false;
+                }
+            }
+
+            let regs = self.regs.borrow(cs);
+            // The core stayed in low-power suspend, its registers are not accessible
+            let gated = read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK) != 0;
+            let (sof, sof_enabled) = if gated {
+                (0, 0)
+            } else {
+                (
+                    read_reg!(otg_global, regs.global(), GINTSTS, SOF),
+                    read_reg!(otg_global, regs.global(), GINTMSK, SOFM),
+                )
+            };
+            if sof != 0 && sof_enabled != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+                events.sof = Some(read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16);
+            }
+            latched.set(events);
+
+            if !gated && self.rx_fifo_blocked.borrow(cs).replace(false) {
+                modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: 0);
+            }
+        });
+        self.finish_resume();
+    }
+
+    /// Reports the events latched by `on_interrupt`, in the order reset, resume, data, suspend.
+    fn take_latched_events(&self, cs: CriticalSection<'_>) -> PollResult {
+        let latched = self.latched.borrow(cs);
+        let mut events = latched.get();
+        // Packets are reported as long as they are in the buffers
+        let (buffered_out, ep_setup) = self.buffered_packets();
+        let ep_out = events.ep_out | buffered_out;
+
+        let result = if events.reset {
+            events.reset = false;
+            PollResult::Reset
+        } else if events.resume {
+            events.resume = false;
+            PollResult::Resume
+        } else if (ep_out | events.ep_in_complete | ep_setup) != 0 {
+            let ep_in_complete = events.ep_in_complete;
+            events.ep_out = 0;
+            events.ep_in_complete = 0;
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        } else if events.suspend {
+            events.suspend = false;
+            PollResult::Suspend
+        } else {
+            PollResult::None
+        };
+        latched.set(events);
+        result
+    }
+
+    /// Unmasks the Rx FIFO interrupt masked by `on_interrupt` once an endpoint buffer is freed.
+    fn unblock_rx_fifo(&self) {
+        if cfg!(feature = "dma") {
+            return;
+        }
+        critical_section::with(|cs| {
+            if self.interrupt_driven.borrow(cs).get() {
+                let regs = self.regs.borrow(cs);
+                modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: 1);
+            }
+        });
+    }
+
+    /// Returns `true` if a dual-role core is currently in host mode.
+    fn in_host_mode(&self, regs: UsbRegisters) -> bool {
+        self.dual_role && read_reg!(otg_global, regs.global(), GINTSTS, CMOD) != 0
+    }
+
+    fn init(&self) {
+        // Enable USB_OTG in RCC
+        USB::enable();
+
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let core_id = read_reg!(otg_global, regs.global(), CID);
+
+            // Wait for AHB ready
+            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+
+            // Configure OTG as device, unless the role is selected by the ID pin
+            let force_device = !self.dual_role as u32;
+            let srp_capable = if self.dual_role {
+                read_reg!(otg_global, regs.global(), GUSBCFG, SRPCAP)
+            } else {
+                0
+            };
+            #[cfg(feature = "fs")]
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                SRPCAP: srp_capable, // Set up by dual-role applications only
+                FHMOD: 0,
+                FDMOD: force_device
+            );
+            #[cfg(feature = "hs")]
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                SRPCAP: srp_capable, // Set up by dual-role applications only
+                TOCAL: 0x1,
+                FHMOD: 0,
+                FDMOD: force_device
+            );
+
+            // Configure USB PHY
+            crate::target::init_phy(&self.peripheral, *regs);
+
+            // Configuring Vbus sense and SOF output
+            match core_id {
+                0x0000_1200 | 0x0000_1100 => {
+                    // F429-like chips have the GCCFG.NOVBUSSENS bit
+
+                    //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
+                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+
+                    modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
+                }
+                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
+                    // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
+
+                    //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
+                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                    // Force B-peripheral session, drop a forced A-device session
+                    //modify_reg!(otg_global, regs.global, GOTGCTL, AVALOEN: 0, BVALOEN: 1, BVALOVAL: 1);
+                    modify_reg!(otg_global, regs.global(), GOTGCTL, |r| (r & !(0b11 << 4))
+                        | (0b11 << 6));
+                }
+                _ => {}
+            }
+
+            // Enable PHY clock
+            write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+            // Soft disconnect device
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+            // Setup USB speed and frame interval
+            let speed = match (USB::HIGH_SPEED, self.peripheral.phy_type()) {
+                (false, _) => 0b11,
+                (true, PhyType::InternalFullSpeed) => 0b11,
+                (true, PhyType::InternalHighSpeed) => 0b00,
+                (true, PhyType::ExternalHighSpeed) => 0b00,
+            };
+            modify_reg!(otg_device, regs.device(), DCFG,
+                PFIVL: 0b00,
+                DSPD: speed
+            );
+            #[cfg(feature = "xcvrdly")]
+            modify_reg!(otg_device, regs.device(), DCFG, XCVRDLY: 1);
+
+            // unmask EP interrupts
+            write_reg!(otg_device, regs.device(), DIEPMSK, XFRCM: 1);
+            #[cfg(feature = "dma")]
+            write_reg!(otg_device, regs.device(), DOEPMSK, XFRCM: 1, STUPM: 1);
+
+            // The core moves received packets out of the Rx FIFO by itself in DMA mode
+            #[cfg(feature = "dma")]
+            modify_reg!(otg_global, regs.global(), GAHBCFG, HBSTLEN: 0b0011, DMAEN: 1); // INCR4
+
+            // unmask core interrupts
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                USBRST: 1, ENUMDNEM: 1,
+                USBSUSPM: 1, WUIM: 1,
+                IEPINT: 1,
+                RXFLVLM: !cfg!(feature = "dma") as u32,
+                OEPINT: cfg!(feature = "dma") as u32,
+                CIDSCHGM: self.dual_role as u32,
+                OTGINT: self.dual_role as u32,
+                SRQIM: self.dual_role as u32
+            );
+
+            // Acknowledge LPM tokens on cores that support them
+            #[cfg(feature = "lpm")]
+            if core_id >= 0x0000_2000 {
+                modify_reg!(otg_global_lpm, regs.global_lpm(), GLPMCFG,
+                    LPMEN: 1,
+                    LPMACK: 1,
+                    L1SSEN: 1
+                );
+                modify_reg!(otg_global_lpm, regs.global(), GINTMSK, LPMINTM: 1);
+            }
+
+            // unmask incomplete isochronous transfer interrupts
+            #[cfg(feature = "fs")]
+            modify_reg!(otg_global, regs.global(), GINTMSK, IISOIXFRM: 1, IPXFRM_IISOOXFRM: 1);
+            #[cfg(feature = "hs")]
+            modify_reg!(otg_global, regs.global(), GINTMSK, IISOIXFRM: 1, PXFRM_IISOOXFRM: 1);
+
+            // clear pending interrupts
+            write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+
+            // unmask global interrupt
+            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+            // connect(true)
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
+        });
+    }
+
+    /// Starts a multi-packet transfer on an IN endpoint.
+    ///
+    /// Unlike [`write`](usb_device::bus::UsbBus::write), `data` can be larger than the max packet
+    /// size: the core splits it into packets, and the driver refills the Tx FIFO from
+    /// [`poll`](usb_device::bus::UsbBus::poll) as space frees up. If `zlp` is set and the size of
+    /// `data` is a multiple of the max packet size, the transfer is terminated by a zero-length
+    /// packet. The transfer completion is reported once, as for a single packet.
+    ///
+    /// Returns `WouldBlock` while the previous transfer of the endpoint is in progress.
+    /// Multi-packet transfers are not supported on EP0. With the `dma` feature, `data` must be
+    /// word-aligned.
+    pub fn write_transfer(
+        &self,
+        ep_addr: EndpointAddress,
+        data: &'static [u8],
+        zlp: bool,
+    ) -> Result<()> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if let Some(ep) = &self.allocator.endpoints_in[ep_addr.index()] {
+            ep.write_transfer(data, zlp)
+        } else {
+            Err(UsbError::InvalidEndpoint)
+        }
+    }
+
+    /// Arms an OUT endpoint for a multi-packet transfer into `buf`.
+    ///
+    /// The core receives packets into `buf` without CPU round trips through
+    /// [`poll`](usb_device::bus::UsbBus::poll) for each packet. The transfer completes when `buf`
+    /// is full (its size is rounded down to a multiple of the max packet size) or on a short
+    /// packet, which is reported once by `poll` as an OUT event for the endpoint. The buffer and
+    /// the number of bytes received are then returned by [`take_transfer`](Self::take_transfer).
+    ///
+    /// Multi-packet transfers are not supported on EP0. With the `dma` feature, `buf` must be
+    /// word-aligned. On error, `buf` is handed back.
+    pub fn read_transfer(
+        &self,
+        ep_addr: EndpointAddress,
+        buf: &'static mut [u8],
+    ) -> core::result::Result<(), (UsbError, &'static mut [u8])> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err((UsbError::InvalidEndpoint, buf));
+        }
+        match &self.allocator.endpoints_out[ep_addr.index()] {
+            Some(ep) => ep.read_transfer(buf),
+            None => Err((UsbError::InvalidEndpoint, buf)),
+        }
+    }
+
+    /// Returns the buffer of a completed multi-packet OUT transfer and the number of bytes
+    /// received.
+    ///
+    /// Returns `WouldBlock` while the transfer is in progress, and `InvalidState` if no transfer
+    /// was started with [`read_transfer`](Self::read_transfer).
+    pub fn take_transfer(&self, ep_addr: EndpointAddress) -> Result<(&'static mut [u8], usize)> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
+            ep.take_transfer()
+        } else {
+            Err(UsbError::InvalidEndpoint)
+        }
+    }
+
+    /// Writes a packet to an IN endpoint once it can accept it.
+    ///
+    /// The future is woken when the endpoint completes a transfer, from
+    /// [`on_interrupt`](Self::on_interrupt) or `poll`, which must keep being called.
+    pub async fn write_packet(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
         }
+        let ep = match &self.allocator.endpoints_in[ep_addr.index()] {
+            Some(ep) => ep,
+            None => return Err(UsbError::InvalidEndpoint),
+        };
 
-        assert!(fifo_top as usize <= USB::FIFO_DEPTH_WORDS);
+        poll_fn(|cx| {
+            ep.register_waker(cx.waker());
+            match usb_device::bus::UsbBus::write(self, ep_addr, buf) {
+                Err(UsbError::WouldBlock) => Poll::Pending,
+                result => Poll::Ready(result),
+            }
+        })
+        .await
+    }
 
-        // Flush Rx & Tx FIFOs
-        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
-        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+    /// Reads a packet from an OUT endpoint once it is received.
+    ///
+    /// The future is woken when the endpoint receives a packet, from
+    /// [`on_interrupt`](Self::on_interrupt) or `poll`, which must keep being called.
+    pub async fn read_packet(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let ep = match &self.allocator.endpoints_out[ep_addr.index()] {
+            Some(ep) => ep,
+            None => return Err(UsbError::InvalidEndpoint),
+        };
 
-        for ep in &self.allocator.endpoints_in {
-            if let Some(ep) = ep {
-                // enabling EP TX interrupt
-                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v
-                    | (0x0001 << ep.address().index()));
+        poll_fn(|cx| {
+            ep.register_waker(cx.waker());
+            match usb_device::bus::UsbBus::read(self, ep_addr, buf) {
+                Err(UsbError::WouldBlock) => Poll::Pending,
+                result => Poll::Ready(result),
+            }
+        })
+        .await
+    }
 
-                ep.configure(cs);
+    /// Returns the number of the current (micro)frame, from the last SOF token received.
+    pub fn frame_number(&self) -> u16 {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16
+        })
+    }
+
+    /// Enables or disables the start-of-frame interrupt.
+    ///
+    /// The interrupt fires every frame (1 kHz) or microframe (8 kHz), so it should only be
+    /// enabled while a class needs per-frame events. While enabled, the interrupt handler must
+    /// call [`poll_sof`](Self::poll_sof) or [`on_interrupt`](Self::on_interrupt) to acknowledge
+    /// it.
+    pub fn set_sof_interrupt(&self, enabled: bool) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            if enabled {
+                // Drop a start-of-frame received while the interrupt was disabled
+                write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
             }
-        }
+            modify_reg!(otg_global, regs.global(), GINTMSK, SOFM: enabled as u32);
+        });
+    }
 
-        for ep in &self.allocator.endpoints_out {
-            if let Some(ep) = ep {
-                if ep.address().index() == 0 {
-                    // enabling RX interrupt from EP0
-                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | 0x00010000);
-                }
+    /// Returns the current frame number if a start-of-frame token was received since the last
+    /// call, while the interrupt is enabled with [`set_sof_interrupt`](Self::set_sof_interrupt).
+    pub fn poll_sof(&self) -> Option<u16> {
+        critical_section::with(|cs| {
+            let latched = self.latched.borrow(cs);
+            let mut events = latched.get();
+            if let Some(frame) = events.sof.take() {
+                latched.set(events);
+                return Some(frame);
+            }
 
-                ep.configure(cs);
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK) != 0
+                || self.in_host_mode(*regs)
+                || read_reg!(otg_global, regs.global(), GINTMSK, SOFM) == 0
+                || read_reg!(otg_global, regs.global(), GINTSTS, SOF) == 0
+            {
+                return None;
             }
-        }
+
+            write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+            Some(read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16)
+        })
     }
 
-    fn deconfigure_all(&self, cs: CriticalSection<'_>) {
-        let regs = self.regs.borrow(cs);
+    /// Signals remote wakeup to a host that suspended the bus.
+    ///
+    /// Resume signaling is driven for 10 ms, within the 1-15 ms mandated by the specification.
+    /// Returns `InvalidState` if the bus is not suspended.
+    ///
+    /// The host enables the feature with SET_FEATURE(DEVICE_REMOTE_WAKEUP), which is handled by
+    /// `usb-device` and not seen by the driver. Callers must check
+    /// `UsbDevice::remote_wakeup_enabled` first, a device must not signal remote wakeup
+    /// otherwise.
+    pub fn remote_wakeup(&self, delay: &mut impl DelayMs<u32>) -> Result<()> {
+        self.start_remote_wakeup()?;
+        delay.delay_ms(10);
+        self.stop_remote_wakeup();
+        Ok(())
+    }
 
-        // disable interrupts
-        modify_reg!(otg_device, regs.device(), DAINTMSK, IEPM: 0, OEPM: 0);
+    /// Starts remote wakeup signaling, for applications that time it with their own timer.
+    ///
+    /// [`stop_remote_wakeup`](Self::stop_remote_wakeup) must be called 1 to 15 ms later.
+    /// Returns `InvalidState` if the bus is not suspended. As with
+    /// [`remote_wakeup`](Self::remote_wakeup), callers must check that the host enabled remote
+    /// wakeup with `UsbDevice::remote_wakeup_enabled`.
+    pub fn start_remote_wakeup(&self) -> Result<()> {
+        let result = critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
 
-        for ep in &self.allocator.endpoints_in {
-            if let Some(ep) = ep {
-                ep.deconfigure(cs);
+            // The core registers are not accessible while the AHB clock is gated
+            let low_power = read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK) != 0;
+            self.exit_low_power(cs, *regs);
+
+            let suspended = read_reg!(otg_device, regs.device(), DSTS, SUSPSTS) != 0;
+            // The core times the shorter remote wakeup signaling from L1 by itself
+            #[cfg(feature = "lpm")]
+            let suspended = suspended || self.lpm_sleeping.borrow(cs).get();
+            if !suspended {
+                // The HAL did not resume, the clocks are stopped again without calling the hooks
+                if low_power {
+                    self.enter_low_power(*regs);
+                    self.clocks_restarted.borrow(cs).set(false);
+                }
+                return Err(UsbError::InvalidState);
             }
-        }
 
-        for ep in &self.allocator.endpoints_out {
-            if let Some(ep) = ep {
-                ep.deconfigure(cs);
+            modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 1);
+            Ok(())
+        });
+
+        self.finish_resume();
+        result
+    }
+
+    /// Stops remote wakeup signaling, the host then drives resume signaling.
+    pub fn stop_remote_wakeup(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 0);
+        });
+    }
+
+    /// Returns the next Link Power Management event.
+    ///
+    /// L1 sleep is distinct from the classic suspend reported by
+    /// [`poll`](usb_device::bus::UsbBus::poll): the host resumes the link within microseconds,
+    /// the device state is kept and only a short remote wakeup is allowed.
+    ///
+    /// [`LpmEvent::Resume`] is only reported for a [`LpmEvent::Sleep`] that was returned before,
+    /// both events are dropped if the link resumed before the sleep was polled.
+    #[cfg(feature = "lpm")]
+    pub fn poll_lpm(&self) -> Option<LpmEvent> {
+        critical_section::with(|cs| {
+            // The resume of the previous sleep comes before the next sleep
+            if self.lpm_resumed.borrow(cs).replace(false) {
+                Some(LpmEvent::Resume)
+            } else {
+                self.lpm_event.borrow(cs).take()
             }
-        }
+        })
+    }
+
+    /// Enters an electrical test mode immediately.
+    ///
+    /// The core generates the standard test packet of [`TestMode::Packet`] by itself. Test modes
+    /// are only left by a power cycle or a reset of the core.
+    pub fn set_test_mode(&self, mode: TestMode) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device(), DCTL, TCTL: mode.tctl());
+        });
+    }
+
+    /// Enters an electrical test mode once the status stage of the current control transfer
+    /// completed.
+    ///
+    /// This is meant to be called by the class that accepts the SET_FEATURE(TEST_MODE) request,
+    /// as the specification requires the device to enter the test mode after the status stage.
+    pub fn set_test_mode_after_status(&self, mode: TestMode) {
+        critical_section::with(|cs| self.pending_test_mode.borrow(cs).set(Some(mode)));
     }
 
     pub fn force_reset(&self, delay: &mut impl DelayMs<u32>) -> Result<()> {
@@ -243,7 +1346,7 @@ pub(crate) struct EndpointAllocator<USB> {
 }
 
 impl<USB: UsbPeripheral> EndpointAllocator<USB> {
-    fn new(memory: &'static mut [u32]) -> Self {
+    fn new(memory: &'static mut [u32], fifo_config: FifoConfig) -> Self {
         assert!(USB::ENDPOINT_COUNT <= 9);
         Self {
             bitmap_in: 0,
@@ -251,7 +1354,7 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
             // [None; 9] requires Copy
             endpoints_in: [None, None, None, None, None, None, None, None, None],
             endpoints_out: [None, None, None, None, None, None, None, None, None],
-            memory_allocator: EndpointMemoryAllocator::new(memory),
+            memory_allocator: EndpointMemoryAllocator::new(memory, fifo_config),
             _marker: PhantomData,
         }
     }
@@ -284,6 +1387,16 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         config: &EndpointConfig,
         direction: UsbDirection,
     ) -> Result<EndpointDescriptor> {
+        // Only periodic IN endpoints of HS cores can send several packets per microframe
+        let high_bandwidth = config.max_packet_size >> 11 != 0;
+        let periodic = matches!(
+            config.ep_type,
+            EndpointType::Isochronous { .. } | EndpointType::Interrupt
+        );
+        if high_bandwidth && (!USB::HIGH_SPEED || direction == UsbDirection::Out || !periodic) {
+            return Err(UsbError::Unsupported);
+        }
+
         let number = Self::alloc_number(bitmap, config.number)?;
         let address = EndpointAddress::from_parts(number as usize, direction);
         Ok(EndpointDescriptor {
@@ -297,9 +1410,20 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
     fn alloc_in(&mut self, config: &EndpointConfig) -> Result<EndpointIn> {
         let descr = Self::alloc(&mut self.bitmap_in, config, UsbDirection::In)?;
 
-        self.memory_allocator
-            .allocate_tx_buffer(descr.address.index() as u8, descr.max_packet_size as usize)?;
+        self.memory_allocator.allocate_tx_buffer(
+            descr.address.index() as u8,
+            descr.max_payload_size() as usize,
+        )?;
+        #[cfg(not(feature = "dma"))]
         let ep = EndpointIn::new::<USB>(descr);
+        #[cfg(feature = "dma")]
+        let ep = {
+            // The core fetches the packets from this buffer
+            let buffer = self
+                .memory_allocator
+                .allocate_dma_tx_buffer(descr.max_payload_size() as usize)?;
+            EndpointIn::new::<USB>(descr, buffer)
+        };
 
         Ok(ep)
     }
@@ -307,9 +1431,17 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
     fn alloc_out(&mut self, config: &EndpointConfig) -> Result<EndpointOut> {
         let descr = Self::alloc(&mut self.bitmap_out, config, UsbDirection::Out)?;
 
+        let size = descr.max_packet_size as usize;
+        // Back-to-back SETUP packets are all written to the buffer of EP0 with DMA
+        #[cfg(feature = "dma")]
+        let size = if descr.address.index() == 0 {
+            size.max(24)
+        } else {
+            size
+        };
         let buffer = self
             .memory_allocator
-            .allocate_rx_buffer(descr.max_packet_size as usize)?;
+            .allocate_rx_slots(descr.address.index() as u8, size)?;
         let ep = EndpointOut::new::<USB>(descr, buffer);
 
         Ok(ep)
@@ -364,156 +1496,7 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
     }
 
     fn enable(&mut self) {
-        // Enable USB_OTG in RCC
-        USB::enable();
-
-        critical_section::with(|cs| {
-            let regs = self.regs.borrow(cs);
-
-            let core_id = read_reg!(otg_global, regs.global(), CID);
-
-            // Wait for AHB ready
-            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
-
-            // Configure OTG as device
-            #[cfg(feature = "fs")]
-            modify_reg!(otg_global, regs.global(), GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                FDMOD: 1 // Force device mode
-            );
-            #[cfg(feature = "hs")]
-            modify_reg!(otg_global, regs.global(), GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TOCAL: 0x1,
-                FDMOD: 1 // Force device mode
-            );
-
-            // Configure USB PHY
-            #[cfg(feature = "hs")]
-            match self.peripheral.phy_type() {
-                PhyType::InternalFullSpeed => {
-                    // Select FS Embedded PHY
-                    modify_reg!(otg_global, regs.global(), GUSBCFG, PHYSEL: 1);
-                }
-                PhyType::InternalHighSpeed => {
-                    // Turn off PHY
-                    modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
-
-                    // Init The UTMI Interface
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        TSDPS: 0,
-                        ULPIFSLS: 0,
-                        PHYSEL: 0 // ULPI or UTMI
-                    );
-
-                    // Select VBUS source
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        ULPIEVBUSD: 0,
-                        ULPIEVBUSI: 0
-                    );
-
-                    // Select UTMI Interace
-                    //modify_reg!(otg_global, regs.global(), GUSBCFG, ULPISEL: 0);
-                    modify_reg!(otg_global, regs.global(), GUSBCFG, |r| r & !(1 << 4));
-
-                    // This is a secret bit from ST that is not mentioned anywhere except
-                    // the driver code shipped with STM32CubeIDE.
-                    //modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 23));
-
-                    self.peripheral.setup_internal_hs_phy();
-                }
-                PhyType::ExternalHighSpeed => {
-                    // Turn off embedded PHY
-                    modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
-
-                    // Init The ULPI Interface
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        TSDPS: 0,
-                        ULPIFSLS: 0,
-                        PHYSEL: 0 // ULPI or UTMI
-                    );
-
-                    // Select VBUS source
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        ULPIEVBUSD: 0,
-                        ULPIEVBUSI: 0
-                    );
-                }
-            }
-
-            // Perform core soft-reset
-            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
-            modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
-            while read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 1 {}
-
-            if self.peripheral.phy_type() == PhyType::InternalFullSpeed {
-                // Activate the USB Transceiver
-                modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
-            }
-
-            // Configuring Vbus sense and SOF output
-            match core_id {
-                0x0000_1200 | 0x0000_1100 => {
-                    // F429-like chips have the GCCFG.NOVBUSSENS bit
-
-                    //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
-
-                    modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
-                }
-                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
-                    // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
-
-                    //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
-
-                    // Force B-peripheral session
-                    //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 1, BVALOVAL: 1);
-                    modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r | (0b11 << 6));
-                }
-                _ => {}
-            }
-
-            // Enable PHY clock
-            write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
-
-            // Soft disconnect device
-            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
-
-            // Setup USB speed and frame interval
-            let speed = match (USB::HIGH_SPEED, self.peripheral.phy_type()) {
-                (false, _) => 0b11,
-                (true, PhyType::InternalFullSpeed) => 0b11,
-                (true, PhyType::InternalHighSpeed) => 0b00,
-                (true, PhyType::ExternalHighSpeed) => 0b00,
-            };
-            modify_reg!(otg_device, regs.device(), DCFG,
-                PFIVL: 0b00,
-                DSPD: speed
-            );
-            #[cfg(feature = "xcvrdly")]
-            modify_reg!(otg_device, regs.device(), DCFG, XCVRDLY: 1);
-
-            // unmask EP interrupts
-            write_reg!(otg_device, regs.device(), DIEPMSK, XFRCM: 1);
-
-            // unmask core interrupts
-            write_reg!(otg_global, regs.global(), GINTMSK,
-                USBRST: 1, ENUMDNEM: 1,
-                USBSUSPM: 1, WUIM: 1,
-                IEPINT: 1, RXFLVLM: 1
-            );
-
-            // clear pending interrupts
-            write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
-
-            // unmask global interrupt
-            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
-
-            // connect(true)
-            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
-        });
+        self.init();
     }
 
     fn reset(&self) {
@@ -524,6 +1507,7 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
 
             modify_reg!(otg_device, regs.device(), DCFG, DAD: 0);
         });
+        self.unblock_rx_fifo();
     }
 
     fn set_device_address(&self, addr: u8) {
@@ -551,7 +1535,11 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         }
 
         if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
-            ep.read(buf)
+            let result = ep.read(buf);
+            if result.is_ok() {
+                self.unblock_rx_fifo();
+            }
+            result
         } else {
             Err(UsbError::InvalidEndpoint)
         }
@@ -576,194 +1564,40 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
     }
 
     fn suspend(&self) {
-        // Nothing to do here?
-    }
-
-    fn resume(&self) {
-        // Nothing to do here?
-    }
-
-    fn poll(&self) -> PollResult {
-        critical_section::with(|cs| {
+        let stopped = critical_section::with(|cs| {
             let regs = self.regs.borrow(cs);
-
-            let core_id = read_reg!(otg_global, regs.global(), CID);
-
-            let (wakeup, suspend, enum_done, reset, iep, rxflvl) = read_reg!(
-                otg_global,
-                regs.global(),
-                GINTSTS,
-                WKUPINT,
-                USBSUSP,
-                ENUMDNE,
-                USBRST,
-                IEPINT,
-                RXFLVL
-            );
-
-            if reset != 0 {
-                write_reg!(otg_global, regs.global(), GINTSTS, USBRST: 1);
-
-                self.deconfigure_all(cs);
-
-                // Flush RX
-                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
-                while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 1 {}
+            if !self.low_power_suspend.borrow(cs).get() || self.in_host_mode(*regs) {
+                return false;
             }
 
-            if enum_done != 0 {
-                write_reg!(otg_global, regs.global(), GINTSTS, ENUMDNE: 1);
-
-                let speed = read_reg!(otg_device, regs.device(), DSTS, ENUMSPD);
-
-                // Compute and update TRDT
-                let trdt;
-                match speed {
-                    0b00 => {
-                        // High speed
-
-                        // From RM0431 (F72xx), RM0090 (F429), RM0390 (F446)
-                        if self.peripheral.ahb_frequency_hz() >= 30_000_000 {
-                            trdt = 0x9;
-                        } else {
-                            panic!("AHB frequency is too low")
-                        }
-                    }
-                    0b01 | 0b11 => {
-                        // Full speed
-
-                        // From RM0431 (F72xx), RM0090 (F429)
-                        trdt = match self.peripheral.ahb_frequency_hz() {
-                            0..=14_199_999 => panic!("AHB frequency is too low"),
-                            14_200_000..=14_999_999 => 0xF,
-                            15_000_000..=15_999_999 => 0xE,
-                            16_000_000..=17_199_999 => 0xD,
-                            17_200_000..=18_499_999 => 0xC,
-                            18_500_000..=19_999_999 => 0xB,
-                            20_000_000..=21_799_999 => 0xA,
-                            21_800_000..=23_999_999 => 0x9,
-                            24_000_000..=27_499_999 => 0x8,
-                            27_500_000..=31_999_999 => 0x7, // 27.7..32 in code from CubeIDE
-                            32_000_000..=u32::MAX => 0x6,
-                        };
-                    }
-                    _ => unimplemented!(),
-                }
-                modify_reg!(otg_global, regs.global(), GUSBCFG, TRDT: trdt);
+            self.enter_low_power(*regs);
+            true
+        });
 
-                PollResult::Reset
-            } else if wakeup != 0 {
-                // Clear the interrupt
-                write_reg!(otg_global, regs.global(), GINTSTS, WKUPINT: 1);
+        // Outside of the critical section, the HAL may enter a low-power mode
+        if stopped {
+            self.peripheral.suspend_low_power();
+        }
+    }
 
-                PollResult::Resume
-            } else if suspend != 0 {
-                write_reg!(otg_global, regs.global(), GINTSTS, USBSUSP: 1);
+    fn resume(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            self.exit_low_power(cs, *regs);
+        });
+        self.finish_resume();
+    }
 
-                PollResult::Suspend
+    fn poll(&self) -> PollResult {
+        let result = critical_section::with(|cs| {
+            if self.interrupt_driven.borrow(cs).get() {
+                self.take_latched_events(cs)
             } else {
-                let mut ep_out = 0;
-                let mut ep_in_complete = 0;
-                let mut ep_setup = 0;
-
-                use crate::ral::{endpoint_in, endpoint_out};
-
-                // RXFLVL & IEPINT flags are read-only, there is no need to clear them
-                if rxflvl != 0 {
-                    let (epnum, data_size, status) =
-                        read_reg!(otg_global, regs.global(), GRXSTSR, EPNUM, BCNT, PKTSTS);
-                    match status {
-                        0x02 => {
-                            // OUT received
-                            ep_out |= 1 << epnum;
-                        }
-                        0x06 => {
-                            // SETUP received
-                            // flushing TX if something stuck in control endpoint
-                            let ep = regs.endpoint_in(epnum as usize);
-                            if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
-                                modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
-                                while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
-                            }
-                            ep_setup |= 1 << epnum;
-                        }
-                        0x03 | 0x04 => {
-                            // OUT completed | SETUP completed
-                            // Re-enable the endpoint, F429-like chips only
-                            if core_id == 0x0000_1200 || core_id == 0x0000_1100 {
-                                let ep = regs.endpoint_out(epnum as usize);
-                                modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                            }
-                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-                        }
-                        _ => {
-                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-                        }
-                    }
-
-                    if status == 0x02 || status == 0x06 {
-                        if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
-                            let mut buffer = ep.buffer.borrow_ref_mut(cs);
-                            if buffer.state() == EndpointBufferState::Empty {
-                                read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-
-                                let is_setup = status == 0x06;
-                                buffer
-                                    .fill_from_fifo(*regs, data_size as u16, is_setup)
-                                    .ok();
-
-                                // Re-enable the endpoint, F446-like chips only
-                                if core_id == 0x0000_2000
-                                    || core_id == 0x0000_2100
-                                    || core_id == 0x0000_2300
-                                    || core_id == 0x0000_3000
-                                    || core_id == 0x0000_3100
-                                {
-                                    let ep = regs.endpoint_out(epnum as usize);
-                                    modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                                }
-                            }
-                        }
-                    }
-                }
-
-                if iep != 0 {
-                    for ep in &self.allocator.endpoints_in {
-                        if let Some(ep) = ep {
-                            let ep_regs = regs.endpoint_in(ep.address().index());
-                            if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
-                                write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
-                                ep_in_complete |= 1 << ep.address().index();
-                            }
-                        }
-                    }
-                }
-
-                for ep in &self.allocator.endpoints_out {
-                    if let Some(ep) = ep {
-                        match ep.buffer_state() {
-                            EndpointBufferState::DataOut => {
-                                ep_out |= 1 << ep.address().index();
-                            }
-                            EndpointBufferState::DataSetup => {
-                                ep_setup |= 1 << ep.address().index();
-                            }
-                            EndpointBufferState::Empty => {}
-                        }
-                    }
-                }
-
-                if (ep_in_complete | ep_out | ep_setup) != 0 {
-                    PollResult::Data {
-                        ep_out,
-                        ep_in_complete,
-                        ep_setup,
-                    }
-                } else {
-                    PollResult::None
-                }
+                self.handle_events(cs)
             }
-        })
+        });
+        self.finish_resume();
+        result
     }
 
     const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
@@ -0,0 +1,673 @@
+use crate::bus::{FifoConfig, UsbBus};
+use crate::endpoint::WakerSlot;
+use crate::ral::{modify_reg, otg_global, otg_pwrclk};
+use crate::target::UsbRegisters;
+use crate::UsbPeripheral;
+use core::cell::{Cell, UnsafeCell};
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::task::Poll;
+use critical_section::Mutex;
+use embassy_usb_driver as driver;
+use embassy_usb_driver::{EndpointAllocError, EndpointError, EndpointInfo, Event, Unsupported};
+use usb_device::bus::{PollResult, UsbBus as _};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+
+/// Maximum number of (bidirectional) endpoints
+const MAX_ENDPOINTS: usize = 9;
+
+/// Events reported by the core since they were last handled.
+#[derive(Copy, Clone)]
+struct Events {
+    /// The core was initialized by [`Bus::enable`]
+    enabled: bool,
+    power_detected: bool,
+    reset: bool,
+    suspend: bool,
+    resume: bool,
+    /// The bus is suspended, until the next event other than a suspend
+    suspended: bool,
+    /// IN endpoints that completed a transfer
+    ep_in_complete: u16,
+    /// Endpoints enabled by the stack, EP0 is always enabled
+    ep_in_enabled: u16,
+    ep_out_enabled: u16,
+}
+
+/// State shared by the async driver and the interrupt handler.
+///
+/// Declare it as a `static` and call [`on_interrupt`](Self::on_interrupt) from the OTG interrupt:
+///
+/// ```ignore
+/// static STATE: State<USB> = State::new();
+///
+/// #[interrupt]
+/// fn OTG_FS() {
+///     STATE.on_interrupt();
+/// }
+///
+/// let driver = Driver::new(usb, ep_memory, &STATE);
+/// let mut builder = embassy_usb::Builder::new(driver, config, /* ... */);
+/// ```
+pub struct State<USB> {
+    bus: UnsafeCell<MaybeUninit<UsbBus<USB>>>,
+    started: Mutex<Cell<bool>>,
+    events: Mutex<Cell<Events>>,
+    bus_waker: WakerSlot,
+    ep_in_wakers: [WakerSlot; MAX_ENDPOINTS],
+    ep_out_wakers: [WakerSlot; MAX_ENDPOINTS],
+}
+
+// The bus is written once by `Driver::start`, before `started` is set, and only read after
+unsafe impl<USB: UsbPeripheral> Sync for State<USB> {}
+
+impl<USB: UsbPeripheral> State<USB> {
+    /// Creates the state of a driver.
+    pub const fn new() -> Self {
+        Self {
+            bus: UnsafeCell::new(MaybeUninit::uninit()),
+            started: Mutex::new(Cell::new(false)),
+            events: Mutex::new(Cell::new(Events {
+                enabled: false,
+                power_detected: true,
+                reset: false,
+                suspend: false,
+                resume: false,
+                suspended: false,
+                ep_in_complete: 0,
+                ep_in_enabled: 1,
+                ep_out_enabled: 1,
+            })),
+            bus_waker: WakerSlot::NEW,
+            ep_in_wakers: [WakerSlot::NEW; MAX_ENDPOINTS],
+            ep_out_wakers: [WakerSlot::NEW; MAX_ENDPOINTS],
+        }
+    }
+
+    /// Handles the OTG interrupt.
+    ///
+    /// The interrupt is masked and the tasks waiting for the driver are woken, they handle the
+    /// events and unmask the interrupt.
+    pub fn on_interrupt(&self) {
+        let regs = UsbRegisters::new::<USB>();
+
+        critical_section::with(|_| {
+            // The core registers are not accessible while the AHB clock is gated
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 0);
+        });
+
+        self.wake_all();
+    }
+
+    fn bus(&self) -> Option<&UsbBus<USB>> {
+        if critical_section::with(|cs| self.started.borrow(cs).get()) {
+            Some(unsafe { (*self.bus.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    fn start(&self, bus: UsbBus<USB>) {
+        critical_section::with(|cs| {
+            let started = self.started.borrow(cs);
+            assert!(!started.get(), "the state is used by another driver");
+            unsafe { (*self.bus.get()).write(bus) };
+            started.set(true);
+        });
+        self.wake_all();
+    }
+
+    fn events(&self) -> Events {
+        critical_section::with(|cs| self.events.borrow(cs).get())
+    }
+
+    fn update_events(&self, f: impl FnOnce(&mut Events)) {
+        critical_section::with(|cs| {
+            let cell = self.events.borrow(cs);
+            let mut events = cell.get();
+            f(&mut events);
+            cell.set(events);
+        });
+    }
+
+    fn wake_all(&self) {
+        self.bus_waker.wake();
+        for waker in self.ep_in_wakers.iter().chain(self.ep_out_wakers.iter()) {
+            waker.wake();
+        }
+    }
+
+    /// Polls the core and records its events, the waiting tasks are woken if something
+    /// happened.
+    fn process(&self) {
+        let bus = match self.bus() {
+            Some(bus) if self.events().enabled => bus,
+            _ => return,
+        };
+
+        // Unmasked before polling, so that no interrupt is missed
+        let regs = UsbRegisters::new::<USB>();
+        critical_section::with(|_| modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1));
+
+        let result = bus.poll();
+        if matches!(result, PollResult::None) {
+            return;
+        }
+
+        let suspended = self.events().suspended;
+        if suspended && !matches!(result, PollResult::Suspend) {
+            bus.resume();
+        }
+
+        match result {
+            PollResult::None => {}
+            PollResult::Reset => {
+                bus.reset();
+                self.update_events(|e| {
+                    e.reset = true;
+                    e.suspend = false;
+                    e.resume = false;
+                    e.suspended = false;
+                    e.ep_in_complete = 0;
+                    e.ep_in_enabled = 1;
+                    e.ep_out_enabled = 1;
+                });
+            }
+            PollResult::Data { ep_in_complete, .. } => {
+                self.update_events(|e| {
+                    e.ep_in_complete |= ep_in_complete;
+                    e.resume |= suspended;
+                    e.suspended = false;
+                });
+            }
+            PollResult::Suspend => {
+                if !suspended {
+                    bus.suspend();
+                    self.update_events(|e| {
+                        e.suspend = true;
+                        e.suspended = true;
+                    });
+                }
+            }
+            PollResult::Resume => {
+                self.update_events(|e| {
+                    e.resume = suspended;
+                    e.suspended = false;
+                });
+            }
+        }
+
+        self.wake_all();
+    }
+
+    fn ep_enabled(&self, ep_addr: EndpointAddress) -> bool {
+        let events = self.events();
+        let enabled = match ep_addr.direction() {
+            UsbDirection::In => events.ep_in_enabled,
+            UsbDirection::Out => events.ep_out_enabled,
+        };
+        enabled & (1 << ep_addr.index()) != 0
+    }
+
+    fn waker(&self, ep_addr: EndpointAddress) -> &WakerSlot {
+        match ep_addr.direction() {
+            UsbDirection::In => &self.ep_in_wakers[ep_addr.index()],
+            UsbDirection::Out => &self.ep_out_wakers[ep_addr.index()],
+        }
+    }
+
+    /// Returns `true` once, if the IN endpoint completed a transfer.
+    fn take_in_complete(&self, index: usize) -> bool {
+        let mut complete = false;
+        self.update_events(|e| {
+            complete = e.ep_in_complete & (1 << index) != 0;
+            e.ep_in_complete &= !(1 << index);
+        });
+        complete
+    }
+
+    /// Runs `f` until it returns a result, each time the driver handled an event.
+    async fn wait<T>(&self, waker: &WakerSlot, mut f: impl FnMut(&UsbBus<USB>) -> Option<T>) -> T {
+        poll_fn(|cx| {
+            waker.register(cx.waker());
+            self.process();
+            match self.bus().and_then(&mut f) {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl<USB: UsbPeripheral> Default for State<USB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `embassy-usb` driver for the device mode of the core.
+///
+/// The endpoints are handled by the same code as [`UsbBus`], the tasks are woken from the OTG
+/// interrupt, see [`State`].
+pub struct Driver<'d, USB> {
+    bus: UsbBus<USB>,
+    state: &'d State<USB>,
+}
+
+impl<'d, USB: UsbPeripheral> Driver<'d, USB> {
+    /// Creates a driver for the peripheral.
+    pub fn new(peripheral: USB, ep_memory: &'static mut [u32], state: &'d State<USB>) -> Self {
+        Self::new_with_fifo_config(peripheral, ep_memory, FifoConfig::default(), state)
+    }
+
+    /// Creates a driver for the peripheral with an explicit FIFO layout.
+    pub fn new_with_fifo_config(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        fifo_config: FifoConfig,
+        state: &'d State<USB>,
+    ) -> Self {
+        Self {
+            bus: UsbBus::create(peripheral, ep_memory, fifo_config, false),
+            state,
+        }
+    }
+
+    fn alloc<D>(
+        &mut self,
+        direction: UsbDirection,
+        ep_type: driver::EndpointType,
+        ep_addr: Option<driver::EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Endpoint<'d, USB, D>, EndpointAllocError> {
+        let ep_type_ = match ep_type {
+            driver::EndpointType::Control => EndpointType::Control,
+            // `embassy-usb-driver` doesn't pass the synchronization and usage types, which only
+            // appear in the descriptors written by `embassy-usb`, the core doesn't use them
+            driver::EndpointType::Isochronous => EndpointType::Isochronous {
+                synchronization:
+                    usb_device::endpoint::IsochronousSynchronizationType::NoSynchronization,
+                usage: usb_device::endpoint::IsochronousUsageType::Data,
+            },
+            driver::EndpointType::Bulk => EndpointType::Bulk,
+            driver::EndpointType::Interrupt => EndpointType::Interrupt,
+        };
+        let ep_addr = ep_addr.map(|a| EndpointAddress::from(u8::from(a)));
+
+        let address = self
+            .bus
+            .alloc_ep(direction, ep_addr, ep_type_, max_packet_size, interval_ms)
+            .map_err(|_| EndpointAllocError)?;
+
+        Ok(Endpoint {
+            info: EndpointInfo {
+                addr: driver::EndpointAddress::from(u8::from(address)),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+            address,
+            state: self.state,
+            _direction: PhantomData,
+        })
+    }
+}
+
+impl<'d, USB: UsbPeripheral> driver::Driver<'d> for Driver<'d, USB> {
+    type EndpointOut = Endpoint<'d, USB, Out>;
+    type EndpointIn = Endpoint<'d, USB, In>;
+    type ControlPipe = ControlPipe<'d, USB>;
+    type Bus = Bus<'d, USB>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: driver::EndpointType,
+        ep_addr: Option<driver::EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.alloc(
+            UsbDirection::Out,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: driver::EndpointType,
+        ep_addr: Option<driver::EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.alloc(
+            UsbDirection::In,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )
+    }
+
+    fn start(mut self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let mps = control_max_packet_size;
+        self.bus
+            .alloc_ep(
+                UsbDirection::Out,
+                Some(ep0_out()),
+                EndpointType::Control,
+                mps,
+                0,
+            )
+            .expect("EP0 OUT is already allocated");
+        self.bus
+            .alloc_ep(
+                UsbDirection::In,
+                Some(ep0_in()),
+                EndpointType::Control,
+                mps,
+                0,
+            )
+            .expect("EP0 IN is already allocated");
+
+        self.state.start(self.bus);
+
+        (
+            Bus { state: self.state },
+            ControlPipe {
+                state: self.state,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+/// Bus events and endpoint configuration of the [`Driver`].
+pub struct Bus<'d, USB> {
+    state: &'d State<USB>,
+}
+
+impl<'d, USB: UsbPeripheral> driver::Bus for Bus<'d, USB> {
+    async fn enable(&mut self) {
+        if let Some(bus) = self.state.bus() {
+            bus.activate();
+            self.state.update_events(|e| e.enabled = true);
+        }
+    }
+
+    async fn disable(&mut self) {
+        if let Some(bus) = self.state.bus() {
+            self.state.update_events(|e| e.enabled = false);
+            bus.deactivate();
+        }
+    }
+
+    async fn poll(&mut self) -> Event {
+        let state = self.state;
+        state
+            .wait(&state.bus_waker, |_| {
+                let mut event = None;
+                state.update_events(|e| {
+                    if e.power_detected {
+                        e.power_detected = false;
+                        event = Some(Event::PowerDetected);
+                    } else if e.reset {
+                        e.reset = false;
+                        event = Some(Event::Reset);
+                    } else if e.suspend {
+                        e.suspend = false;
+                        event = Some(Event::Suspend);
+                    } else if e.resume {
+                        e.resume = false;
+                        event = Some(Event::Resume);
+                    }
+                });
+                event
+            })
+            .await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: driver::EndpointAddress, enabled: bool) {
+        let ep_addr = EndpointAddress::from(u8::from(ep_addr));
+        if let Some(bus) = self.state.bus() {
+            bus.set_endpoint_enabled(ep_addr, enabled);
+        }
+
+        let mask = 1 << ep_addr.index();
+        self.state.update_events(|e| {
+            let bits = match ep_addr.direction() {
+                UsbDirection::In => &mut e.ep_in_enabled,
+                UsbDirection::Out => &mut e.ep_out_enabled,
+            };
+            if enabled {
+                *bits |= mask;
+            } else {
+                *bits &= !mask;
+            }
+        });
+        self.state.waker(ep_addr).wake();
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: driver::EndpointAddress, stalled: bool) {
+        if let Some(bus) = self.state.bus() {
+            bus.set_stalled(EndpointAddress::from(u8::from(ep_addr)), stalled);
+        }
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: driver::EndpointAddress) -> bool {
+        match self.state.bus() {
+            Some(bus) => bus.is_stalled(EndpointAddress::from(u8::from(ep_addr))),
+            None => false,
+        }
+    }
+
+    /// Not supported, the signaling must last 1 to 15 ms and the driver has no timer.
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+/// Direction marker of an [`Endpoint`]
+pub enum In {}
+
+/// Direction marker of an [`Endpoint`]
+pub enum Out {}
+
+/// Endpoint allocated from the [`Driver`].
+pub struct Endpoint<'d, USB, D> {
+    info: EndpointInfo,
+    address: EndpointAddress,
+    state: &'d State<USB>,
+    _direction: PhantomData<D>,
+}
+
+impl<'d, USB: UsbPeripheral, D> driver::Endpoint for Endpoint<'d, USB, D> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        let state = self.state;
+        let address = self.address;
+        state
+            .wait(state.waker(address), |_| {
+                state.ep_enabled(address).then_some(())
+            })
+            .await
+    }
+}
+
+impl<'d, USB: UsbPeripheral> driver::EndpointIn for Endpoint<'d, USB, In> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+
+        let state = self.state;
+        let address = self.address;
+        state
+            .wait(state.waker(address), |bus| {
+                if !state.ep_enabled(address) {
+                    return Some(Err(EndpointError::Disabled));
+                }
+                match bus.write(address, buf) {
+                    Ok(_) => Some(Ok(())),
+                    Err(UsbError::WouldBlock) => None,
+                    Err(_) => Some(Err(EndpointError::BufferOverflow)),
+                }
+            })
+            .await
+    }
+}
+
+impl<'d, USB: UsbPeripheral> driver::EndpointOut for Endpoint<'d, USB, Out> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let state = self.state;
+        let address = self.address;
+        state
+            .wait(state.waker(address), |bus| {
+                if !state.ep_enabled(address) {
+                    return Some(Err(EndpointError::Disabled));
+                }
+                match bus.read(address, buf) {
+                    Ok(size) => Some(Ok(size)),
+                    Err(UsbError::WouldBlock) => None,
+                    Err(_) => Some(Err(EndpointError::BufferOverflow)),
+                }
+            })
+            .await
+    }
+}
+
+/// Control endpoint of the [`Driver`].
+pub struct ControlPipe<'d, USB> {
+    state: &'d State<USB>,
+    max_packet_size: usize,
+}
+
+fn ep0_out() -> EndpointAddress {
+    EndpointAddress::from_parts(0, UsbDirection::Out)
+}
+
+fn ep0_in() -> EndpointAddress {
+    EndpointAddress::from_parts(0, UsbDirection::In)
+}
+
+impl<'d, USB: UsbPeripheral> ControlPipe<'d, USB> {
+    /// Writes a packet to EP0 and waits until it is sent.
+    ///
+    /// A SETUP packet aborts the transfer, the packet is then dropped.
+    async fn write_packet(&self, data: &[u8]) -> Result<(), EndpointError> {
+        let state = self.state;
+        let waker = state.waker(ep0_in());
+        state.take_in_complete(0);
+        state
+            .wait(waker, |bus| {
+                if bus.setup_pending() {
+                    return Some(Err(EndpointError::Disabled));
+                }
+                match bus.write(ep0_in(), data) {
+                    Ok(_) => Some(Ok(())),
+                    Err(UsbError::WouldBlock) => None,
+                    Err(_) => Some(Err(EndpointError::BufferOverflow)),
+                }
+            })
+            .await?;
+        state
+            .wait(waker, |bus| {
+                if state.take_in_complete(0) {
+                    Some(Ok(()))
+                } else if bus.setup_pending() {
+                    Some(Err(EndpointError::Disabled))
+                } else {
+                    None
+                }
+            })
+            .await
+    }
+}
+
+impl<'d, USB: UsbPeripheral> driver::ControlPipe for ControlPipe<'d, USB> {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let state = self.state;
+        state
+            .wait(state.waker(ep0_out()), |bus| {
+                let mut setup = [0; 8];
+                loop {
+                    let is_setup = bus.setup_pending();
+                    match bus.read(ep0_out(), &mut setup) {
+                        Ok(8) if is_setup => return Some(setup),
+                        // Status stage of a control read, or data the stack didn't read
+                        Ok(_) | Err(UsbError::BufferOverflow) => {}
+                        Err(_) => return None,
+                    }
+                }
+            })
+            .await
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        let state = self.state;
+        state
+            .wait(state.waker(ep0_out()), |bus| {
+                if bus.setup_pending() {
+                    // The host aborted the control transfer
+                    return Some(Err(EndpointError::Disabled));
+                }
+                match bus.read(ep0_out(), buf) {
+                    Ok(size) => Some(Ok(size)),
+                    Err(UsbError::WouldBlock) => None,
+                    Err(_) => Some(Err(EndpointError::BufferOverflow)),
+                }
+            })
+            .await
+    }
+
+    async fn data_in(
+        &mut self,
+        data: &[u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<(), EndpointError> {
+        if data.len() > self.max_packet_size {
+            return Err(EndpointError::BufferOverflow);
+        }
+        self.write_packet(data).await
+    }
+
+    async fn accept(&mut self) {
+        // A new SETUP packet cancels the status stage
+        self.write_packet(&[]).await.ok();
+    }
+
+    async fn reject(&mut self) {
+        if let Some(bus) = self.state.bus() {
+            bus.set_stalled(ep0_in(), true);
+            bus.set_stalled(ep0_out(), true);
+        }
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        // The core sends the status stage with the previous address
+        if let Some(bus) = self.state.bus() {
+            bus.set_device_address(addr);
+        }
+        self.accept().await;
+    }
+}
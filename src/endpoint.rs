@@ -1,14 +1,26 @@
 use crate::endpoint_memory::{EndpointBuffer, EndpointBufferState};
-use crate::ral::{endpoint0_out, endpoint_in, endpoint_out, modify_reg, read_reg, write_reg};
-use crate::target::{fifo_write, UsbRegisters};
+use crate::ral::{
+    endpoint0_out, endpoint_in, endpoint_out, modify_reg, otg_device, otg_global, read_reg,
+    write_reg,
+};
+use crate::target::{fifo_discard, fifo_read, fifo_write, UsbRegisters};
 use crate::transition::EndpointDescriptor;
 use crate::UsbPeripheral;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::ops::{Deref, DerefMut};
+use core::task::Waker;
 use critical_section::{CriticalSection, Mutex};
-use usb_device::endpoint::EndpointAddress;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::{Result, UsbDirection, UsbError};
 
+/// Limit of DIEPTSIZx.PKTCNT
+const MAX_PACKET_COUNT: usize = 0x3ff;
+/// Limit of DIEPTSIZx.XFRSIZ
+const MAX_TRANSFER_SIZE: usize = 0x7ffff;
+/// Number of packets that can wait in the Tx FIFO of an IN endpoint
+#[cfg(not(feature = "dma"))]
+const MAX_QUEUED_PACKETS: usize = 8;
+
 pub fn set_stalled(usb: UsbRegisters, address: EndpointAddress, stalled: bool) {
     critical_section::with(|_| match address.direction() {
         UsbDirection::Out => {
@@ -36,10 +48,36 @@ pub fn is_stalled(usb: UsbRegisters, address: EndpointAddress) -> bool {
     stall != 0
 }
 
+/// Task waiting for an endpoint.
+pub(crate) struct WakerSlot(Mutex<Cell<Option<Waker>>>);
+
+impl WakerSlot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const NEW: WakerSlot = WakerSlot(Mutex::new(Cell::new(None)));
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        critical_section::with(|cs| {
+            let slot = self.0.borrow(cs);
+            match slot.take() {
+                Some(w) if w.will_wake(waker) => slot.set(Some(w)),
+                _ => slot.set(Some(waker.clone())),
+            }
+        });
+    }
+
+    pub(crate) fn wake(&self) {
+        if let Some(waker) = critical_section::with(|cs| self.0.borrow(cs).take()) {
+            waker.wake();
+        }
+    }
+}
+
 /// Arbitrates access to the endpoint-specific registers and packet buffer memory.
 pub struct Endpoint {
     descriptor: EndpointDescriptor,
     usb: UsbRegisters,
+    /// Task waiting for the endpoint to be written or read
+    waker: WakerSlot,
 }
 
 impl Endpoint {
@@ -47,6 +85,7 @@ impl Endpoint {
         Endpoint {
             descriptor,
             usb: UsbRegisters::new::<USB>(),
+            waker: WakerSlot::NEW,
         }
     }
 
@@ -58,16 +97,144 @@ impl Endpoint {
     fn index(&self) -> u8 {
         self.descriptor.address.index() as u8
     }
+
+    /// Registers the task to wake once the endpoint is ready again.
+    pub fn register_waker(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    /// Wakes the task waiting for the endpoint.
+    pub fn wake(&self) {
+        self.waker.wake();
+    }
+
+    pub fn is_isochronous(&self) -> bool {
+        matches!(self.descriptor.ep_type, EndpointType::Isochronous { .. })
+    }
+
+    /// Schedules the next transaction of an isochronous endpoint in the next (micro)frame.
+    ///
+    /// Isochronous endpoints only transfer data in (micro)frames of the selected parity.
+    pub fn select_frame_parity(&self) {
+        if !self.is_isochronous() {
+            return;
+        }
+
+        // The next (micro)frame is odd if the current one is even
+        let odd = read_reg!(otg_device, self.usb.device(), DSTS, FNSOF) & 1 == 0;
+        match self.descriptor.address.direction() {
+            UsbDirection::In => {
+                let regs = self.usb.endpoint_in(self.index() as usize);
+                #[cfg(feature = "fs")]
+                modify_reg!(endpoint_in, regs, DIEPCTL,
+                    SD0PID_SEVNFRM: !odd as u32,
+                    SODDFRM_SD1PID: odd as u32
+                );
+                #[cfg(feature = "hs")]
+                modify_reg!(endpoint_in, regs, DIEPCTL,
+                    SD0PID_SEVNFRM: !odd as u32,
+                    SODDFRM: odd as u32
+                );
+            }
+            UsbDirection::Out => {
+                let regs = self.usb.endpoint_out(self.index() as usize);
+                modify_reg!(endpoint_out, regs, DOEPCTL,
+                    SD0PID_SEVNFRM: !odd as u32,
+                    SODDFRM: odd as u32
+                );
+            }
+        }
+    }
+
+    /// Returns `true` if an isochronous transaction is pending for the current (micro)frame.
+    ///
+    /// Used at the end of a periodic frame, when the transaction was not completed.
+    fn is_isochronous_due(&self, epctl: u32) -> bool {
+        let enabled = epctl & endpoint_in::DIEPCTL::EPENA::mask != 0;
+        let odd_frame = (epctl & endpoint_in::DIEPCTL::EONUM_DPID::mask) != 0;
+        let frame = read_reg!(otg_device, self.usb.device(), DSTS, FNSOF);
+        self.is_isochronous() && enabled && odd_frame == (frame & 1 != 0)
+    }
+}
+
+/// Sizes of the packets written to the Tx FIFO while the endpoint was still busy
+#[cfg(not(feature = "dma"))]
+#[derive(Copy, Clone, Default)]
+struct PacketQueue {
+    sizes: [u16; MAX_QUEUED_PACKETS],
+    len: usize,
+}
+
+#[cfg(not(feature = "dma"))]
+impl PacketQueue {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, size: usize) -> bool {
+        if self.len == MAX_QUEUED_PACKETS {
+            return false;
+        }
+        self.sizes[self.len] = size as u16;
+        self.len += 1;
+        true
+    }
+
+    /// Removes the packets that can be sent as one transfer, up to the first short packet.
+    ///
+    /// Returns the packet count and the transfer size.
+    fn take_transfer(&mut self, max_packet_size: usize) -> (usize, usize) {
+        let mut packets = 0;
+        let mut size = 0;
+        while packets < self.len {
+            let packet = self.sizes[packets] as usize;
+            packets += 1;
+            size += packet;
+            if packet < max_packet_size {
+                break;
+            }
+        }
+        self.sizes.copy_within(packets..self.len, 0);
+        self.len -= packets;
+        (packets, size)
+    }
 }
 
 pub struct EndpointIn {
     common: Endpoint,
+    /// Data of a multi-packet transfer that is not in the FIFO yet
+    transfer: Mutex<Cell<&'static [u8]>>,
+    /// A zero-length packet is sent once the multi-packet transfer completes
+    zlp: Mutex<Cell<bool>>,
+    /// Packets in the FIFO that are sent once the current transfer completes
+    #[cfg(not(feature = "dma"))]
+    queue: Mutex<Cell<PacketQueue>>,
+    /// Packet fetched by the core with DMA
+    #[cfg(feature = "dma")]
+    buffer: Mutex<RefCell<EndpointBuffer>>,
 }
 
 impl EndpointIn {
+    #[cfg(not(feature = "dma"))]
     pub fn new<USB: UsbPeripheral>(descriptor: EndpointDescriptor) -> EndpointIn {
         EndpointIn {
             common: Endpoint::new::<USB>(descriptor),
+            transfer: Mutex::new(Cell::new(&[])),
+            zlp: Mutex::new(Cell::new(false)),
+            queue: Mutex::new(Cell::new(PacketQueue::default())),
+        }
+    }
+
+    #[cfg(feature = "dma")]
+    pub fn new<USB: UsbPeripheral>(
+        descriptor: EndpointDescriptor,
+        buffer: EndpointBuffer,
+    ) -> EndpointIn {
+        EndpointIn {
+            common: Endpoint::new::<USB>(descriptor),
+            transfer: Mutex::new(Cell::new(&[])),
+            zlp: Mutex::new(Cell::new(false)),
+            buffer: Mutex::new(RefCell::new(buffer)),
         }
     }
 
@@ -90,19 +257,28 @@ impl EndpointIn {
                 SNAK: 1,
                 USBAEP: 1,
                 EPTYP: self.descriptor.ep_type.to_bm_attributes() as u32,
-                SD0PID_SEVNFRM: 1,
+                // The frame parity of isochronous endpoints is selected for each packet
+                SD0PID_SEVNFRM: !self.is_isochronous() as u32,
                 TXFNUM: self.index() as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                MPSIZ: self.descriptor.packet_size() as u32
             );
         }
     }
 
-    pub fn deconfigure(&self, _cs: CriticalSection<'_>) {
+    pub fn deconfigure(&self, cs: CriticalSection<'_>) {
         let regs = self.usb.endpoint_in(self.index() as usize);
 
         // deactivating endpoint
         modify_reg!(endpoint_in, regs, DIEPCTL, USBAEP: 0);
 
+        // dropping a multi-packet transfer in progress
+        self.transfer.borrow(cs).set(&[]);
+        self.zlp.borrow(cs).set(false);
+        #[cfg(not(feature = "dma"))]
+        self.queue.borrow(cs).take();
+        let mask = 1 << self.index();
+        modify_reg!(otg_device, self.usb.device(), DIEPEMPMSK, |v| v & !mask);
+
         // TODO: flushing FIFO
 
         // disabling endpoint
@@ -117,15 +293,61 @@ impl EndpointIn {
     }
 
     pub fn write(&self, buf: &[u8]) -> Result<()> {
+        // High-bandwidth endpoints send up to 3 packets per microframe
+        if buf.len() > self.descriptor.max_payload_size() as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        #[cfg(not(feature = "dma"))]
+        if self.index() != 0 && !self.is_isochronous() {
+            return critical_section::with(|cs| self.write_or_queue(cs, buf));
+        }
+
+        self.write_packet(buf)
+    }
+
+    /// Writes a packet of a bulk or interrupt endpoint.
+    ///
+    /// The packet is queued in the FIFO behind the previous ones if the endpoint is busy, and
+    /// sent from [`handle_transfer_complete`](Self::handle_transfer_complete).
+    #[cfg(not(feature = "dma"))]
+    fn write_or_queue(&self, cs: CriticalSection<'_>, buf: &[u8]) -> Result<()> {
         let ep = self.usb.endpoint_in(self.index() as usize);
-        if self.index() != 0 && read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0 {
+        let queue = self.queue.borrow(cs);
+        if read_reg!(endpoint_in, ep, DIEPCTL, EPENA) == 0 && queue.get().is_empty() {
+            return self.write_packet(buf);
+        }
+
+        // The FIFO must only hold complete packets of the current transfer
+        if !self.transfer.borrow(cs).get().is_empty() || self.zlp.borrow(cs).get() {
             return Err(UsbError::WouldBlock);
         }
 
-        if buf.len() > self.descriptor.max_packet_size as usize {
-            return Err(UsbError::BufferOverflow);
+        let size_words = buf.len().div_ceil(4);
+        if size_words > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let mut packets = queue.get();
+        if !packets.push(buf.len()) {
+            return Err(UsbError::WouldBlock);
+        }
+        queue.set(packets);
+
+        fifo_write(self.usb, self.index(), buf);
+        Ok(())
+    }
+
+    fn write_packet(&self, buf: &[u8]) -> Result<()> {
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        // With DMA, the packet buffer of EP0 is also busy until the transfer completes
+        if (self.index() != 0 || cfg!(feature = "dma"))
+            && read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0
+        {
+            return Err(UsbError::WouldBlock);
         }
 
+        #[cfg(not(feature = "dma"))]
         if !buf.is_empty() {
             // Check for FIFO free space
             let size_words = (buf.len() + 3) / 4;
@@ -137,19 +359,204 @@ impl EndpointIn {
         #[cfg(feature = "fs")]
         write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: buf.len() as u32);
         #[cfg(feature = "hs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: 1, XFRSIZ: buf.len() as u32);
+        {
+            let packet_size = self.descriptor.packet_size() as usize;
+            let packets = buf.len().div_ceil(packet_size).max(1) as u32;
+            // Only periodic endpoints send several packets per microframe, EP0 has no MCNT field
+            let periodic = self.index() != 0
+                && matches!(
+                    self.descriptor.ep_type,
+                    EndpointType::Isochronous { .. } | EndpointType::Interrupt
+                );
+            if periodic {
+                write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: packets, PKTCNT: packets, XFRSIZ: buf.len() as u32);
+            } else {
+                write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets, XFRSIZ: buf.len() as u32);
+            }
+        }
+
+        #[cfg(feature = "dma")]
+        critical_section::with(|cs| {
+            let mut buffer = self.buffer.borrow_ref_mut(cs);
+            buffer.fill_from_slice(buf);
+            write_reg!(endpoint_in, ep, DIEPDMA, buffer.dma_address());
+        });
 
+        self.select_frame_parity();
         modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
 
+        #[cfg(not(feature = "dma"))]
         fifo_write(self.usb, self.index(), buf);
 
         Ok(())
     }
+
+    /// Starts a transfer of several packets, the core splits `data` into packets by itself.
+    ///
+    /// The data is written to the FIFO as space frees up, from [`handle_txfifo_empty`].
+    /// Completion is reported once, after the last packet (or the terminating zero-length
+    /// packet if `zlp` is set and the size of `data` is a multiple of the max packet size).
+    ///
+    /// [`handle_txfifo_empty`]: Self::handle_txfifo_empty
+    pub fn write_transfer(&self, data: &'static [u8], zlp: bool) -> Result<()> {
+        if self.index() == 0 {
+            return Err(UsbError::Unsupported);
+        }
+
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        if read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0 {
+            return Err(UsbError::WouldBlock);
+        }
+        #[cfg(not(feature = "dma"))]
+        if critical_section::with(|cs| !self.queue.borrow(cs).get().is_empty()) {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let max_packet_size = self.descriptor.packet_size() as usize;
+        let packets = data.len().div_ceil(max_packet_size).max(1);
+        if packets > MAX_PACKET_COUNT || data.len() > MAX_TRANSFER_SIZE {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        // The DMA fetches the data directly from the buffer, word by word
+        #[cfg(feature = "dma")]
+        if data.as_ptr() as usize & 3 != 0 {
+            return Err(UsbError::Unsupported);
+        }
+
+        critical_section::with(|cs| {
+            self.zlp
+                .borrow(cs)
+                .set(zlp && !data.is_empty() && data.len() == packets * max_packet_size);
+
+            #[cfg(feature = "fs")]
+            write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets as u32, XFRSIZ: data.len() as u32);
+            #[cfg(feature = "hs")]
+            write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: packets as u32, XFRSIZ: data.len() as u32);
+
+            #[cfg(feature = "dma")]
+            write_reg!(endpoint_in, ep, DIEPDMA, data.as_ptr() as u32);
+
+            self.select_frame_parity();
+            modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+
+            #[cfg(not(feature = "dma"))]
+            {
+                self.transfer.borrow(cs).set(data);
+                self.handle_txfifo_empty(cs);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Writes the next packets of a multi-packet transfer to the FIFO, as long as they fit.
+    ///
+    /// The TXFE interrupt of the endpoint stays unmasked until all the data is in the FIFO.
+    pub fn handle_txfifo_empty(&self, cs: CriticalSection<'_>) {
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        let transfer = self.transfer.borrow(cs);
+        let max_packet_size = self.descriptor.packet_size() as usize;
+
+        let mut data = transfer.get();
+        while !data.is_empty() {
+            let (packet, rest) = data.split_at(data.len().min(max_packet_size));
+            let size_words = packet.len().div_ceil(4);
+            if size_words > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
+                break;
+            }
+
+            fifo_write(self.usb, self.index(), packet);
+            data = rest;
+        }
+        transfer.set(data);
+
+        let mask = 1 << self.index();
+        if data.is_empty() {
+            modify_reg!(otg_device, self.usb.device(), DIEPEMPMSK, |v| v & !mask);
+        } else {
+            modify_reg!(otg_device, self.usb.device(), DIEPEMPMSK, |v| v | mask);
+        }
+    }
+
+    /// Handles the completion of a transfer.
+    ///
+    /// Returns `false` if a zero-length packet still has to be sent to terminate it.
+    /// Packets queued in the FIFO by [`write`](Self::write) are sent as the next transfer.
+    pub fn handle_transfer_complete(&self, cs: CriticalSection<'_>) -> bool {
+        if !self.zlp.borrow(cs).replace(false) {
+            #[cfg(not(feature = "dma"))]
+            self.send_queued(cs);
+            return true;
+        }
+
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        #[cfg(feature = "fs")]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: 0);
+        #[cfg(feature = "hs")]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: 1, XFRSIZ: 0);
+        self.select_frame_parity();
+        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+        false
+    }
+
+    /// Starts a transfer of the packets already in the FIFO.
+    #[cfg(not(feature = "dma"))]
+    fn send_queued(&self, cs: CriticalSection<'_>) {
+        let queue = self.queue.borrow(cs);
+        let mut packets = queue.get();
+        if packets.is_empty() {
+            return;
+        }
+        let (count, size) = packets.take_transfer(self.descriptor.packet_size() as usize);
+        queue.set(packets);
+
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        #[cfg(feature = "fs")]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: count as u32, XFRSIZ: size as u32);
+        #[cfg(feature = "hs")]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: count as u32, XFRSIZ: size as u32);
+        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+    }
+
+    /// Drops the packet of an isochronous endpoint that missed its (micro)frame.
+    ///
+    /// Returns `true` if a packet was dropped, the endpoint can then be written again.
+    pub fn handle_incomplete_isochronous(&self, cs: CriticalSection<'_>) -> bool {
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        if !self.is_isochronous_due(read_reg!(endpoint_in, regs, DIEPCTL)) {
+            return false;
+        }
+
+        modify_reg!(endpoint_in, regs, DIEPCTL, SNAK: 1, EPDIS: 1);
+        while read_reg!(endpoint_in, regs, DIEPINT, EPDISD) == 0 {}
+        write_reg!(endpoint_in, regs, DIEPINT, EPDISD: 1);
+
+        // Flush the remains of the packet
+        let global = self.usb.global();
+        modify_reg!(otg_global, global, GRSTCTL, TXFNUM: self.index() as u32, TXFFLSH: 1);
+        while read_reg!(otg_global, global, GRSTCTL, TXFFLSH) == 1 {}
+
+        self.transfer.borrow(cs).set(&[]);
+        self.zlp.borrow(cs).set(false);
+        true
+    }
+}
+
+/// Multi-packet OUT transfer into a caller-provided buffer.
+struct OutTransfer {
+    buffer: &'static mut [u8],
+    /// Size programmed in DOEPTSIZ, a multiple of the max packet size
+    size: usize,
+    received: usize,
+    short_packet: bool,
+    complete: bool,
 }
 
 pub struct EndpointOut {
     common: Endpoint,
     pub(crate) buffer: Mutex<RefCell<EndpointBuffer>>,
+    transfer: Mutex<RefCell<Option<OutTransfer>>>,
 }
 
 impl EndpointOut {
@@ -160,10 +567,15 @@ impl EndpointOut {
         EndpointOut {
             common: Endpoint::new::<USB>(descriptor),
             buffer: Mutex::new(RefCell::new(buffer)),
+            transfer: Mutex::new(RefCell::new(None)),
         }
     }
 
     pub fn configure(&self, _cs: CriticalSection<'_>) {
+        // The transfer must be set up before the endpoint is enabled
+        #[cfg(feature = "dma")]
+        self.prepare_dma(_cs);
+
         if self.index() == 0 {
             let mpsiz = match self.descriptor.max_packet_size {
                 8 => 0b11,
@@ -179,22 +591,25 @@ impl EndpointOut {
         } else {
             let regs = self.usb.endpoint_out(self.index() as usize);
             write_reg!(endpoint_out, regs, DOEPCTL,
-                SD0PID_SEVNFRM: 1,
-                CNAK: 1,
-                EPENA: 1,
+                SD0PID_SEVNFRM: !self.is_isochronous() as u32,
                 USBAEP: 1,
                 EPTYP: self.descriptor.ep_type.to_bm_attributes() as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                MPSIZ: self.descriptor.packet_size() as u32
             );
+            self.select_frame_parity();
+            modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1, EPENA: 1);
         }
     }
 
-    pub fn deconfigure(&self, _cs: CriticalSection<'_>) {
+    pub fn deconfigure(&self, cs: CriticalSection<'_>) {
         let regs = self.usb.endpoint_out(self.index() as usize);
 
         // deactivating endpoint
         modify_reg!(endpoint_out, regs, DOEPCTL, USBAEP: 0);
 
+        // dropping a multi-packet transfer in progress
+        self.transfer.borrow_ref_mut(cs).take();
+
         // disabling endpoint
         if read_reg!(endpoint_out, regs, DOEPCTL, EPENA) != 0 && self.index() != 0 {
             modify_reg!(endpoint_out, regs, DOEPCTL, EPDIS: 1)
@@ -205,7 +620,236 @@ impl EndpointOut {
     }
 
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
-        critical_section::with(|cs| self.buffer.borrow_ref_mut(cs).read_packet(buf))
+        critical_section::with(|cs| {
+            let mut buffer = self.buffer.borrow_ref_mut(cs);
+            #[cfg(not(feature = "dma"))]
+            let was_full = buffer.is_full();
+            let result = buffer.read_packet(buf);
+            drop(buffer);
+
+            // A slot is free again, the endpoint NAKed since the last one was filled
+            #[cfg(not(feature = "dma"))]
+            if result.is_ok() && was_full && !self.transfer_armed(cs) {
+                let regs = self.usb.endpoint_out(self.index() as usize);
+                modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1);
+            }
+
+            // The endpoint NAKs until the buffer is free again
+            #[cfg(feature = "dma")]
+            if result.is_ok() {
+                self.prepare_dma(cs);
+                let regs = self.usb.endpoint_out(self.index() as usize);
+                self.select_frame_parity();
+                modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1, EPENA: 1);
+            }
+
+            result
+        })
+    }
+
+    /// Re-enables the endpoint for the next packet once the previous one is in the buffer.
+    ///
+    /// The endpoint NAKs while all the packet slots are full, until [`read`](Self::read) frees
+    /// one.
+    pub fn enable_next_packet(&self, cs: CriticalSection<'_>) {
+        self.select_frame_parity();
+        let regs = self.usb.endpoint_out(self.index() as usize);
+        if self.buffer.borrow_ref(cs).is_full() {
+            modify_reg!(endpoint_out, regs, DOEPCTL, SNAK: 1, EPENA: 1);
+        } else {
+            modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1, EPENA: 1);
+        }
+    }
+
+    /// Moves the pending transaction of an isochronous endpoint that missed its (micro)frame to
+    /// the next one.
+    pub fn handle_incomplete_isochronous(&self) {
+        let regs = self.usb.endpoint_out(self.index() as usize);
+        if self.is_isochronous_due(read_reg!(endpoint_out, regs, DOEPCTL)) {
+            self.select_frame_parity();
+        }
+    }
+
+    /// Arms the endpoint for a transfer of several packets into `buf`.
+    ///
+    /// The transfer completes when `buf` is full (rounded down to a multiple of the max packet
+    /// size) or on a short packet. On error, `buf` is handed back.
+    pub fn read_transfer(
+        &self,
+        buf: &'static mut [u8],
+    ) -> core::result::Result<(), (UsbError, &'static mut [u8])> {
+        if self.index() == 0 {
+            return Err((UsbError::Unsupported, buf));
+        }
+
+        let max_packet_size = self.descriptor.packet_size() as usize;
+        let packets = (buf.len().min(MAX_TRANSFER_SIZE) / max_packet_size).min(MAX_PACKET_COUNT);
+        if packets == 0 {
+            return Err((UsbError::BufferOverflow, buf));
+        }
+
+        // The DMA stores the data directly in the buffer, word by word
+        #[cfg(feature = "dma")]
+        if buf.as_ptr() as usize & 3 != 0 {
+            return Err((UsbError::Unsupported, buf));
+        }
+
+        critical_section::with(|cs| {
+            let mut transfer = self.transfer.borrow_ref_mut(cs);
+            if transfer.is_some()
+                || self.buffer.borrow_ref(cs).state() != EndpointBufferState::Empty
+            {
+                return Err((UsbError::WouldBlock, buf));
+            }
+
+            let size = packets * max_packet_size;
+            let regs = self.usb.endpoint_out(self.index() as usize);
+            // The endpoint is armed for the next packet, it has to be disabled before its
+            // transfer size can be changed
+            if read_reg!(endpoint_out, regs, DOEPCTL, EPENA) != 0 {
+                modify_reg!(endpoint_out, regs, DOEPCTL, SNAK: 1, EPDIS: 1);
+                while read_reg!(endpoint_out, regs, DOEPINT, EPDISD) == 0 {}
+                write_reg!(endpoint_out, regs, DOEPINT, EPDISD: 1);
+            }
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: packets as u32, XFRSIZ: size as u32);
+            #[cfg(feature = "dma")]
+            write_reg!(endpoint_out, regs, DOEPDMA, buf.as_ptr() as u32);
+
+            *transfer = Some(OutTransfer {
+                buffer: buf,
+                size,
+                received: 0,
+                short_packet: false,
+                complete: false,
+            });
+
+            self.select_frame_parity();
+            modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1, EPENA: 1);
+            Ok(())
+        })
+    }
+
+    /// Returns the buffer of a completed multi-packet transfer and the number of bytes received.
+    ///
+    /// The endpoint goes back to single-packet operation.
+    pub fn take_transfer(&self) -> Result<(&'static mut [u8], usize)> {
+        critical_section::with(|cs| {
+            let mut transfer = self.transfer.borrow_ref_mut(cs);
+            match transfer.as_ref() {
+                None => return Err(UsbError::InvalidState),
+                Some(t) if !t.complete => return Err(UsbError::WouldBlock),
+                Some(_) => {}
+            }
+            let t = transfer.take().unwrap();
+
+            let regs = self.usb.endpoint_out(self.index() as usize);
+            #[cfg(feature = "dma")]
+            self.prepare_dma(cs);
+            #[cfg(not(feature = "dma"))]
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: 1, XFRSIZ: self.descriptor.packet_size() as u32);
+            self.select_frame_parity();
+            modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1, EPENA: 1);
+
+            Ok((t.buffer, t.received))
+        })
+    }
+
+    /// Returns `true` if a multi-packet transfer is waiting for data.
+    pub fn transfer_armed(&self, cs: CriticalSection<'_>) -> bool {
+        matches!(&*self.transfer.borrow_ref(cs), Some(t) if !t.complete)
+    }
+
+    /// Moves a received packet from the Rx FIFO to the buffer of the multi-packet transfer.
+    pub fn fill_transfer_from_fifo(
+        &self,
+        cs: CriticalSection<'_>,
+        usb: UsbRegisters,
+        data_size: u16,
+    ) {
+        let mut transfer = self.transfer.borrow_ref_mut(cs);
+        let transfer = match transfer.as_mut() {
+            Some(t) => t,
+            None => return,
+        };
+
+        let data_size = data_size as usize;
+        let end = transfer.received + data_size;
+        if end <= transfer.buffer.len() {
+            fifo_read(usb, &mut transfer.buffer[transfer.received..end]);
+            transfer.received = end;
+        } else {
+            fifo_discard(usb, data_size.div_ceil(4));
+        }
+        if data_size < self.descriptor.packet_size() as usize {
+            transfer.short_packet = true;
+        }
+    }
+
+    /// Handles an OUT transfer completed status.
+    ///
+    /// Returns `true` if it ended the multi-packet transfer.
+    pub fn complete_transfer(&self, cs: CriticalSection<'_>) -> bool {
+        match self.transfer.borrow_ref_mut(cs).as_mut() {
+            // A completed status can still be pending for a packet received before the transfer
+            // was armed
+            Some(t) if !t.complete && (t.short_packet || t.received >= t.size) => {
+                t.complete = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Programs the endpoint to receive the next packet into its buffer with DMA.
+    #[cfg(feature = "dma")]
+    fn prepare_dma(&self, cs: CriticalSection<'_>) {
+        let address = self.buffer.borrow_ref(cs).dma_address();
+        let size = self.descriptor.packet_size() as u32;
+
+        if self.index() == 0 {
+            let regs = self.usb.endpoint0_out();
+            write_reg!(endpoint0_out, regs, DOEPDMA0, address);
+            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 3, PKTCNT: 1, XFRSIZ: size);
+        } else {
+            let regs = self.usb.endpoint_out(self.index() as usize);
+            write_reg!(endpoint_out, regs, DOEPDMA, address);
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: 1, XFRSIZ: size);
+        }
+    }
+
+    /// Handles the completion of a DMA transfer, the packet is then available in the buffer.
+    ///
+    /// Returns `true` if it ended a multi-packet transfer.
+    #[cfg(feature = "dma")]
+    pub fn complete_dma(&self, cs: CriticalSection<'_>) -> bool {
+        let regs = self.usb.endpoint_out(self.index() as usize);
+        let doepint = read_reg!(endpoint_out, regs, DOEPINT);
+        write_reg!(endpoint_out, regs, DOEPINT, doepint);
+
+        if let Some(t) = self.transfer.borrow_ref_mut(cs).as_mut() {
+            if doepint & endpoint_out::DOEPINT::XFRC::mask == 0 || t.complete {
+                return false;
+            }
+            let remaining = read_reg!(endpoint_out, regs, DOEPTSIZ, XFRSIZ) as usize;
+            t.received = t.size.saturating_sub(remaining);
+            t.complete = true;
+            return true;
+        }
+
+        let mut buffer = self.buffer.borrow_ref_mut(cs);
+        if doepint & endpoint_out::DOEPINT::STUP::mask != 0 {
+            // Back-to-back SETUP packets are stored one after the other, the DMA address points
+            // past the last one
+            let end = read_reg!(endpoint_out, regs, DOEPDMA);
+            let offset_words =
+                (end.wrapping_sub(buffer.dma_address()) as usize / 4).saturating_sub(2);
+            buffer.complete_dma(offset_words, 8, true);
+        } else if doepint & endpoint_out::DOEPINT::XFRC::mask != 0 {
+            let remaining = read_reg!(endpoint_out, regs, DOEPTSIZ, XFRSIZ);
+            let size = (self.descriptor.packet_size() as u32).saturating_sub(remaining);
+            buffer.complete_dma(0, size as u16, false);
+        }
+        false
     }
 
     pub fn buffer_state(&self) -> EndpointBufferState {
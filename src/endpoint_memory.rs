@@ -13,38 +13,66 @@ pub enum EndpointBufferState {
     DataSetup,
 }
 
-pub struct EndpointBuffer {
-    buffer: &'static mut [VolatileCell<u32>],
+/// Maximum number of packets an OUT endpoint buffer holds
+pub const MAX_PACKET_SLOTS: usize = 8;
+
+#[derive(Copy, Clone, Default)]
+struct Packet {
     data_size: u16,
-    has_data: bool,
     is_setup: bool,
 }
 
+/// Ring of packet slots of an OUT endpoint.
+pub struct EndpointBuffer {
+    buffer: &'static mut [VolatileCell<u32>],
+    slot_size_words: usize,
+    slot_count: usize,
+    packets: [Packet; MAX_PACKET_SLOTS],
+    /// Slot of the oldest packet
+    head: usize,
+    /// Number of packets in the buffer
+    len: usize,
+}
+
 impl EndpointBuffer {
     pub fn new(buffer: &'static mut [u32]) -> Self {
+        Self::new_ring(buffer, 1)
+    }
+
+    /// Splits `buffer` in `slots` packet slots.
+    pub fn new_ring(buffer: &'static mut [u32], slots: usize) -> Self {
+        let slot_count = slots.clamp(1, MAX_PACKET_SLOTS);
         Self {
+            slot_size_words: buffer.len() / slot_count,
+            slot_count,
             buffer: unsafe { &mut *(buffer as *mut [u32] as *mut [VolatileCell<u32>]) },
-            data_size: 0,
-            has_data: false,
-            is_setup: false,
+            packets: [Packet::default(); MAX_PACKET_SLOTS],
+            head: 0,
+            len: 0,
         }
     }
 
+    fn slot(&self, index: usize) -> &[VolatileCell<u32>] {
+        let offset = index * self.slot_size_words;
+        &self.buffer[offset..offset + self.slot_size_words]
+    }
+
     pub fn read_packet(&mut self, mut buf: &mut [u8]) -> Result<usize> {
-        if !self.has_data {
+        if self.len == 0 {
             return Err(UsbError::WouldBlock);
         }
 
-        let data_size = self.data_size as usize;
+        let data_size = self.packets[self.head].data_size as usize;
 
         if buf.len() < data_size {
             return Err(UsbError::BufferOverflow);
         }
 
+        let slot = self.slot(self.head);
         let mut index = 0;
         let mut current_size = data_size;
         while current_size >= 4 {
-            let word = self.buffer[index].get();
+            let word = slot[index].get();
             index += 1;
 
             let bytes = word.to_ne_bytes();
@@ -54,12 +82,13 @@ impl EndpointBuffer {
             current_size -= 4;
         }
         if current_size > 0 {
-            let word = self.buffer[index].get();
+            let word = slot[index].get();
             let bytes = word.to_ne_bytes();
             buf[..current_size].copy_from_slice(&bytes[..current_size]);
         }
 
-        self.has_data = false;
+        self.head = (self.head + 1) % self.slot_count;
+        self.len -= 1;
 
         Ok(data_size)
     }
@@ -70,7 +99,7 @@ impl EndpointBuffer {
         data_size: u16,
         is_setup: bool,
     ) -> Result<()> {
-        if self.has_data {
+        if self.is_full() {
             return Err(UsbError::WouldBlock);
         }
 
@@ -78,30 +107,95 @@ impl EndpointBuffer {
             return Err(UsbError::BufferOverflow);
         }
 
+        let tail = (self.head + self.len) % self.slot_count;
         let words = (data_size as usize + 3) / 4;
-        fifo_read_into(usb, &self.buffer[..words]);
+        fifo_read_into(usb, &self.slot(tail)[..words]);
 
-        self.is_setup = is_setup;
-        self.data_size = data_size;
-        self.has_data = true;
+        self.packets[tail] = Packet {
+            data_size,
+            is_setup,
+        };
+        self.len += 1;
 
         Ok(())
     }
 
-    pub fn state(&self) -> EndpointBufferState {
-        if self.has_data {
-            if self.is_setup {
-                EndpointBufferState::DataSetup
-            } else {
-                EndpointBufferState::DataOut
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Returns `true` if no slot is free for the next packet.
+    pub fn is_full(&self) -> bool {
+        self.len == self.slot_count
+    }
+
+    /// Returns the address the core reads or writes packets with DMA.
+    #[cfg(feature = "dma")]
+    pub fn dma_address(&self) -> u32 {
+        self.buffer.as_ptr() as u32
+    }
+
+    /// Marks a packet written by DMA as received.
+    ///
+    /// `offset_words` is the position of the packet in the buffer, it is moved to the start.
+    /// The buffer holds a single packet with DMA.
+    #[cfg(feature = "dma")]
+    pub fn complete_dma(&mut self, offset_words: usize, data_size: u16, is_setup: bool) {
+        let words = (data_size as usize).div_ceil(4);
+        if offset_words != 0 && offset_words + words <= self.buffer.len() {
+            for index in 0..words {
+                let word = self.buffer[offset_words + index].get();
+                self.buffer[index].set(word);
             }
-        } else {
+        }
+
+        self.packets[0] = Packet {
+            data_size,
+            is_setup,
+        };
+        self.head = 0;
+        self.len = 1;
+    }
+
+    /// Copies a packet into the buffer, to be fetched by DMA or written to a Tx FIFO later.
+    pub fn fill_from_slice(&mut self, mut buf: &[u8]) {
+        let mut index = 0;
+        while buf.len() >= 4 {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes.copy_from_slice(&buf[..4]);
+            buf = &buf[4..];
+            self.buffer[index].set(u32::from_ne_bytes(u32_bytes));
+            index += 1;
+        }
+        if !buf.is_empty() {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes[..buf.len()].copy_from_slice(buf);
+            self.buffer[index].set(u32::from_ne_bytes(u32_bytes));
+        }
+    }
+
+    /// Writes the packet copied with `fill_from_slice` into the Tx FIFO of `channel`.
+    pub fn write_to_fifo(&self, usb: UsbRegisters, channel: usize, data_size: usize) {
+        let fifo = usb.fifo(channel);
+        for word in &self.buffer[..data_size.div_ceil(4)] {
+            fifo.write(word.get());
+        }
+    }
+
+    pub fn state(&self) -> EndpointBufferState {
+        if self.len == 0 {
             EndpointBufferState::Empty
+        } else if self.packets[self.head].is_setup {
+            EndpointBufferState::DataSetup
+        } else {
+            EndpointBufferState::DataOut
         }
     }
 
+    /// Returns the size of a packet slot in bytes.
     pub fn capacity(&self) -> usize {
-        self.buffer.len() * 4
+        self.slot_size_words * 4
     }
 }
 
@@ -111,43 +205,177 @@ impl Default for EndpointBuffer {
     }
 }
 
+/// Layout of the FIFO memory of the core.
+///
+/// The default layout sizes the Rx FIFO after the OUT endpoint buffers and each Tx FIFO after
+/// the max packet size of its IN endpoint. Sizes are in 32-bit words.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FifoConfig {
+    /// Size of the Rx FIFO shared by all the OUT endpoints, automatic if `None`
+    ///
+    /// An explicit size must hold the largest OUT packet and its status word.
+    pub rx_fifo_size_words: Option<u16>,
+    /// Depth of the Tx FIFO of each IN endpoint, automatic if `None`
+    ///
+    /// An explicit depth must hold the packets of `tx_fifo_packets`, automatic depths are at
+    /// least 16 words.
+    pub tx_fifo_size_words: [Option<u16>; 9],
+    /// Number of max size packets the Tx FIFO of each IN endpoint holds, 1 if `None`
+    ///
+    /// Deeper FIFOs let bulk and interrupt endpoints queue packets while the previous ones
+    /// are sent.
+    pub tx_fifo_packets: [Option<u8>; 9],
+    /// Number of packets buffered in `ep_memory` for each OUT endpoint, 1 if `None`
+    ///
+    /// An OUT endpoint NAKs while its packet slots are full, so that its packets don't wait in
+    /// the Rx FIFO shared with the other endpoints. Ignored with the `dma` feature.
+    pub out_packet_slots: [Option<u8>; 9],
+    /// Size of the FIFO memory left unused, at the end of the FIFO memory
+    pub reserved_words: u16,
+}
+
+impl FifoConfig {
+    /// Returns the automatic layout.
+    pub const fn new() -> Self {
+        Self {
+            rx_fifo_size_words: None,
+            tx_fifo_size_words: [None; 9],
+            tx_fifo_packets: [None; 9],
+            out_packet_slots: [None; 9],
+            reserved_words: 0,
+        }
+    }
+
+    /// Sets the size of the Rx FIFO.
+    pub const fn rx_fifo_size_words(mut self, size: u16) -> Self {
+        self.rx_fifo_size_words = Some(size);
+        self
+    }
+
+    /// Sets the depth of the Tx FIFO of an IN endpoint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ep_number` is above 8, at compile time in a const context.
+    pub const fn tx_fifo_size_words(mut self, ep_number: usize, size: u16) -> Self {
+        self.tx_fifo_size_words[ep_number] = Some(size);
+        self
+    }
+
+    /// Sets the number of max size packets the Tx FIFO of an IN endpoint holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ep_number` is above 8, at compile time in a const context.
+    pub const fn tx_fifo_packets(mut self, ep_number: usize, packets: u8) -> Self {
+        self.tx_fifo_packets[ep_number] = Some(packets);
+        self
+    }
+
+    /// Sets the number of packets buffered for an OUT endpoint, up to 8.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ep_number` is above 8, at compile time in a const context.
+    pub const fn out_packet_slots(mut self, ep_number: usize, slots: u8) -> Self {
+        self.out_packet_slots[ep_number] = Some(slots);
+        self
+    }
+
+    /// Leaves part of the FIFO memory unused.
+    pub const fn reserved_words(mut self, size: u16) -> Self {
+        self.reserved_words = size;
+        self
+    }
+}
+
+/// Minimum depth of a Tx FIFO
+const MIN_TX_FIFO_SIZE_WORDS: usize = 16;
+
 pub struct EndpointMemoryAllocator<USB> {
     next_free_offset: usize,
     max_size_words: usize,
     memory: &'static mut [u32],
     tx_fifo_size_words: [u16; 9],
+    fifo_config: FifoConfig,
+    /// Memory used by the additional packet slots of OUT endpoints, that doesn't take room in
+    /// the Rx FIFO
+    rx_slots_size_words: usize,
+    /// Memory used by IN endpoint buffers, that doesn't take room in the Rx FIFO
+    #[cfg(feature = "dma")]
+    dma_tx_size_words: usize,
     _marker: PhantomData<USB>,
 }
 
 impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
-    pub fn new(memory: &'static mut [u32]) -> Self {
+    pub fn new(memory: &'static mut [u32], fifo_config: FifoConfig) -> Self {
         Self {
             next_free_offset: 0,
             max_size_words: 0,
             memory,
             tx_fifo_size_words: [0; 9],
+            fifo_config,
+            rx_slots_size_words: 0,
+            #[cfg(feature = "dma")]
+            dma_tx_size_words: 0,
             _marker: PhantomData,
         }
     }
 
-    pub fn allocate_rx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
-        let size_words = (size + 3) / 4;
-
+    fn allocate_words(&mut self, size_words: usize) -> Result<&'static mut [u32]> {
         let offset = self.next_free_offset;
         if offset + size_words > self.memory.len() {
             return Err(UsbError::EndpointMemoryOverflow);
         }
 
         self.next_free_offset += size_words;
-        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
 
         let buffer = unsafe {
             let ptr = self.memory.as_mut_ptr().add(offset);
             slice::from_raw_parts_mut(ptr, size_words)
         };
+        Ok(buffer)
+    }
+
+    pub fn allocate_rx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        let size_words = size.div_ceil(4);
+        let buffer = self.allocate_words(size_words)?;
+        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
         Ok(EndpointBuffer::new(buffer))
     }
 
+    /// Allocates the packet slots of an OUT endpoint, as configured by the `FifoConfig`.
+    pub fn allocate_rx_slots(&mut self, ep_number: u8, size: usize) -> Result<EndpointBuffer> {
+        let slots = if cfg!(feature = "dma") {
+            1
+        } else {
+            let slots = self.fifo_config.out_packet_slots[ep_number as usize].unwrap_or(1);
+            (slots as usize).clamp(1, MAX_PACKET_SLOTS)
+        };
+
+        let size_words = size.div_ceil(4);
+        if let Some(rx_fifo_size_words) = self.fifo_config.rx_fifo_size_words {
+            if (rx_fifo_size_words as usize) < size_words + 1 {
+                return Err(UsbError::BufferOverflow);
+            }
+        }
+        // The automatic Rx FIFO grows with the first slot
+        self.check_fifo_fit(size_words, 0)?;
+
+        let buffer = self.allocate_words(size_words * slots)?;
+        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
+        self.rx_slots_size_words += size_words * (slots - 1);
+        Ok(EndpointBuffer::new_ring(buffer, slots))
+    }
+
+    /// Allocates the buffer an IN endpoint transmits from with DMA.
+    #[cfg(feature = "dma")]
+    pub fn allocate_dma_tx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        let buffer = self.allocate_rx_buffer(size)?;
+        self.dma_tx_size_words += size.div_ceil(4);
+        Ok(buffer)
+    }
+
     pub fn allocate_tx_buffer(&mut self, ep_number: u8, size: usize) -> Result<()> {
         let ep_number = ep_number as usize;
         assert!(ep_number < self.tx_fifo_size_words.len());
@@ -156,23 +384,63 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
             return Err(UsbError::InvalidEndpoint);
         }
 
-        let used = 30
-            + self.total_rx_buffer_size_words() as usize
-            + self.tx_fifo_size_words.iter().sum::<u16>() as usize;
-
-        let size_words = core::cmp::max((size + 3) / 4, 16);
-        if (used + size_words) > USB::FIFO_DEPTH_WORDS {
-            return Err(UsbError::EndpointMemoryOverflow);
-        }
+        let packets = self.fifo_config.tx_fifo_packets[ep_number].unwrap_or(1) as usize;
+        let packets_size_words = packets * size.div_ceil(4);
+        let size_words = match self.fifo_config.tx_fifo_size_words[ep_number] {
+            Some(size_words) if (size_words as usize) < packets_size_words => {
+                return Err(UsbError::BufferOverflow);
+            }
+            Some(size_words) => size_words as usize,
+            None => core::cmp::max(packets_size_words, MIN_TX_FIFO_SIZE_WORDS),
+        };
+        self.check_fifo_fit(0, size_words)?;
 
         self.tx_fifo_size_words[ep_number] = size_words as u16;
 
         Ok(())
     }
 
+    /// Checks that the FIFOs still fit in the FIFO memory of the core after the automatic Rx
+    /// FIFO grows by `rx_size_words` and a Tx FIFO of `tx_size_words` is added.
+    fn check_fifo_fit(&self, rx_size_words: usize, tx_size_words: usize) -> Result<()> {
+        let rx_size_words = match self.fifo_config.rx_fifo_size_words {
+            Some(size) => size as usize,
+            None => self.rx_fifo_size_words() as usize + rx_size_words,
+        };
+        let used = rx_size_words
+            + self.tx_fifo_size_words.iter().sum::<u16>() as usize
+            + tx_size_words
+            + self.fifo_config.reserved_words as usize;
+        if used > USB::FIFO_DEPTH_WORDS {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+        Ok(())
+    }
+
     /// Returns the size of memory allocated for OUT endpoints in words
     pub fn total_rx_buffer_size_words(&self) -> u16 {
-        self.next_free_offset as u16
+        let size = self.next_free_offset - self.rx_slots_size_words;
+        #[cfg(feature = "dma")]
+        let size = size - self.dma_tx_size_words;
+        size as u16
+    }
+
+    /// Returns the size of the Rx FIFO in words
+    pub fn rx_fifo_size_words(&self) -> u16 {
+        match self.fifo_config.rx_fifo_size_words {
+            Some(size) => size,
+            // This calculation doesn't correspond to one in a Reference Manual.
+            // In fact, the required number of words is higher than indicated in RM.
+            // The following numbers are pessimistic and were figured out empirically.
+            // F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
+            // F446 requires 39+ words for the same setup
+            None => self.total_rx_buffer_size_words() + 30,
+        }
+    }
+
+    /// Returns the size of the FIFO memory left unused in words
+    pub fn reserved_words(&self) -> u16 {
+        self.fifo_config.reserved_words
     }
 
     pub fn tx_fifo_size_words(&self, ep_number: usize) -> u16 {
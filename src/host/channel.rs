@@ -0,0 +1,654 @@
+//! Host channel allocation and transfers.
+
+use super::{Speed, UsbHost};
+use crate::endpoint_memory::EndpointBuffer;
+use crate::ral::{host_channel, modify_reg, otg_global, otg_host, read_reg, write_reg};
+use crate::target::{fifo_discard, fifo_write, UsbRegisters};
+use crate::UsbPeripheral;
+use critical_section::CriticalSection;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+
+/// Maximum number of host channels supported by Synopsys cores.
+pub(crate) const MAX_CHANNELS: usize = 16;
+
+/// PID values for HCTSIZ.DPID
+const PID_DATA0: u32 = 0b00;
+const PID_DATA1: u32 = 0b10;
+const PID_SETUP: u32 = 0b11;
+
+/// Channel interrupts that terminate a transaction
+#[cfg(feature = "fs")]
+const HCINT_MASK: u32 = host_channel::HCINT::XFRC::mask
+    | host_channel::HCINT::CHH::mask
+    | host_channel::HCINT::STALL::mask
+    | host_channel::HCINT::NAK::mask
+    | host_channel::HCINT::TXERR::mask
+    | host_channel::HCINT::BBERR::mask
+    | host_channel::HCINT::FRMOR::mask
+    | host_channel::HCINT::DTERR::mask;
+/// Channel interrupts that terminate a transaction, plus NYET of split transactions
+#[cfg(feature = "hs")]
+const HCINT_MASK: u32 = host_channel::HCINT::XFRC::mask
+    | host_channel::HCINT::CHH::mask
+    | host_channel::HCINT::STALL::mask
+    | host_channel::HCINT::NAK::mask
+    | host_channel::HCINT::NYET::mask
+    | host_channel::HCINT::TXERR::mask
+    | host_channel::HCINT::BBERR::mask
+    | host_channel::HCINT::FRMOR::mask
+    | host_channel::HCINT::DTERR::mask;
+
+/// Errors reported by host transfers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HostError {
+    /// The transfer is still in progress, or there is no room to start it yet.
+    WouldBlock,
+    /// The device responded with NAK. The transfer can be retried.
+    Nak,
+    /// The device responded with STALL.
+    Stall,
+    /// The data toggle of the received packet did not match the expected one.
+    DataToggle,
+    /// The transaction failed because of a CRC error, timeout, bit stuff error or false EOP.
+    Transaction,
+    /// The device sent more data than requested.
+    Babble,
+    /// A periodic transaction could not be completed in the scheduled frame.
+    FrameOverrun,
+    /// The buffer is too small for the data, or the packet does not fit into the channel buffer.
+    BufferOverflow,
+    /// All host channels are in use.
+    NoChannel,
+    /// The pipe has no transfer in progress, or is otherwise unusable.
+    InvalidPipe,
+    /// The device was detached during the transfer.
+    Disconnected,
+}
+
+/// Result type for host operations.
+pub type Result<T> = core::result::Result<T, HostError>;
+
+/// Parameters of a pipe, a host channel bound to a device endpoint.
+#[derive(Copy, Clone, Debug)]
+pub struct PipeConfig {
+    /// Address of the device.
+    pub device_address: u8,
+
+    /// Endpoint address. The direction is ignored for control pipes.
+    pub endpoint: EndpointAddress,
+
+    /// Endpoint transfer type.
+    pub ep_type: EndpointType,
+
+    /// Maximum packet size of the endpoint.
+    pub max_packet_size: u16,
+
+    /// Speed of the device.
+    pub speed: Speed,
+
+    /// Poll interval (`bInterval`) for interrupt and isochronous endpoints.
+    pub interval: u8,
+
+    /// Address of the high-speed hub whose transaction translator serves a full-speed or
+    /// low-speed device, 0 if no split transactions are needed.
+    ///
+    /// Only used by high-speed cores with a high-speed device on the root port.
+    pub hub_address: u8,
+
+    /// Port of the high-speed hub the device is attached to.
+    pub hub_port: u8,
+}
+
+impl PipeConfig {
+    pub(super) fn is_periodic(&self) -> bool {
+        matches!(
+            self.ep_type,
+            EndpointType::Interrupt | EndpointType::Isochronous { .. }
+        )
+    }
+}
+
+/// Handle to a pipe allocated with [`UsbHost::alloc_pipe`].
+#[derive(Debug, Eq, PartialEq)]
+pub struct Pipe {
+    channel: u8,
+}
+
+impl Pipe {
+    /// Returns the host channel number used by the pipe.
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub(super) enum ChannelState {
+    Idle,
+    Busy,
+    Done(Result<usize>),
+}
+
+pub(crate) struct Channel {
+    pub(super) config: Option<PipeConfig>,
+    buffer: EndpointBuffer,
+    pub(super) state: ChannelState,
+    /// Outcome of the transaction, reported once the channel is halted
+    pending: Option<Result<usize>>,
+    /// `true` if the next data packet is DATA1
+    data_toggle: bool,
+    is_in: bool,
+    out_len: usize,
+    /// `true` if a packet for a scheduled OUT pipe waits in the buffer
+    pub(super) out_queued: bool,
+    pid: u32,
+    /// `true` if the halted channel has to be re-enabled to continue the transaction
+    restart: bool,
+    /// `true` if the next split transaction is a complete-split
+    #[cfg(feature = "hs")]
+    pub(super) complete_split: bool,
+    /// Microframe of the complete-split of a scheduled periodic pipe, issued on SOF
+    #[cfg(feature = "hs")]
+    pub(super) complete_split_frame: Option<u16>,
+    /// Poll interval in (micro)frames of a scheduled periodic pipe, 0 if not scheduled
+    pub(super) interval: u16,
+    /// Frame number of the next scheduled transaction
+    pub(super) next_frame: u16,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            config: None,
+            buffer: EndpointBuffer::default(),
+            state: ChannelState::Idle,
+            pending: None,
+            data_toggle: false,
+            is_in: false,
+            out_len: 0,
+            out_queued: false,
+            pid: PID_DATA0,
+            restart: false,
+            #[cfg(feature = "hs")]
+            complete_split: false,
+            #[cfg(feature = "hs")]
+            complete_split_frame: None,
+            interval: 0,
+            next_frame: 0,
+        }
+    }
+}
+
+impl Channel {
+    /// Programs the channel registers and starts a single-packet transaction.
+    pub(super) fn start(
+        &mut self,
+        regs: UsbRegisters,
+        index: usize,
+        is_in: bool,
+        pid: Option<u32>,
+        data: &[u8],
+    ) -> Result<()> {
+        self.start_with(regs, index, is_in, pid, data.len(), Some(data))
+    }
+
+    /// Copies a packet into the buffer, to be sent by [`start_queued`](Self::start_queued).
+    pub(super) fn queue_out(&mut self, data: &[u8]) {
+        self.buffer.fill_from_slice(data);
+        self.out_len = data.len();
+        self.out_queued = true;
+    }
+
+    /// Starts sending the packet queued in the buffer of a scheduled OUT pipe.
+    pub(super) fn start_queued(&mut self, regs: UsbRegisters, index: usize) -> Result<()> {
+        let len = self.out_len;
+        self.start_with(regs, index, false, None, len, None)
+    }
+
+    /// Starts a transaction of `len` bytes, sending `data` or the packet queued in the buffer
+    /// if `data` is `None`.
+    fn start_with(
+        &mut self,
+        regs: UsbRegisters,
+        index: usize,
+        is_in: bool,
+        pid: Option<u32>,
+        len: usize,
+        data: Option<&[u8]>,
+    ) -> Result<()> {
+        let config = self.config.ok_or(HostError::InvalidPipe)?;
+
+        if self.state == ChannelState::Busy {
+            return Err(HostError::WouldBlock);
+        }
+        if len > config.max_packet_size as usize {
+            return Err(HostError::BufferOverflow);
+        }
+
+        // Check for FIFO and request queue space
+        let size_words = if is_in { 0 } else { len.div_ceil(4) };
+        let (fifo_space, queue_space) = if config.is_periodic() {
+            read_reg!(otg_host, regs.host(), HPTXSTS, PTXFSAVL, PTXQSAV)
+        } else {
+            read_reg!(otg_global, regs.global(), GNPTXSTS, NPTXFSAV, NPTQXSAV)
+        };
+        if size_words > fifo_space as usize || queue_space == 0 {
+            return Err(HostError::WouldBlock);
+        }
+
+        self.pid = pid.unwrap_or(if self.data_toggle {
+            PID_DATA1
+        } else {
+            PID_DATA0
+        });
+        self.is_in = is_in;
+        self.out_len = len;
+        self.restart = false;
+        #[cfg(feature = "hs")]
+        {
+            self.complete_split = false;
+            self.complete_split_frame = None;
+        }
+        self.enable(regs, index);
+
+        if !is_in && len != 0 {
+            match data {
+                Some(data) => fifo_write(regs, index, data),
+                None => self.buffer.write_to_fifo(regs, index, len),
+            }
+        }
+
+        if self.pid == PID_SETUP {
+            // XFRC flips the toggle, so the data stage starts with DATA1
+            self.data_toggle = false;
+        }
+        self.buffer.clear();
+        self.state = ChannelState::Busy;
+        self.pending = None;
+
+        Ok(())
+    }
+
+    /// Returns the channel interrupts handled for the pipe.
+    fn interrupt_mask(&self) -> u32 {
+        // Only the start-split of a split transaction is acknowledged with an ACK interrupt
+        #[cfg(feature = "hs")]
+        if self.is_split() {
+            return HCINT_MASK | host_channel::HCINT::ACK::mask;
+        }
+        HCINT_MASK
+    }
+
+    /// Programs the channel registers for the current transaction and enables the channel.
+    pub(super) fn enable(&self, regs: UsbRegisters, index: usize) {
+        let config = match self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        let size = if self.is_in {
+            config.max_packet_size as u32
+        } else {
+            self.out_len as u32
+        };
+        // The data of a split OUT transaction is sent with the start-split only
+        #[cfg(feature = "hs")]
+        let size = if self.complete_split && !self.is_in {
+            0
+        } else {
+            size
+        };
+
+        let ch_regs = regs.host_channel(index);
+        write_reg!(host_channel, ch_regs, HCINT, 0xffff_ffff);
+        write_reg!(host_channel, ch_regs, HCTSIZ, PKTCNT: 1, XFRSIZ: size, DPID: self.pid);
+        #[cfg(feature = "hs")]
+        self.write_split(regs, index);
+
+        // Periodic transactions are scheduled for the next (micro)frame
+        let next_frame_odd = read_reg!(otg_host, regs.host(), HFNUM, FRNUM) & 1 == 0;
+        write_reg!(host_channel, ch_regs, HCCHAR,
+            MPSIZ: config.max_packet_size as u32,
+            EPNUM: config.endpoint.index() as u32,
+            EPDIR: self.is_in as u32,
+            LSDEV: (config.speed == Speed::Low) as u32,
+            EPTYP: (config.ep_type.to_bm_attributes() & 0b11) as u32,
+            MC: 1,
+            DAD: config.device_address as u32,
+            ODDFRM: (config.is_periodic() && next_frame_odd) as u32,
+            CHENA: 1
+        );
+    }
+
+    /// Split transactions are only supported by high-speed cores.
+    #[cfg(feature = "fs")]
+    fn handle_split(&mut self, _hcint: u32, _frame: u16) -> bool {
+        false
+    }
+}
+
+/// Splits the channel memory evenly between all the channels.
+pub(crate) fn init_channels<USB: UsbPeripheral>(
+    memory: &'static mut [u32],
+) -> [Channel; MAX_CHANNELS] {
+    assert!(USB::HOST_CHANNEL_COUNT <= MAX_CHANNELS);
+
+    let mut channels: [Channel; MAX_CHANNELS] = Default::default();
+    let chunk_size = memory.len() / USB::HOST_CHANNEL_COUNT;
+    if chunk_size > 0 {
+        for (channel, chunk) in channels.iter_mut().zip(memory.chunks_mut(chunk_size)) {
+            channel.buffer = EndpointBuffer::new(chunk);
+        }
+    }
+    channels
+}
+
+impl<USB: UsbPeripheral> UsbHost<USB> {
+    /// Allocates a host channel for the endpoint described by `config`.
+    pub fn alloc_pipe(&self, config: PipeConfig) -> Result<Pipe> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let mut channels = self.channels.borrow_ref_mut(cs);
+
+            let index = channels[..USB::HOST_CHANNEL_COUNT]
+                .iter()
+                .position(|ch| ch.config.is_none())
+                .ok_or(HostError::NoChannel)?;
+
+            let channel = &mut channels[index];
+            if config.max_packet_size as usize > channel.buffer.capacity() {
+                return Err(HostError::BufferOverflow);
+            }
+            channel.config = Some(config);
+            channel.state = ChannelState::Idle;
+            channel.pending = None;
+            channel.data_toggle = false;
+            channel.out_queued = false;
+
+            let ch_regs = regs.host_channel(index);
+            write_reg!(host_channel, ch_regs, HCINT, 0xffff_ffff);
+            write_reg!(host_channel, ch_regs, HCINTMSK, channel.interrupt_mask());
+            modify_reg!(otg_host, regs.host(), HAINTMSK, |r| r | (1 << index));
+
+            Ok(Pipe {
+                channel: index as u8,
+            })
+        })
+    }
+
+    /// Changes the parameters of a pipe, e.g. after the device address was assigned.
+    ///
+    /// The data toggle is reset to DATA0.
+    pub fn configure_pipe(&self, pipe: &Pipe, config: PipeConfig) -> Result<()> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            let channel = &mut channels[pipe.channel as usize];
+            if channel.state == ChannelState::Busy {
+                return Err(HostError::WouldBlock);
+            }
+            if config.max_packet_size as usize > channel.buffer.capacity() {
+                return Err(HostError::BufferOverflow);
+            }
+            channel.config = Some(config);
+            channel.data_toggle = false;
+
+            let ch_regs = regs.host_channel(pipe.channel as usize);
+            write_reg!(host_channel, ch_regs, HCINTMSK, channel.interrupt_mask());
+            Ok(())
+        })
+    }
+
+    /// Returns the parameters of a pipe.
+    ///
+    /// Returns [`HostError::InvalidPipe`] if the pipe was freed meanwhile.
+    pub fn pipe_config(&self, pipe: &Pipe) -> Result<PipeConfig> {
+        critical_section::with(|cs| {
+            let channels = self.channels.borrow_ref(cs);
+            channels[pipe.channel as usize]
+                .config
+                .ok_or(HostError::InvalidPipe)
+        })
+    }
+
+    /// Releases the host channel used by the pipe, aborting a transfer in progress.
+    pub fn free_pipe(&self, pipe: Pipe) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            let index = pipe.channel as usize;
+
+            let ch_regs = regs.host_channel(index);
+            if read_reg!(host_channel, ch_regs, HCCHAR, CHENA) != 0 {
+                modify_reg!(host_channel, ch_regs, HCCHAR, CHDIS: 1, CHENA: 1);
+            }
+            write_reg!(host_channel, ch_regs, HCINTMSK, 0);
+            modify_reg!(otg_host, regs.host(), HAINTMSK, |r| r & !(1 << index));
+
+            let channel = &mut channels[index];
+            channel.config = None;
+            channel.state = ChannelState::Idle;
+            channel.interval = 0;
+            channel.out_queued = false;
+            channel.buffer.clear();
+
+            self.update_sof_mask(*regs, &channels);
+        });
+    }
+
+    /// Sets the data toggle used for the next data packet of the pipe.
+    ///
+    /// `data1 == true` selects DATA1. The toggle is maintained automatically after successful
+    /// transactions, this is only needed to start the data and status stages of control
+    /// transfers or after clearing an endpoint halt.
+    pub fn set_data_toggle(&self, pipe: &Pipe, data1: bool) {
+        critical_section::with(|cs| {
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            channels[pipe.channel as usize].data_toggle = data1;
+        });
+    }
+
+    /// Starts the SETUP stage of a control transfer.
+    ///
+    /// The data toggle is set to DATA1 once the SETUP packet is acknowledged.
+    pub fn start_setup(&self, pipe: &Pipe, setup: &[u8; 8]) -> Result<()> {
+        critical_section::with(|cs| self.start_transaction(cs, pipe, false, Some(PID_SETUP), setup))
+    }
+
+    /// Starts sending a single packet to an OUT endpoint.
+    pub fn start_out(&self, pipe: &Pipe, data: &[u8]) -> Result<()> {
+        critical_section::with(|cs| self.start_transaction(cs, pipe, false, None, data))
+    }
+
+    /// Starts receiving a single packet from an IN endpoint.
+    pub fn start_in(&self, pipe: &Pipe) -> Result<()> {
+        critical_section::with(|cs| self.start_transaction(cs, pipe, true, None, &[]))
+    }
+
+    /// Returns the outcome of the last transfer started on the pipe.
+    ///
+    /// For IN transfers the received packet is copied into `buf`, for OUT transfers `buf` is not
+    /// used. Returns the number of bytes transferred, [`HostError::WouldBlock`] if the transfer
+    /// is still in progress or [`HostError::InvalidPipe`] if no transfer was started.
+    pub fn poll_transfer(&self, pipe: &Pipe, buf: &mut [u8]) -> Result<usize> {
+        critical_section::with(|cs| {
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            let channel = &mut channels[pipe.channel as usize];
+            match channel.state {
+                ChannelState::Idle => Err(HostError::InvalidPipe),
+                ChannelState::Busy => Err(HostError::WouldBlock),
+                ChannelState::Done(result) => {
+                    channel.state = ChannelState::Idle;
+                    match result {
+                        Ok(_) if channel.is_in => channel
+                            .buffer
+                            .read_packet(buf)
+                            .map_err(|_| HostError::BufferOverflow),
+                        Ok(len) => Ok(len),
+                        Err(e) => {
+                            channel.buffer.clear();
+                            Err(e)
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn start_transaction(
+        &self,
+        cs: CriticalSection<'_>,
+        pipe: &Pipe,
+        is_in: bool,
+        pid: Option<u32>,
+        data: &[u8],
+    ) -> Result<()> {
+        let regs = self.regs.borrow(cs);
+        let mut channels = self.channels.borrow_ref_mut(cs);
+        let index = pipe.channel as usize;
+        channels[index].start(*regs, index, is_in, pid, data)
+    }
+
+    /// Reads the packets available in the Rx FIFO into the channel buffers.
+    pub(crate) fn handle_rx_fifo(&self, cs: CriticalSection<'_>, regs: UsbRegisters) {
+        let mut channels = self.channels.borrow_ref_mut(cs);
+
+        while read_reg!(otg_global, regs.global(), GINTSTS, RXFLVL) != 0 {
+            let (index, data_size, status) =
+                read_reg!(otg_global, regs.global(), GRXSTSP, EPNUM, BCNT, PKTSTS);
+
+            // IN data packet received
+            if status == 0b0010 {
+                let channel = &mut channels[index as usize];
+                if channel
+                    .buffer
+                    .fill_from_fifo(regs, data_size as u16, false)
+                    .is_err()
+                {
+                    fifo_discard(regs, (data_size as usize).div_ceil(4));
+                    channel.pending = Some(Err(HostError::BufferOverflow));
+                }
+            }
+        }
+    }
+
+    /// Processes channel interrupts, returns a bitmask of channels with completed transfers.
+    pub(crate) fn handle_channels(&self, cs: CriticalSection<'_>, regs: UsbRegisters) -> u16 {
+        let mut channels = self.channels.borrow_ref_mut(cs);
+        let mut completed = 0;
+
+        let haint = read_reg!(otg_host, regs.host(), HAINT);
+        let frame = read_reg!(otg_host, regs.host(), HFNUM, FRNUM) as u16;
+        for (index, channel) in channels[..USB::HOST_CHANNEL_COUNT].iter_mut().enumerate() {
+            if haint & (1 << index) == 0 {
+                continue;
+            }
+
+            let ch_regs = regs.host_channel(index);
+            let hcint = read_reg!(host_channel, ch_regs, HCINT) & channel.interrupt_mask();
+            write_reg!(host_channel, ch_regs, HCINT, hcint);
+
+            let outcome = if hcint & host_channel::HCINT::XFRC::mask != 0 {
+                // Full-speed isochronous packets are always DATA0
+                let isochronous = matches!(
+                    channel.config.map(|config| config.ep_type),
+                    Some(EndpointType::Isochronous { .. })
+                );
+                if !isochronous {
+                    channel.data_toggle = !channel.data_toggle;
+                }
+                Some(Ok(if channel.is_in { 0 } else { channel.out_len }))
+            } else if hcint & host_channel::HCINT::STALL::mask != 0 {
+                Some(Err(HostError::Stall))
+            } else if hcint & host_channel::HCINT::NAK::mask != 0 {
+                Some(Err(HostError::Nak))
+            } else if hcint & host_channel::HCINT::TXERR::mask != 0 {
+                Some(Err(HostError::Transaction))
+            } else if hcint & host_channel::HCINT::BBERR::mask != 0 {
+                Some(Err(HostError::Babble))
+            } else if hcint & host_channel::HCINT::DTERR::mask != 0 {
+                Some(Err(HostError::DataToggle))
+            } else if hcint & host_channel::HCINT::FRMOR::mask != 0 {
+                Some(Err(HostError::FrameOverrun))
+            } else {
+                None
+            };
+
+            // The start-split was acknowledged or the complete-split has to be retried
+            let restart = outcome.is_none()
+                && channel.state == ChannelState::Busy
+                && channel.handle_split(hcint, frame);
+
+            if let Some(outcome) = outcome {
+                // An earlier error (e.g. an Rx buffer overflow) takes precedence
+                channel.pending.get_or_insert(outcome);
+            }
+            if outcome.is_some() || restart {
+                channel.restart |= restart;
+
+                if hcint & host_channel::HCINT::CHH::mask == 0 {
+                    // Halt the channel, the outcome is reported on CHH
+                    if read_reg!(host_channel, ch_regs, HCCHAR, CHENA) != 0 {
+                        modify_reg!(host_channel, ch_regs, HCCHAR, CHDIS: 1, CHENA: 1);
+                        continue;
+                    }
+                }
+            } else if hcint & host_channel::HCINT::CHH::mask == 0 {
+                continue;
+            }
+
+            if channel.state == ChannelState::Busy {
+                if channel.restart && channel.pending.is_none() {
+                    channel.restart = false;
+                    // Periodic complete-splits wait for their microframe
+                    #[cfg(feature = "hs")]
+                    if channel.complete_split_frame.is_some() {
+                        continue;
+                    }
+                    channel.enable(regs, index);
+                    continue;
+                }
+
+                let result = channel
+                    .pending
+                    .take()
+                    .unwrap_or(Err(HostError::Transaction));
+                match result {
+                    // Scheduled pipes are silently re-armed in the next interval
+                    Err(HostError::Nak) | Err(HostError::FrameOverrun) if channel.interval != 0 => {
+                        channel.state = ChannelState::Idle;
+                    }
+                    _ => {
+                        channel.state = ChannelState::Done(result);
+                        channel.out_queued = false;
+                        completed |= 1 << index;
+                    }
+                }
+            }
+        }
+
+        completed
+    }
+
+    /// Aborts all the transfers in progress after the device was detached.
+    pub(crate) fn abort_channels(&self, cs: CriticalSection<'_>, regs: UsbRegisters) {
+        let mut channels = self.channels.borrow_ref_mut(cs);
+
+        for (index, channel) in channels[..USB::HOST_CHANNEL_COUNT].iter_mut().enumerate() {
+            let ch_regs = regs.host_channel(index);
+            if read_reg!(host_channel, ch_regs, HCCHAR, CHENA) != 0 {
+                modify_reg!(host_channel, ch_regs, HCCHAR, CHDIS: 1, CHENA: 1);
+            }
+            if channel.state == ChannelState::Busy {
+                channel.state = ChannelState::Done(Err(HostError::Disconnected));
+            }
+            channel.pending = None;
+            channel.restart = false;
+            channel.out_queued = false;
+            channel.buffer.clear();
+        }
+
+        // Flush Rx & Tx FIFOs
+        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+    }
+}
@@ -0,0 +1,491 @@
+//! Control transfers over a host pipe.
+
+use super::{HostError, Pipe, Result, UsbHost};
+use crate::UsbPeripheral;
+use usb_device::control::Request;
+use usb_device::UsbDirection;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Stage {
+    Setup,
+    SetupWait,
+    Data,
+    DataWait,
+    Status,
+    StatusWait,
+    Done,
+}
+
+/// A control transfer driven by repeated calls to [`ControlTransfer::poll`].
+///
+/// The transfer retries transactions that were NAKed by the device and reports the first other
+/// error.
+pub struct ControlTransfer {
+    request: Request,
+    stage: Stage,
+    transferred: usize,
+}
+
+impl ControlTransfer {
+    /// Creates a transfer for the given request. Nothing is sent until the first `poll`.
+    pub fn new(request: Request) -> Self {
+        Self {
+            request,
+            stage: Stage::Setup,
+            transferred: 0,
+        }
+    }
+
+    /// Returns the request of this transfer.
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// Advances the transfer.
+    ///
+    /// `data` holds the data to send for OUT requests and receives the data of IN requests, it
+    /// must be at least `request.length` bytes long. Returns the length of the data stage once the
+    /// status stage has completed, or [`HostError::WouldBlock`] while the transfer is in progress.
+    /// Data sent by the device beyond `request.length` is reported as
+    /// [`HostError::BufferOverflow`].
+    pub fn poll<USB: UsbPeripheral>(
+        &mut self,
+        host: &UsbHost<USB>,
+        pipe: &Pipe,
+        data: &mut [u8],
+    ) -> Result<usize> {
+        self.advance(&HostPipe { host, pipe }, data)
+    }
+
+    fn advance(&mut self, pipe: &impl Transactions, data: &mut [u8]) -> Result<usize> {
+        let length = self.request.length as usize;
+        if data.len() < length {
+            return Err(HostError::BufferOverflow);
+        }
+        let is_in = self.request.direction == UsbDirection::In;
+
+        loop {
+            match self.stage {
+                Stage::Setup => {
+                    pipe.start_setup(&setup_packet(&self.request))?;
+                    self.stage = Stage::SetupWait;
+                    return Err(HostError::WouldBlock);
+                }
+                Stage::SetupWait => match pipe.poll_transfer(&mut []) {
+                    Ok(_) => {
+                        self.transferred = 0;
+                        self.stage = if length > 0 {
+                            Stage::Data
+                        } else {
+                            Stage::Status
+                        };
+                    }
+                    Err(HostError::Nak) | Err(HostError::Transaction) => {
+                        self.stage = Stage::Setup;
+                    }
+                    Err(e) => return self.fail(e),
+                },
+                Stage::Data => {
+                    let result = if is_in {
+                        pipe.start_in()
+                    } else {
+                        let mps = pipe.max_packet_size()?;
+                        let end = core::cmp::min(self.transferred + mps, length);
+                        pipe.start_out(&data[self.transferred..end])
+                    };
+                    result?;
+                    self.stage = Stage::DataWait;
+                    return Err(HostError::WouldBlock);
+                }
+                // A device sending more than wLength bytes fails with BufferOverflow
+                Stage::DataWait => {
+                    match pipe.poll_transfer(&mut data[self.transferred..length]) {
+                        Ok(size) => {
+                            self.transferred += size;
+                            let mps = match pipe.max_packet_size() {
+                                Ok(mps) => mps,
+                                Err(e) => return self.fail(e),
+                            };
+                            // A short packet ends the data stage
+                            self.stage = if self.transferred >= length || size < mps {
+                                Stage::Status
+                            } else {
+                                Stage::Data
+                            };
+                        }
+                        Err(HostError::Nak) => {
+                            self.stage = Stage::Data;
+                        }
+                        Err(e) => return self.fail(e),
+                    }
+                }
+                Stage::Status => {
+                    // The status stage always uses DATA1 and the opposite direction of the data
+                    pipe.set_data_toggle(true);
+                    if is_in && length > 0 {
+                        pipe.start_out(&[])?;
+                    } else {
+                        pipe.start_in()?;
+                    }
+                    self.stage = Stage::StatusWait;
+                    return Err(HostError::WouldBlock);
+                }
+                Stage::StatusWait => match pipe.poll_transfer(&mut []) {
+                    Ok(_) => {
+                        self.stage = Stage::Done;
+                        return Ok(self.transferred);
+                    }
+                    Err(HostError::Nak) => {
+                        self.stage = Stage::Status;
+                    }
+                    Err(e) => return self.fail(e),
+                },
+                Stage::Done => return Ok(self.transferred),
+            }
+        }
+    }
+
+    fn fail(&mut self, error: HostError) -> Result<usize> {
+        if error != HostError::WouldBlock {
+            self.stage = Stage::Setup;
+        }
+        Err(error)
+    }
+}
+
+/// Transactions on the pipe of a control transfer.
+trait Transactions {
+    fn start_setup(&self, setup: &[u8; 8]) -> Result<()>;
+    fn start_out(&self, data: &[u8]) -> Result<()>;
+    fn start_in(&self) -> Result<()>;
+    fn poll_transfer(&self, buf: &mut [u8]) -> Result<usize>;
+    fn set_data_toggle(&self, data1: bool);
+    fn max_packet_size(&self) -> Result<usize>;
+}
+
+struct HostPipe<'a, USB> {
+    host: &'a UsbHost<USB>,
+    pipe: &'a Pipe,
+}
+
+impl<USB: UsbPeripheral> Transactions for HostPipe<'_, USB> {
+    fn start_setup(&self, setup: &[u8; 8]) -> Result<()> {
+        self.host.start_setup(self.pipe, setup)
+    }
+
+    fn start_out(&self, data: &[u8]) -> Result<()> {
+        self.host.start_out(self.pipe, data)
+    }
+
+    fn start_in(&self) -> Result<()> {
+        self.host.start_in(self.pipe)
+    }
+
+    fn poll_transfer(&self, buf: &mut [u8]) -> Result<usize> {
+        self.host.poll_transfer(self.pipe, buf)
+    }
+
+    fn set_data_toggle(&self, data1: bool) {
+        self.host.set_data_toggle(self.pipe, data1)
+    }
+
+    fn max_packet_size(&self) -> Result<usize> {
+        Ok(self.host.pipe_config(self.pipe)?.max_packet_size as usize)
+    }
+}
+
+/// Serializes a control request into a SETUP packet.
+pub fn setup_packet(request: &Request) -> [u8; 8] {
+    let request_type = ((request.direction == UsbDirection::In) as u8) << 7
+        | (request.request_type as u8) << 5
+        | request.recipient as u8;
+    let value = request.value.to_le_bytes();
+    let index = request.index.to_le_bytes();
+    let length = request.length.to_le_bytes();
+    [
+        request_type,
+        request.request,
+        value[0],
+        value[1],
+        index[0],
+        index[1],
+        length[0],
+        length[1],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+    use usb_device::control::{Recipient, RequestType};
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Call {
+        Setup,
+        Out(usize),
+        In,
+        Toggle(bool),
+    }
+
+    /// Transaction in progress on the mock pipe
+    struct Transaction {
+        is_in: bool,
+        out_len: usize,
+        response: Result<&'static [u8]>,
+    }
+
+    /// Pipe to a device that answers the transactions as scripted.
+    ///
+    /// Every transaction takes the next response: the data of an IN packet, or an error. OUT
+    /// and SETUP transactions ignore the data. Transactions are acknowledged once the script is
+    /// exhausted.
+    struct MockPipe {
+        max_packet_size: usize,
+        responses: RefCell<VecDeque<Result<&'static [u8]>>>,
+        current: RefCell<Option<Transaction>>,
+        calls: RefCell<Vec<Call>>,
+    }
+
+    impl MockPipe {
+        fn new(max_packet_size: usize, responses: &[Result<&'static [u8]>]) -> Self {
+            Self {
+                max_packet_size,
+                responses: RefCell::new(responses.iter().copied().collect()),
+                current: RefCell::new(None),
+                calls: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn start(&self, is_in: bool, out_len: usize, call: Call) -> Result<()> {
+            let response = self.responses.borrow_mut().pop_front().unwrap_or(Ok(&[]));
+            *self.current.borrow_mut() = Some(Transaction {
+                is_in,
+                out_len,
+                response,
+            });
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+    }
+
+    impl Transactions for MockPipe {
+        fn start_setup(&self, _setup: &[u8; 8]) -> Result<()> {
+            self.start(false, 8, Call::Setup)
+        }
+
+        fn start_out(&self, data: &[u8]) -> Result<()> {
+            self.start(false, data.len(), Call::Out(data.len()))
+        }
+
+        fn start_in(&self) -> Result<()> {
+            self.start(true, 0, Call::In)
+        }
+
+        fn poll_transfer(&self, buf: &mut [u8]) -> Result<usize> {
+            let transaction = self
+                .current
+                .borrow_mut()
+                .take()
+                .ok_or(HostError::InvalidPipe)?;
+            let packet = transaction.response?;
+            if !transaction.is_in {
+                return Ok(transaction.out_len);
+            }
+            let buf = buf
+                .get_mut(..packet.len())
+                .ok_or(HostError::BufferOverflow)?;
+            buf.copy_from_slice(packet);
+            Ok(packet.len())
+        }
+
+        fn set_data_toggle(&self, data1: bool) {
+            self.calls.borrow_mut().push(Call::Toggle(data1));
+        }
+
+        fn max_packet_size(&self) -> Result<usize> {
+            Ok(self.max_packet_size)
+        }
+    }
+
+    fn request(direction: UsbDirection, length: u16) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Device,
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length,
+        }
+    }
+
+    /// Polls the transfer until it finishes.
+    fn run(transfer: &mut ControlTransfer, pipe: &MockPipe, data: &mut [u8]) -> Result<usize> {
+        for _ in 0..32 {
+            match transfer.advance(pipe, data) {
+                Err(HostError::WouldBlock) => {}
+                result => return result,
+            }
+        }
+        panic!("the transfer did not finish");
+    }
+
+    #[test]
+    fn setup_packet_layout() {
+        let mut request = request(UsbDirection::In, 0x0112);
+        request.value = 0x0304;
+        request.index = 0x0506;
+        assert_eq!(
+            setup_packet(&request),
+            [0xc0, 0x01, 0x04, 0x03, 0x06, 0x05, 0x12, 0x01]
+        );
+    }
+
+    #[test]
+    fn in_transfer() {
+        let pipe = MockPipe::new(8, &[Ok(&[]), Ok(&[1; 8]), Ok(&[2; 4])]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::In, 12));
+        let mut data = [0; 16];
+
+        assert_eq!(run(&mut transfer, &pipe, &mut data), Ok(12));
+        assert_eq!(data[..12], [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(
+            *pipe.calls.borrow(),
+            [
+                Call::Setup,
+                Call::In,
+                Call::In,
+                Call::Toggle(true),
+                Call::Out(0)
+            ]
+        );
+        // The finished transfer keeps reporting its length
+        assert_eq!(transfer.advance(&pipe, &mut data), Ok(12));
+    }
+
+    #[test]
+    fn short_packet_ends_data_stage() {
+        let pipe = MockPipe::new(8, &[Ok(&[]), Ok(&[1; 8]), Ok(&[2; 2])]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::In, 18));
+        let mut data = [0; 18];
+
+        assert_eq!(run(&mut transfer, &pipe, &mut data), Ok(10));
+        assert_eq!(
+            *pipe.calls.borrow(),
+            [
+                Call::Setup,
+                Call::In,
+                Call::In,
+                Call::Toggle(true),
+                Call::Out(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn excess_in_data_overflows() {
+        let pipe = MockPipe::new(8, &[Ok(&[]), Ok(&[1; 8])]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::In, 4));
+        let mut data = [0; 16];
+
+        assert_eq!(
+            run(&mut transfer, &pipe, &mut data),
+            Err(HostError::BufferOverflow)
+        );
+        // The next poll starts over with the SETUP stage
+        pipe.calls.borrow_mut().clear();
+        assert_eq!(
+            transfer.advance(&pipe, &mut data),
+            Err(HostError::WouldBlock)
+        );
+        assert_eq!(*pipe.calls.borrow(), [Call::Setup]);
+    }
+
+    #[test]
+    fn naks_are_retried() {
+        let pipe = MockPipe::new(
+            8,
+            &[
+                Err(HostError::Nak),
+                Ok(&[]),
+                Err(HostError::Nak),
+                Ok(&[1; 2]),
+                Err(HostError::Nak),
+            ],
+        );
+        let mut transfer = ControlTransfer::new(request(UsbDirection::In, 2));
+        let mut data = [0; 2];
+
+        assert_eq!(run(&mut transfer, &pipe, &mut data), Ok(2));
+        assert_eq!(
+            *pipe.calls.borrow(),
+            [
+                Call::Setup,
+                Call::Setup,
+                Call::In,
+                Call::In,
+                Call::Toggle(true),
+                Call::Out(0),
+                Call::Toggle(true),
+                Call::Out(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn out_transfer() {
+        let pipe = MockPipe::new(8, &[]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::Out, 10));
+        let mut data = [0; 10];
+
+        assert_eq!(run(&mut transfer, &pipe, &mut data), Ok(10));
+        assert_eq!(
+            *pipe.calls.borrow(),
+            [
+                Call::Setup,
+                Call::Out(8),
+                Call::Out(2),
+                Call::Toggle(true),
+                Call::In
+            ]
+        );
+    }
+
+    #[test]
+    fn no_data_stage() {
+        let pipe = MockPipe::new(8, &[]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::Out, 0));
+
+        assert_eq!(run(&mut transfer, &pipe, &mut []), Ok(0));
+        assert_eq!(
+            *pipe.calls.borrow(),
+            [Call::Setup, Call::Toggle(true), Call::In]
+        );
+    }
+
+    #[test]
+    fn stall_fails_transfer() {
+        let pipe = MockPipe::new(8, &[Ok(&[]), Err(HostError::Stall)]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::In, 8));
+        let mut data = [0; 8];
+
+        assert_eq!(run(&mut transfer, &pipe, &mut data), Err(HostError::Stall));
+    }
+
+    #[test]
+    fn short_buffer_is_rejected() {
+        let pipe = MockPipe::new(8, &[]);
+        let mut transfer = ControlTransfer::new(request(UsbDirection::In, 8));
+
+        assert_eq!(
+            transfer.advance(&pipe, &mut [0; 4]),
+            Err(HostError::BufferOverflow)
+        );
+        assert!(pipe.calls.borrow().is_empty());
+    }
+}
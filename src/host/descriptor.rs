@@ -0,0 +1,397 @@
+//! Parsers for standard USB descriptors.
+
+use usb_device::descriptor::descriptor_type;
+use usb_device::endpoint::{
+    EndpointAddress, EndpointType, IsochronousSynchronizationType, IsochronousUsageType,
+};
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Standard device descriptor.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceDescriptor {
+    /// USB specification release number in BCD.
+    pub usb_release: u16,
+    /// Device class code.
+    pub device_class: u8,
+    /// Device subclass code.
+    pub device_sub_class: u8,
+    /// Device protocol code.
+    pub device_protocol: u8,
+    /// Maximum packet size of endpoint 0.
+    pub max_packet_size_0: u8,
+    /// Vendor ID.
+    pub vendor_id: u16,
+    /// Product ID.
+    pub product_id: u16,
+    /// Device release number in BCD.
+    pub device_release: u16,
+    /// Index of the manufacturer string.
+    pub manufacturer_index: u8,
+    /// Index of the product string.
+    pub product_index: u8,
+    /// Index of the serial number string.
+    pub serial_number_index: u8,
+    /// Number of configurations.
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    /// Length of the device descriptor in bytes.
+    pub const SIZE: usize = 18;
+
+    /// Parses a device descriptor. Returns `None` if `buf` is not a complete device descriptor.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::SIZE || buf[1] != descriptor_type::DEVICE {
+            return None;
+        }
+
+        Some(Self {
+            usb_release: read_u16(buf, 2),
+            device_class: buf[4],
+            device_sub_class: buf[5],
+            device_protocol: buf[6],
+            max_packet_size_0: buf[7],
+            vendor_id: read_u16(buf, 8),
+            product_id: read_u16(buf, 10),
+            device_release: read_u16(buf, 12),
+            manufacturer_index: buf[14],
+            product_index: buf[15],
+            serial_number_index: buf[16],
+            num_configurations: buf[17],
+        })
+    }
+}
+
+/// Standard interface descriptor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InterfaceDescriptor {
+    /// Interface number.
+    pub interface_number: u8,
+    /// Alternate setting.
+    pub alternate_setting: u8,
+    /// Number of endpoints, excluding endpoint 0.
+    pub num_endpoints: u8,
+    /// Interface class code.
+    pub interface_class: u8,
+    /// Interface subclass code.
+    pub interface_sub_class: u8,
+    /// Interface protocol code.
+    pub interface_protocol: u8,
+    /// Index of the interface string.
+    pub interface_index: u8,
+}
+
+/// Standard endpoint descriptor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EndpointDescriptor {
+    /// Endpoint address.
+    pub address: EndpointAddress,
+    /// Endpoint transfer type.
+    pub ep_type: EndpointType,
+    /// Maximum packet size.
+    pub max_packet_size: u16,
+    /// Additional transactions per microframe for high-bandwidth endpoints.
+    pub additional_transactions: u8,
+    /// Poll interval.
+    pub interval: u8,
+}
+
+fn endpoint_type(attributes: u8) -> EndpointType {
+    match attributes & 0b11 {
+        0b00 => EndpointType::Control,
+        0b01 => EndpointType::Isochronous {
+            synchronization: match (attributes >> 2) & 0b11 {
+                0b00 => IsochronousSynchronizationType::NoSynchronization,
+                0b01 => IsochronousSynchronizationType::Asynchronous,
+                0b10 => IsochronousSynchronizationType::Adaptive,
+                _ => IsochronousSynchronizationType::Synchronous,
+            },
+            usage: match (attributes >> 4) & 0b11 {
+                0b01 => IsochronousUsageType::Feedback,
+                0b10 => IsochronousUsageType::ImplicitFeedbackData,
+                _ => IsochronousUsageType::Data,
+            },
+        },
+        0b10 => EndpointType::Bulk,
+        _ => EndpointType::Interrupt,
+    }
+}
+
+/// A descriptor found in a configuration descriptor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Descriptor<'a> {
+    /// An interface descriptor.
+    Interface(InterfaceDescriptor),
+    /// An endpoint descriptor that belongs to the last reported interface.
+    Endpoint(EndpointDescriptor),
+    /// Any other descriptor, e.g. a class-specific one.
+    Other {
+        /// Descriptor type.
+        descriptor_type: u8,
+        /// The whole descriptor including its header.
+        data: &'a [u8],
+    },
+}
+
+/// Standard configuration descriptor together with its interface and endpoint descriptors.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ConfigurationDescriptor<'a> {
+    /// Value to use with SET_CONFIGURATION to select this configuration.
+    pub configuration_value: u8,
+    /// Number of interfaces.
+    pub num_interfaces: u8,
+    /// Index of the configuration string.
+    pub configuration_index: u8,
+    /// Configuration characteristics (self-powered, remote wakeup).
+    pub attributes: u8,
+    /// Maximum power consumption in 2 mA units.
+    pub max_power: u8,
+    data: &'a [u8],
+}
+
+impl<'a> ConfigurationDescriptor<'a> {
+    /// Length of the configuration descriptor header in bytes.
+    pub const HEADER_SIZE: usize = 9;
+
+    /// Parses the configuration descriptor header and returns the total length of the
+    /// configuration descriptor set.
+    ///
+    /// Returns `None` if the header is malformed, i.e. if `bLength` is shorter than the header
+    /// or longer than `wTotalLength`.
+    pub fn total_length(buf: &[u8]) -> Option<u16> {
+        if buf.len() < Self::HEADER_SIZE || buf[1] != descriptor_type::CONFIGURATION {
+            return None;
+        }
+        let length = buf[0] as u16;
+        let total_length = read_u16(buf, 2);
+        if length < Self::HEADER_SIZE as u16 || length > total_length {
+            return None;
+        }
+        Some(total_length)
+    }
+
+    /// Parses a complete configuration descriptor set. Returns `None` if the descriptor set is
+    /// malformed or not complete.
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let total_length = Self::total_length(buf)? as usize;
+        if buf.len() < total_length {
+            return None;
+        }
+
+        Some(Self {
+            num_interfaces: buf[4],
+            configuration_value: buf[5],
+            configuration_index: buf[6],
+            attributes: buf[7],
+            max_power: buf[8],
+            data: &buf[..total_length],
+        })
+    }
+
+    /// Returns the raw descriptor set.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns an iterator over the descriptors that follow the configuration descriptor.
+    pub fn descriptors(&self) -> Descriptors<'a> {
+        Descriptors {
+            data: self.data.get(self.data[0] as usize..).unwrap_or(&[]),
+        }
+    }
+
+    /// Returns an iterator over the interface descriptors.
+    pub fn interfaces(&self) -> impl Iterator<Item = InterfaceDescriptor> + 'a {
+        self.descriptors().filter_map(|d| match d {
+            Descriptor::Interface(i) => Some(i),
+            _ => None,
+        })
+    }
+
+    /// Returns an iterator over the endpoint descriptors of the given interface and alternate
+    /// setting.
+    pub fn endpoints(
+        &self,
+        interface_number: u8,
+        alternate_setting: u8,
+    ) -> impl Iterator<Item = EndpointDescriptor> + 'a {
+        let mut selected = false;
+        self.descriptors().filter_map(move |d| match d {
+            Descriptor::Interface(i) => {
+                selected = i.interface_number == interface_number
+                    && i.alternate_setting == alternate_setting;
+                None
+            }
+            Descriptor::Endpoint(e) if selected => Some(e),
+            _ => None,
+        })
+    }
+}
+
+/// Iterator over the descriptors of a configuration descriptor set.
+#[derive(Clone)]
+pub struct Descriptors<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Descriptor<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let length = self.data[0] as usize;
+        if length < 2 || length > self.data.len() {
+            // Empty or truncated descriptor, stop here
+            self.data = &[];
+            return None;
+        }
+        let (d, rest) = self.data.split_at(length);
+        self.data = rest;
+
+        let descriptor = match d[1] {
+            descriptor_type::INTERFACE if length >= 9 => {
+                Descriptor::Interface(InterfaceDescriptor {
+                    interface_number: d[2],
+                    alternate_setting: d[3],
+                    num_endpoints: d[4],
+                    interface_class: d[5],
+                    interface_sub_class: d[6],
+                    interface_protocol: d[7],
+                    interface_index: d[8],
+                })
+            }
+            descriptor_type::ENDPOINT if length >= 7 => {
+                let max_packet_size = read_u16(d, 4);
+                Descriptor::Endpoint(EndpointDescriptor {
+                    address: EndpointAddress::from(d[2]),
+                    ep_type: endpoint_type(d[3]),
+                    max_packet_size: max_packet_size & 0x7ff,
+                    additional_transactions: ((max_packet_size >> 11) & 0b11) as u8,
+                    interval: d[6],
+                })
+            }
+            descriptor_type => Descriptor::Other {
+                descriptor_type,
+                data: d,
+            },
+        };
+        Some(descriptor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration with one interface, a bulk IN endpoint and a class-specific descriptor
+    const CONFIGURATION: [u8; 9 + 9 + 7 + 4] = [
+        9, 0x02, 29, 0, 1, 1, 0, 0x80, 50, // configuration
+        9, 0x04, 0, 0, 1, 0xff, 0, 0, 0, // interface 0
+        7, 0x05, 0x81, 0x02, 64, 0, 0, // bulk IN endpoint 1
+        4, 0x24, 0x01, 0x02, // class-specific
+    ];
+
+    #[test]
+    fn device_descriptor() {
+        let buf = [
+            18, 0x01, 0x00, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1,
+        ];
+        let descriptor = DeviceDescriptor::parse(&buf).unwrap();
+        assert_eq!(descriptor.usb_release, 0x0200);
+        assert_eq!(descriptor.max_packet_size_0, 64);
+        assert_eq!(descriptor.vendor_id, 0x1234);
+        assert_eq!(descriptor.product_id, 0x5678);
+        assert_eq!(descriptor.num_configurations, 1);
+
+        assert_eq!(DeviceDescriptor::parse(&buf[..8]), None);
+    }
+
+    #[test]
+    fn total_length() {
+        assert_eq!(
+            ConfigurationDescriptor::total_length(&CONFIGURATION[..9]),
+            Some(29)
+        );
+        // Too short, or not a configuration descriptor
+        assert_eq!(
+            ConfigurationDescriptor::total_length(&CONFIGURATION[..8]),
+            None
+        );
+        assert_eq!(
+            ConfigurationDescriptor::total_length(&[9, 0x01, 29, 0, 1, 1, 0, 0x80, 50]),
+            None
+        );
+        // bLength shorter than the header
+        assert_eq!(
+            ConfigurationDescriptor::total_length(&[2, 0x02, 29, 0, 1, 1, 0, 0x80, 50]),
+            None
+        );
+        // bLength longer than wTotalLength
+        assert_eq!(
+            ConfigurationDescriptor::total_length(&[9, 0x02, 4, 0, 1, 1, 0, 0x80, 50]),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_configuration() {
+        let configuration = ConfigurationDescriptor::parse(&CONFIGURATION).unwrap();
+        assert_eq!(configuration.configuration_value, 1);
+        assert_eq!(configuration.num_interfaces, 1);
+        assert_eq!(configuration.max_power, 50);
+        assert_eq!(configuration.data().len(), 29);
+
+        let interface = configuration.interfaces().next().unwrap();
+        assert_eq!(interface.interface_class, 0xff);
+        let mut endpoints = configuration.endpoints(0, 0);
+        assert_eq!(
+            endpoints.next(),
+            Some(EndpointDescriptor {
+                address: EndpointAddress::from(0x81),
+                ep_type: EndpointType::Bulk,
+                max_packet_size: 64,
+                additional_transactions: 0,
+                interval: 0,
+            })
+        );
+        assert_eq!(endpoints.next(), None);
+        assert_eq!(configuration.endpoints(0, 1).count(), 0);
+
+        // Incomplete descriptor set
+        assert_eq!(ConfigurationDescriptor::parse(&CONFIGURATION[..28]), None);
+    }
+
+    #[test]
+    fn descriptors_stop_at_truncated_descriptor() {
+        let mut buf = CONFIGURATION;
+        // The endpoint descriptor claims to extend past the end of the set
+        buf[18] = 12;
+        let configuration = ConfigurationDescriptor::parse(&buf).unwrap();
+        let mut descriptors = configuration.descriptors();
+        assert!(matches!(descriptors.next(), Some(Descriptor::Interface(_))));
+        assert_eq!(descriptors.next(), None);
+        assert_eq!(descriptors.next(), None);
+
+        // A zero bLength would never advance
+        buf[18] = 0;
+        let configuration = ConfigurationDescriptor::parse(&buf).unwrap();
+        assert_eq!(configuration.descriptors().count(), 1);
+    }
+
+    #[test]
+    fn descriptors_keep_other_descriptors() {
+        let configuration = ConfigurationDescriptor::parse(&CONFIGURATION).unwrap();
+        assert_eq!(
+            configuration.descriptors().last(),
+            Some(Descriptor::Other {
+                descriptor_type: 0x24,
+                data: &CONFIGURATION[25..],
+            })
+        );
+    }
+}
@@ -0,0 +1,415 @@
+//! Device enumeration.
+
+use super::control::ControlTransfer;
+use super::descriptor::{ConfigurationDescriptor, DeviceDescriptor};
+use super::periodic::FrameTimer;
+use super::{HostError, HostEvent, Pipe, PipeConfig, Speed, UsbHost};
+use crate::UsbPeripheral;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::descriptor::descriptor_type;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::UsbDirection;
+
+/// Progress reported by [`Enumerator::poll`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnumerationStatus {
+    /// Nothing to do: no device is attached, or the device has already been configured.
+    Idle,
+    /// Enumeration is in progress.
+    InProgress,
+    /// The device was configured. Reported once, the device can be used from now on.
+    Configured,
+    /// The device was detached.
+    Disconnected,
+    /// Enumeration failed. The device has to be detached or reset to start over.
+    Failed(HostError),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the connection to settle, since the given timestamp
+    Debounce(u32),
+    /// Driving reset on the root port, since the given timestamp
+    Reset(u32),
+    WaitPortEnabled,
+    ResetRecovery {
+        since: u32,
+        speed: Speed,
+    },
+    GetMaxPacketSize,
+    SetAddress,
+    AddressRecovery(FrameTimer),
+    GetDeviceDescriptor,
+    GetConfigurationHeader,
+    GetConfiguration,
+    SetConfiguration,
+    Configured,
+    Failed(HostError),
+}
+
+/// Enumerates a single device: resets it, reads its descriptors, assigns an address and selects
+/// the first configuration.
+///
+/// The enumerator is driven from the same loop that polls the host:
+///
+/// ```ignore
+/// let mut enumerator = Enumerator::new(1, config_buffer);
+/// loop {
+///     let event = host.poll();
+///     if enumerator.poll(&host, event, now_ms()) == EnumerationStatus::Configured {
+///         let config = enumerator.configuration().unwrap();
+///         // bind class drivers to config.interfaces()
+///     }
+/// }
+/// ```
+pub struct Enumerator<'a> {
+    state: State,
+    address: u8,
+    speed: Speed,
+    pipe: Option<Pipe>,
+    transfer: Option<ControlTransfer>,
+    device_descriptor: DeviceDescriptor,
+    buffer: &'a mut [u8],
+    configuration_length: usize,
+}
+
+impl<'a> Enumerator<'a> {
+    /// Creates an enumerator that assigns `address` to the device.
+    ///
+    /// `buffer` receives the configuration descriptor set and must be large enough to hold it.
+    pub fn new(address: u8, buffer: &'a mut [u8]) -> Self {
+        assert!(address > 0 && address < 128);
+        Self {
+            state: State::Idle,
+            address,
+            speed: Speed::Full,
+            pipe: None,
+            transfer: None,
+            device_descriptor: DeviceDescriptor::default(),
+            buffer,
+            configuration_length: 0,
+        }
+    }
+
+    /// Returns the address assigned to the device.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Returns the speed of the device.
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Returns the control pipe of the device once enumeration has started.
+    pub fn control_pipe(&self) -> Option<&Pipe> {
+        self.pipe.as_ref()
+    }
+
+    /// Returns the device descriptor once it has been read.
+    pub fn device_descriptor(&self) -> Option<&DeviceDescriptor> {
+        match self.state {
+            State::GetConfigurationHeader
+            | State::GetConfiguration
+            | State::SetConfiguration
+            | State::Configured => Some(&self.device_descriptor),
+            _ => None,
+        }
+    }
+
+    /// Returns the selected configuration once the device is configured.
+    pub fn configuration(&self) -> Option<ConfigurationDescriptor<'_>> {
+        match self.state {
+            State::Configured => {
+                ConfigurationDescriptor::parse(&self.buffer[..self.configuration_length])
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the device has been configured.
+    pub fn is_configured(&self) -> bool {
+        self.state == State::Configured
+    }
+
+    /// Starts enumerating a device that has just been reset and responds at address 0.
+    ///
+    /// This is done automatically for the device attached to the root port.
+    pub fn start<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>, speed: Speed) {
+        self.start_on_hub(host, speed, 0, 0);
+    }
+
+    /// Starts enumerating a device attached to a hub port.
+    ///
+    /// `hub_address` and `hub_port` select the transaction translator of a full-speed or
+    /// low-speed device behind a high-speed hub, see [`PipeConfig::hub_address`].
+    pub fn start_on_hub<USB: UsbPeripheral>(
+        &mut self,
+        host: &UsbHost<USB>,
+        speed: Speed,
+        hub_address: u8,
+        hub_port: u8,
+    ) {
+        self.release(host);
+        self.speed = speed;
+
+        let config = PipeConfig {
+            device_address: 0,
+            endpoint: EndpointAddress::from_parts(0, UsbDirection::Out),
+            ep_type: EndpointType::Control,
+            // Every device supports at least 8 bytes on endpoint 0
+            max_packet_size: 8,
+            speed,
+            interval: 0,
+            hub_address,
+            hub_port,
+        };
+        match host.alloc_pipe(config) {
+            Ok(pipe) => {
+                self.pipe = Some(pipe);
+                self.request_descriptor(descriptor_type::DEVICE, 8);
+                self.state = State::GetMaxPacketSize;
+            }
+            Err(e) => self.state = State::Failed(e),
+        }
+    }
+
+    /// Hands the control pipe over to a driver that takes care of the device, e.g. a [`Hub`].
+    ///
+    /// [`Hub`]: super::Hub
+    pub(super) fn take_control_pipe(&mut self) -> Option<Pipe> {
+        self.pipe.take()
+    }
+
+    /// Releases the control pipe and forgets the device.
+    pub fn release<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) {
+        if let Some(pipe) = self.pipe.take() {
+            host.free_pipe(pipe);
+        }
+        self.transfer = None;
+        self.state = State::Idle;
+    }
+
+    /// Advances the enumeration of the device attached to the root port.
+    ///
+    /// `event` is the value returned by [`UsbHost::poll`]. `now` is a timestamp in milliseconds,
+    /// e.g. from a free-running timer, that times the debounce interval and the port reset. It
+    /// may wrap around.
+    pub fn poll<USB: UsbPeripheral>(
+        &mut self,
+        host: &UsbHost<USB>,
+        event: HostEvent,
+        now: u32,
+    ) -> EnumerationStatus {
+        match event {
+            HostEvent::Connected => {
+                self.release_root_port(host);
+                self.state = State::Debounce(now);
+            }
+            HostEvent::PortEnabled(speed) if self.state == State::WaitPortEnabled => {
+                self.state = State::ResetRecovery { since: now, speed };
+            }
+            HostEvent::Disconnected => {
+                let was_idle = self.state == State::Idle;
+                self.release_root_port(host);
+                if !was_idle {
+                    return EnumerationStatus::Disconnected;
+                }
+            }
+            _ => {}
+        }
+
+        match self.state {
+            // Wait for the connection to settle before resetting the device (USB 2.0, 7.1.7.3)
+            State::Debounce(since) if now.wrapping_sub(since) >= 100 => {
+                host.set_port_reset(true);
+                self.state = State::Reset(now);
+            }
+            // Root ports must drive reset for at least 50 ms (USB 2.0, 7.1.7.5)
+            State::Reset(since) if now.wrapping_sub(since) >= 50 => {
+                host.set_port_reset(false);
+                self.state = State::WaitPortEnabled;
+            }
+            // Reset recovery time (USB 2.0, 7.1.7.5)
+            State::ResetRecovery { since, speed } if now.wrapping_sub(since) >= 10 => {
+                self.start(host, speed);
+            }
+            _ => {}
+        }
+
+        self.advance(host)
+    }
+
+    /// Advances the enumeration of a device started with [`start`](Self::start).
+    pub fn advance<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) -> EnumerationStatus {
+        match self.state {
+            State::Idle => return EnumerationStatus::Idle,
+            State::Debounce(_)
+            | State::Reset(_)
+            | State::WaitPortEnabled
+            | State::ResetRecovery { .. } => return EnumerationStatus::InProgress,
+            State::AddressRecovery(timer) => {
+                // SET_ADDRESS recovery interval (USB 2.0, 9.2.6.3)
+                if !timer.expired(host) {
+                    return EnumerationStatus::InProgress;
+                }
+                return self.configure_address(host);
+            }
+            State::Configured => return EnumerationStatus::Idle,
+            State::Failed(e) => return EnumerationStatus::Failed(e),
+            _ => {}
+        }
+
+        let (pipe, transfer) = match (&self.pipe, &mut self.transfer) {
+            (Some(pipe), Some(transfer)) => (pipe, transfer),
+            _ => return EnumerationStatus::Idle,
+        };
+
+        let result = transfer.poll(host, pipe, self.buffer);
+        let length = match result {
+            Ok(length) => length,
+            Err(HostError::WouldBlock) => return EnumerationStatus::InProgress,
+            Err(e) => {
+                self.state = State::Failed(e);
+                return EnumerationStatus::Failed(e);
+            }
+        };
+
+        match self.state {
+            State::GetMaxPacketSize => {
+                if length < 8 {
+                    return self.fail(HostError::Transaction);
+                }
+                let max_packet_size = self.buffer[7] as u16;
+                if !valid_ep0_max_packet_size(self.speed, max_packet_size) {
+                    return self.fail(HostError::Transaction);
+                }
+                let mut config = match host.pipe_config(pipe) {
+                    Ok(config) => config,
+                    Err(e) => return self.fail(e),
+                };
+                config.max_packet_size = max_packet_size;
+                if host.configure_pipe(pipe, config).is_err() {
+                    return self.fail(HostError::BufferOverflow);
+                }
+                self.transfer = Some(ControlTransfer::new(Request {
+                    direction: UsbDirection::Out,
+                    request_type: RequestType::Standard,
+                    recipient: Recipient::Device,
+                    request: Request::SET_ADDRESS,
+                    value: self.address as u16,
+                    index: 0,
+                    length: 0,
+                }));
+                self.state = State::SetAddress;
+            }
+            State::SetAddress => {
+                self.transfer = None;
+                self.state = State::AddressRecovery(FrameTimer::start(host, 2));
+            }
+            State::GetDeviceDescriptor => match DeviceDescriptor::parse(&self.buffer[..length]) {
+                Some(descriptor) => {
+                    self.device_descriptor = descriptor;
+                    self.request_descriptor(
+                        descriptor_type::CONFIGURATION,
+                        ConfigurationDescriptor::HEADER_SIZE as u16,
+                    );
+                    self.state = State::GetConfigurationHeader;
+                }
+                None => return self.fail(HostError::Transaction),
+            },
+            State::GetConfigurationHeader => {
+                match ConfigurationDescriptor::total_length(&self.buffer[..length]) {
+                    Some(total_length) if total_length as usize <= self.buffer.len() => {
+                        self.request_descriptor(descriptor_type::CONFIGURATION, total_length);
+                        self.state = State::GetConfiguration;
+                    }
+                    Some(_) => return self.fail(HostError::BufferOverflow),
+                    None => return self.fail(HostError::Transaction),
+                }
+            }
+            State::GetConfiguration => match ConfigurationDescriptor::parse(&self.buffer[..length])
+            {
+                Some(configuration) => {
+                    self.configuration_length = length;
+                    self.transfer = Some(ControlTransfer::new(Request {
+                        direction: UsbDirection::Out,
+                        request_type: RequestType::Standard,
+                        recipient: Recipient::Device,
+                        request: Request::SET_CONFIGURATION,
+                        value: configuration.configuration_value as u16,
+                        index: 0,
+                        length: 0,
+                    }));
+                    self.state = State::SetConfiguration;
+                }
+                None => return self.fail(HostError::Transaction),
+            },
+            State::SetConfiguration => {
+                self.transfer = None;
+                self.state = State::Configured;
+                return EnumerationStatus::Configured;
+            }
+            _ => {}
+        }
+
+        EnumerationStatus::InProgress
+    }
+
+    /// Switches the control pipe to the assigned address once the device has recovered.
+    fn configure_address<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) -> EnumerationStatus {
+        let pipe = match &self.pipe {
+            Some(pipe) => pipe,
+            None => return EnumerationStatus::Idle,
+        };
+        let mut config = match host.pipe_config(pipe) {
+            Ok(config) => config,
+            Err(e) => return self.fail(e),
+        };
+        config.device_address = self.address;
+        if let Err(e) = host.configure_pipe(pipe, config) {
+            return self.fail(e);
+        }
+        self.request_descriptor(descriptor_type::DEVICE, DeviceDescriptor::SIZE as u16);
+        self.state = State::GetDeviceDescriptor;
+        EnumerationStatus::InProgress
+    }
+
+    /// Releases the device attached to the root port, and stops a port reset in progress.
+    fn release_root_port<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) {
+        if let State::Reset(_) = self.state {
+            host.set_port_reset(false);
+        }
+        self.release(host);
+    }
+
+    fn request_descriptor(&mut self, descriptor_type: u8, length: u16) {
+        self.transfer = Some(ControlTransfer::new(Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Standard,
+            recipient: Recipient::Device,
+            request: Request::GET_DESCRIPTOR,
+            value: (descriptor_type as u16) << 8,
+            index: 0,
+            length,
+        }));
+    }
+
+    fn fail(&mut self, error: HostError) -> EnumerationStatus {
+        self.transfer = None;
+        self.state = State::Failed(error);
+        EnumerationStatus::Failed(error)
+    }
+}
+
+/// Returns `true` if `max_packet_size` is allowed for endpoint 0 at the given speed: 64 bytes for
+/// high-speed, 8 bytes for low-speed and 8, 16, 32 or 64 bytes for full-speed devices
+/// (USB 2.0, 5.5.3).
+fn valid_ep0_max_packet_size(speed: Speed, max_packet_size: u16) -> bool {
+    match speed {
+        Speed::High => max_packet_size == 64,
+        Speed::Low => max_packet_size == 8,
+        Speed::Full => matches!(max_packet_size, 8 | 16 | 32 | 64),
+    }
+}
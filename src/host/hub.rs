@@ -0,0 +1,494 @@
+//! Hub class driver.
+
+use super::control::ControlTransfer;
+use super::enumeration::{EnumerationStatus, Enumerator};
+use super::periodic::FrameTimer;
+use super::{HostError, Pipe, PipeConfig, Result, Speed, UsbHost};
+use crate::UsbPeripheral;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+/// Hub class code
+const CLASS_HUB: u8 = 0x09;
+/// Hub descriptor type
+const DESCRIPTOR_TYPE_HUB: u8 = 0x29;
+
+// Hub class feature selectors (USB 2.0, table 11-17)
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+/// Port change features are numbered from C_PORT_CONNECTION in the order of the wPortChange bits
+const C_PORT_CONNECTION: u16 = 16;
+
+// wPortStatus bits (USB 2.0, table 11-21)
+const PORT_STATUS_CONNECTION: u16 = 1 << 0;
+const PORT_STATUS_ENABLE: u16 = 1 << 1;
+const PORT_STATUS_LOW_SPEED: u16 = 1 << 9;
+const PORT_STATUS_HIGH_SPEED: u16 = 1 << 10;
+
+// wPortChange bits (USB 2.0, table 11-22)
+const PORT_CHANGE_CONNECTION: u16 = 1 << 0;
+const PORT_CHANGE_RESET: u16 = 1 << 4;
+/// Change bits that have to be acknowledged with CLEAR_FEATURE
+const PORT_CHANGE_MASK: u16 = 0x1f;
+/// wHubChange bits: C_HUB_LOCAL_POWER and C_HUB_OVER_CURRENT
+const HUB_CHANGE_MASK: u16 = 0x03;
+
+/// Event reported by [`Hub::poll`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HubEvent {
+    /// Nothing happened.
+    None,
+    /// The hub ports are powered, attached devices are enumerated from now on.
+    Ready,
+    /// The device attached to the port was configured, see [`Hub::device`].
+    DeviceConfigured(u8),
+    /// The device attached to the port was detached.
+    DeviceDisconnected(u8),
+    /// Enumeration of the device attached to the port failed.
+    EnumerationFailed {
+        /// Hub port number
+        port: u8,
+        /// Cause of the failure
+        error: HostError,
+    },
+    /// A request to the hub failed. The hub has to be reset to start over.
+    Failed(HostError),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Step {
+    GetHubDescriptor,
+    PowerPort(u8),
+    /// Waiting for the power of the ports to become good
+    PowerOnDelay(FrameTimer),
+    Idle,
+    /// Port 0 is the hub itself
+    GetPortStatus(u8),
+    ClearPortChange {
+        port: u8,
+        status: u16,
+        change: u16,
+        remaining: u16,
+    },
+    /// Waiting for the connection to settle before resetting the port
+    Debounce {
+        port: u8,
+        timer: FrameTimer,
+    },
+    ResetPort(u8),
+    ResetRecovery {
+        port: u8,
+        speed: Speed,
+        timer: FrameTimer,
+    },
+    Failed(HostError),
+}
+
+/// Driver for a hub that has been enumerated with an [`Enumerator`].
+///
+/// The hub powers its ports, watches the status change endpoint, resets the ports devices are
+/// attached to and enumerates these devices one after the other with the enumerators given to
+/// [`Hub::new`]. Every enumerator must have been created with a distinct address. Delays are
+/// counted in frames of the root port, so the hub has to be polled at least once per frame
+/// while a port is being powered or reset.
+///
+/// ```ignore
+/// let mut hub = Hub::new(&host, &mut enumerator, &mut port_enumerators)?;
+/// loop {
+///     let event = host.poll();
+///     enumerator.poll(&host, event, now_ms());
+///     if let HubEvent::DeviceConfigured(port) = hub.poll(&host) {
+///         let config = hub.device(port).unwrap().configuration().unwrap();
+///         // bind class drivers to config.interfaces()
+///     }
+/// }
+/// ```
+pub struct Hub<'a, 'b> {
+    step: Step,
+    address: u8,
+    speed: Speed,
+    /// Transaction translator used by the hub itself
+    hub_address: u8,
+    hub_port: u8,
+    control: Option<Pipe>,
+    status: Option<Pipe>,
+    transfer: Option<ControlTransfer>,
+    buffer: [u8; 16],
+    num_ports: u8,
+    power_on_delay: u16,
+    /// Bitmask of ports with unprocessed status changes, bit 0 is the hub itself
+    changes: u32,
+    /// Bitmask of ports waiting to be reset
+    resets: u32,
+    /// Port of the device that is being enumerated
+    enumerating: Option<u8>,
+    ports: &'a mut [Enumerator<'b>],
+}
+
+impl<'a, 'b> Hub<'a, 'b> {
+    /// Creates a driver for the configured hub enumerated by `device`.
+    ///
+    /// `ports` holds an enumerator for each downstream port, starting with port 1. Ports without
+    /// an enumerator are left unpowered. The hub takes over the control pipe of `device` and
+    /// allocates a pipe for its status change endpoint, so it needs one more host channel.
+    pub fn new<USB: UsbPeripheral>(
+        host: &UsbHost<USB>,
+        device: &mut Enumerator<'_>,
+        ports: &'a mut [Enumerator<'b>],
+    ) -> Result<Self> {
+        let configuration = device.configuration().ok_or(HostError::InvalidPipe)?;
+        let control_config =
+            host.pipe_config(device.control_pipe().ok_or(HostError::InvalidPipe)?)?;
+
+        let interface = configuration
+            .interfaces()
+            .find(|i| i.interface_class == CLASS_HUB)
+            .ok_or(HostError::InvalidPipe)?;
+        let endpoint = configuration
+            .endpoints(interface.interface_number, interface.alternate_setting)
+            .find(|e| e.ep_type == EndpointType::Interrupt && e.address.is_in())
+            .ok_or(HostError::InvalidPipe)?;
+
+        let status = host.alloc_pipe(PipeConfig {
+            endpoint: endpoint.address,
+            ep_type: endpoint.ep_type,
+            max_packet_size: endpoint.max_packet_size,
+            interval: endpoint.interval,
+            ..control_config
+        })?;
+        let control = device.take_control_pipe();
+
+        let mut hub = Self {
+            step: Step::GetHubDescriptor,
+            address: control_config.device_address,
+            speed: control_config.speed,
+            hub_address: control_config.hub_address,
+            hub_port: control_config.hub_port,
+            control,
+            status: Some(status),
+            transfer: None,
+            buffer: [0; 16],
+            num_ports: 0,
+            power_on_delay: 0,
+            changes: 0,
+            resets: 0,
+            enumerating: None,
+            ports,
+        };
+        hub.request(Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Class,
+            recipient: Recipient::Device,
+            request: Request::GET_DESCRIPTOR,
+            value: (DESCRIPTOR_TYPE_HUB as u16) << 8,
+            index: 0,
+            length: hub.buffer.len() as u16,
+        });
+        Ok(hub)
+    }
+
+    /// Returns the address of the hub.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Returns the number of ports in use, known once the hub descriptor has been read.
+    pub fn num_ports(&self) -> u8 {
+        self.num_ports
+    }
+
+    /// Returns the enumerator of the device attached to the given port, starting with port 1.
+    pub fn device(&self, port: u8) -> Option<&Enumerator<'b>> {
+        if port == 0 {
+            return None;
+        }
+        self.ports.get(port as usize - 1)
+    }
+
+    /// Releases the pipes of the hub and of all the devices attached to it.
+    pub fn release<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) {
+        for port in self.ports.iter_mut() {
+            port.release(host);
+        }
+        if let Some(pipe) = self.control.take() {
+            host.free_pipe(pipe);
+        }
+        if let Some(pipe) = self.status.take() {
+            host.free_pipe(pipe);
+        }
+        self.transfer = None;
+        self.enumerating = None;
+        self.step = Step::Failed(HostError::Disconnected);
+    }
+
+    /// Handles port changes and advances the enumeration of attached devices.
+    pub fn poll<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) -> HubEvent {
+        if let Step::Failed(_) = self.step {
+            return HubEvent::None;
+        }
+
+        if let Some(port) = self.enumerating {
+            match self.ports[port as usize - 1].advance(host) {
+                EnumerationStatus::InProgress => {}
+                EnumerationStatus::Configured => {
+                    self.enumerating = None;
+                    return HubEvent::DeviceConfigured(port);
+                }
+                EnumerationStatus::Failed(error) => {
+                    self.enumerating = None;
+                    return HubEvent::EnumerationFailed { port, error };
+                }
+                _ => self.enumerating = None,
+            }
+        }
+
+        if let Some(status) = &self.status {
+            let mut bitmap = [0; 8];
+            match host.poll_transfer(status, &mut bitmap) {
+                Ok(_) => {
+                    let bitmap = u32::from_le_bytes([bitmap[0], bitmap[1], bitmap[2], bitmap[3]]);
+                    // Bit 0 is the hub itself, bit n is port n
+                    self.changes |= bitmap & (u32::MAX >> (31 - self.num_ports));
+                }
+                Err(e @ HostError::Stall) | Err(e @ HostError::Disconnected) => {
+                    return self.fail(e)
+                }
+                // Transient errors, the endpoint is polled again in the next interval
+                Err(_) => {}
+            }
+        }
+
+        if let (Some(transfer), Some(control)) = (&mut self.transfer, &self.control) {
+            return match transfer.poll(host, control, &mut self.buffer) {
+                Ok(length) => {
+                    self.transfer = None;
+                    self.complete(host, length)
+                }
+                Err(HostError::WouldBlock) => HubEvent::None,
+                Err(e) => self.fail(e),
+            };
+        }
+
+        match self.step {
+            Step::Idle => self.start_next_request(host),
+            Step::PowerOnDelay(timer) if timer.expired(host) => {
+                if let Some(status) = &self.status {
+                    if let Err(e) = host.schedule_periodic(status) {
+                        return self.fail(e);
+                    }
+                }
+                self.step = Step::Idle;
+                return HubEvent::Ready;
+            }
+            Step::Debounce { port, timer } if timer.expired(host) => {
+                self.set_port_feature(port, PORT_RESET);
+                self.step = Step::ResetPort(port);
+            }
+            Step::ResetRecovery { port, speed, timer } if timer.expired(host) => {
+                // Full-speed and low-speed devices behind a high-speed hub use its transaction
+                // translator, otherwise the one used by the hub itself
+                let (hub_address, hub_port) = if self.speed == Speed::High {
+                    (self.address, port)
+                } else {
+                    (self.hub_address, self.hub_port)
+                };
+                self.ports[port as usize - 1].start_on_hub(host, speed, hub_address, hub_port);
+                self.enumerating = Some(port);
+                self.step = Step::Idle;
+            }
+            _ => {}
+        }
+
+        HubEvent::None
+    }
+
+    /// Requests the status of the next changed port, or starts resetting the next port.
+    fn start_next_request<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>) {
+        if self.changes != 0 {
+            let port = self.changes.trailing_zeros() as u8;
+            self.changes &= !(1 << port);
+            self.request(Request {
+                direction: UsbDirection::In,
+                request_type: RequestType::Class,
+                recipient: recipient(port),
+                request: Request::GET_STATUS,
+                value: 0,
+                index: port as u16,
+                length: 4,
+            });
+            self.step = Step::GetPortStatus(port);
+        } else if self.resets != 0 && self.enumerating.is_none() {
+            // Only one device at a time may respond at address 0
+            let port = self.resets.trailing_zeros() as u8;
+            self.resets &= !(1 << port);
+            // Wait for the connection to settle before resetting the device (USB 2.0, 7.1.7.3)
+            self.step = Step::Debounce {
+                port,
+                timer: FrameTimer::start(host, 100),
+            };
+        }
+    }
+
+    fn complete<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>, length: usize) -> HubEvent {
+        match self.step {
+            Step::GetHubDescriptor => {
+                if length < 7 || self.buffer[1] != DESCRIPTOR_TYPE_HUB {
+                    return self.fail(HostError::Transaction);
+                }
+                self.num_ports = self.buffer[2].min(self.ports.len().min(31) as u8);
+                // bPwrOn2PwrGood is given in 2 ms units
+                self.power_on_delay = self.buffer[5] as u16 * 2;
+                self.power_port(host, 1)
+            }
+            Step::PowerPort(port) => self.power_port(host, port + 1),
+            Step::GetPortStatus(port) => {
+                if length < 4 {
+                    return self.fail(HostError::Transaction);
+                }
+                let status = u16::from_le_bytes([self.buffer[0], self.buffer[1]]);
+                let change = u16::from_le_bytes([self.buffer[2], self.buffer[3]])
+                    & if port == 0 {
+                        HUB_CHANGE_MASK
+                    } else {
+                        PORT_CHANGE_MASK
+                    };
+                self.clear_change(host, port, status, change, change)
+            }
+            Step::ClearPortChange {
+                port,
+                status,
+                change,
+                remaining,
+            } => {
+                // Clear the lowest bit, it has just been acknowledged
+                let remaining = remaining & (remaining - 1);
+                self.clear_change(host, port, status, change, remaining)
+            }
+            Step::ResetPort(_) => {
+                // Completion of the reset is reported by the status change endpoint
+                self.step = Step::Idle;
+                HubEvent::None
+            }
+            _ => HubEvent::None,
+        }
+    }
+
+    /// Powers the ports starting at `port`, then waits for the power to become good.
+    fn power_port<USB: UsbPeripheral>(&mut self, host: &UsbHost<USB>, port: u8) -> HubEvent {
+        if port <= self.num_ports {
+            self.set_port_feature(port, PORT_POWER);
+            self.step = Step::PowerPort(port);
+        } else {
+            self.step = Step::PowerOnDelay(FrameTimer::start(host, self.power_on_delay));
+        }
+        HubEvent::None
+    }
+
+    /// Acknowledges the `remaining` change bits one after the other, then handles the change.
+    fn clear_change<USB: UsbPeripheral>(
+        &mut self,
+        host: &UsbHost<USB>,
+        port: u8,
+        status: u16,
+        change: u16,
+        remaining: u16,
+    ) -> HubEvent {
+        if remaining != 0 {
+            let mut feature = remaining.trailing_zeros() as u16;
+            if port != 0 {
+                feature += C_PORT_CONNECTION;
+            }
+            self.request(Request {
+                direction: UsbDirection::Out,
+                request_type: RequestType::Class,
+                recipient: recipient(port),
+                request: Request::CLEAR_FEATURE,
+                value: feature,
+                index: port as u16,
+                length: 0,
+            });
+            self.step = Step::ClearPortChange {
+                port,
+                status,
+                change,
+                remaining,
+            };
+            return HubEvent::None;
+        }
+
+        self.step = Step::Idle;
+        if port == 0 {
+            // Local power and overcurrent changes of the hub are only acknowledged
+            return HubEvent::None;
+        }
+
+        if change & PORT_CHANGE_CONNECTION != 0 {
+            let device = &mut self.ports[port as usize - 1];
+            let attached = device.control_pipe().is_some();
+            device.release(host);
+            if self.enumerating == Some(port) {
+                self.enumerating = None;
+            }
+
+            if status & PORT_STATUS_CONNECTION != 0 {
+                self.resets |= 1 << port;
+            } else {
+                self.resets &= !(1 << port);
+                if attached {
+                    return HubEvent::DeviceDisconnected(port);
+                }
+            }
+        } else if change & PORT_CHANGE_RESET != 0
+            && status & PORT_STATUS_ENABLE != 0
+            && self.enumerating.is_none()
+        {
+            let speed = if status & PORT_STATUS_LOW_SPEED != 0 {
+                Speed::Low
+            } else if status & PORT_STATUS_HIGH_SPEED != 0 {
+                Speed::High
+            } else {
+                Speed::Full
+            };
+            // Reset recovery time (USB 2.0, 7.1.7.5)
+            self.step = Step::ResetRecovery {
+                port,
+                speed,
+                timer: FrameTimer::start(host, 10),
+            };
+        }
+
+        HubEvent::None
+    }
+
+    fn set_port_feature(&mut self, port: u8, feature: u16) {
+        self.request(Request {
+            direction: UsbDirection::Out,
+            request_type: RequestType::Class,
+            recipient: Recipient::Other,
+            request: Request::SET_FEATURE,
+            value: feature,
+            index: port as u16,
+            length: 0,
+        });
+    }
+
+    fn request(&mut self, request: Request) {
+        self.transfer = Some(ControlTransfer::new(request));
+    }
+
+    fn fail(&mut self, error: HostError) -> HubEvent {
+        self.transfer = None;
+        self.step = Step::Failed(error);
+        HubEvent::Failed(error)
+    }
+}
+
+/// Hub requests address the hub itself as port 0.
+fn recipient(port: u8) -> Recipient {
+    if port == 0 {
+        Recipient::Device
+    } else {
+        Recipient::Other
+    }
+}
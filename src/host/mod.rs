@@ -0,0 +1,483 @@
+use crate::host::channel::{Channel, MAX_CHANNELS};
+use crate::ral::{modify_reg, otg_global, otg_host, otg_pwrclk, read_reg, write_reg};
+use crate::target::UsbRegisters;
+use crate::{PhyType, UsbPeripheral};
+use core::cell::{Cell, RefCell};
+use critical_section::Mutex;
+use embedded_hal::blocking::delay::DelayMs;
+
+mod channel;
+mod control;
+pub mod descriptor;
+mod enumeration;
+mod hub;
+mod periodic;
+#[cfg(feature = "hs")]
+mod split;
+
+pub use channel::{HostError, Pipe, PipeConfig, Result};
+pub use control::{setup_packet, ControlTransfer};
+pub use enumeration::{EnumerationStatus, Enumerator};
+pub use hub::{Hub, HubEvent};
+
+/// Bits of HPRT that are cleared by writing 1. They must be masked out when HPRT is modified,
+/// otherwise a read-modify-write would acknowledge pending port events or disable the port.
+const HPRT_W1C_MASK: u32 = otg_host::HPRT::PENA::mask
+    | otg_host::HPRT::PCDET::mask
+    | otg_host::HPRT::PENCHNG::mask
+    | otg_host::HPRT::POCCHNG::mask;
+
+/// Speed of a USB device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Speed {
+    /// Low-Speed (1.5 Mbit/s)
+    Low,
+    /// Full-Speed (12 Mbit/s)
+    Full,
+    /// High-Speed (480 Mbit/s)
+    High,
+}
+
+/// Event reported by [`UsbHost::poll`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HostEvent {
+    /// Nothing happened.
+    None,
+    /// A device was attached to the root port.
+    ///
+    /// The port must be reset with [`UsbHost::reset_port`] before the device can be accessed.
+    Connected,
+    /// The device was detached from the root port.
+    Disconnected,
+    /// The port was enabled after a reset. The attached device operates at the given speed.
+    PortEnabled(Speed),
+    /// The port was disabled by the core.
+    PortDisabled,
+    /// The overcurrent condition of the port changed.
+    Overcurrent(bool),
+    /// Transfers finished on the channels set in the bitmask.
+    ///
+    /// The outcome is retrieved with [`UsbHost::poll_transfer`].
+    TransferComplete {
+        /// Bitmask of host channels
+        channels: u16,
+    },
+}
+
+/// USB host driver for Synopsys USB OTG peripherals.
+///
+/// Unlike [`UsbBus`](crate::UsbBus), this driver forces the core into host mode and drives the
+/// single root port of the core.
+pub struct UsbHost<USB> {
+    peripheral: USB,
+    regs: Mutex<UsbRegisters>,
+    channels: Mutex<RefCell<[Channel; MAX_CHANNELS]>>,
+    /// Channels with completed transfers that were not reported yet
+    completed: Mutex<Cell<u16>>,
+    dual_role: bool,
+}
+
+impl<USB: UsbPeripheral> UsbHost<USB> {
+    /// Constructs a new USB host driver.
+    ///
+    /// `memory` is split evenly between the host channels and holds the packets received on IN
+    /// pipes, so each channel gets `memory.len() / USB::HOST_CHANNEL_COUNT` words.
+    pub fn new(peripheral: USB, memory: &'static mut [u32]) -> Self {
+        Self {
+            peripheral,
+            regs: Mutex::new(UsbRegisters::new::<USB>()),
+            channels: Mutex::new(RefCell::new(channel::init_channels::<USB>(memory))),
+            completed: Mutex::new(Cell::new(0)),
+            dual_role: false,
+        }
+    }
+
+    /// Constructs a USB host driver for a core that switches roles with the ID pin.
+    ///
+    /// The core is not forced into host mode, and the driver stays idle while the core is in
+    /// device mode. See [`DualRole`](crate::otg::DualRole) for switching roles.
+    pub fn new_dual_role(peripheral: USB, memory: &'static mut [u32]) -> Self {
+        Self {
+            dual_role: true,
+            ..Self::new(peripheral, memory)
+        }
+    }
+
+    /// Releases the peripheral.
+    ///
+    /// The core is left in its current state, call [`disable`](Self::disable) first to switch
+    /// off the root port.
+    pub fn free(self) -> USB {
+        self.peripheral
+    }
+
+    /// Enables and initializes the peripheral in host mode.
+    ///
+    /// The root port is left unpowered, call [`set_port_power`](Self::set_port_power) to supply
+    /// the port.
+    ///
+    /// Returns [`HostError::WouldBlock`] and leaves the core untouched if a dual-role core is
+    /// still in device mode. The call has to be repeated once
+    /// [`DualRole::current_mode`](crate::otg::DualRole::current_mode) reports the host role.
+    pub fn enable(&mut self) -> Result<()> {
+        // Enable USB_OTG in RCC
+        USB::enable();
+
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // The role of a dual-role core follows the ID pin, waiting for it could hang forever
+            if self.dual_role && read_reg!(otg_global, regs.global(), GINTSTS, CMOD) == 0 {
+                return Err(HostError::WouldBlock);
+            }
+
+            let core_id = read_reg!(otg_global, regs.global(), CID);
+
+            // Wait for AHB ready
+            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+
+            // Configure OTG as host, unless the role is selected by the ID pin
+            let force_host = !self.dual_role as u32;
+            let srp_capable = if self.dual_role {
+                read_reg!(otg_global, regs.global(), GUSBCFG, SRPCAP)
+            } else {
+                0
+            };
+            #[cfg(feature = "fs")]
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                SRPCAP: srp_capable, // Set up by dual-role applications only
+                FDMOD: 0,
+                FHMOD: force_host
+            );
+            #[cfg(feature = "hs")]
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                SRPCAP: srp_capable, // Set up by dual-role applications only
+                TOCAL: 0x1,
+                FDMOD: 0,
+                FHMOD: force_host
+            );
+
+            // Configure USB PHY
+            crate::target::init_phy(&self.peripheral, *regs);
+
+            // Wait for the forced mode change to take effect
+            while read_reg!(otg_global, regs.global(), GINTSTS, CMOD) == 0 {}
+
+            // Configuring Vbus sense and SOF output
+            match core_id {
+                0x0000_1200 | 0x0000_1100 => {
+                    // F429-like chips have the GCCFG.NOVBUSSENS bit
+
+                    //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
+                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+
+                    modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
+                }
+                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
+                    // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
+
+                    //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
+                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                    // Force A-device session, drop a forced B-peripheral session
+                    //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 0, AVALOEN: 1, AVALOVAL: 1);
+                    modify_reg!(otg_global, regs.global(), GOTGCTL, |r| (r & !(0b11 << 6))
+                        | (0b11 << 4));
+                }
+                _ => {}
+            }
+
+            // Enable PHY clock
+            write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+            // Select FS/LS PHY clock
+            if self.uses_fs_phy() {
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 0b01, FSLSS: 1);
+            } else {
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 0b00, FSLSS: 0);
+            }
+
+            // Split the FIFO RAM between the Rx FIFO, the non-periodic and the periodic Tx FIFOs
+            let rx_fifo_size = USB::FIFO_DEPTH_WORDS * 2 / 5;
+            let np_tx_fifo_size = USB::FIFO_DEPTH_WORDS * 3 / 10;
+            let p_tx_fifo_size = USB::FIFO_DEPTH_WORDS - rx_fifo_size - np_tx_fifo_size;
+            write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size as u32);
+            #[cfg(feature = "fs")]
+            write_reg!(otg_global, regs.global(), DIEPTXF0,
+                TX0FD: np_tx_fifo_size as u32,
+                TX0FSA: rx_fifo_size as u32
+            );
+            #[cfg(feature = "hs")]
+            write_reg!(otg_global, regs.global(), GNPTXFSIZ,
+                TX0FD: np_tx_fifo_size as u32,
+                TX0FSA: rx_fifo_size as u32
+            );
+            #[cfg(feature = "fs")]
+            write_reg!(otg_global, regs.global(), HPTXFSIZ,
+                PTXFSIZ: p_tx_fifo_size as u32,
+                PTXSA: (rx_fifo_size + np_tx_fifo_size) as u32
+            );
+            #[cfg(feature = "hs")]
+            write_reg!(otg_global, regs.global(), HPTXFSIZ,
+                PTXFD: p_tx_fifo_size as u32,
+                PTXSA: (rx_fifo_size + np_tx_fifo_size) as u32
+            );
+
+            // Flush Rx & Tx FIFOs
+            modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+            while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+
+            // unmask core interrupts
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                PRTIM: 1, DISCINT: 1,
+                HCIM: 1, RXFLVLM: 1,
+                CIDSCHGM: self.dual_role as u32,
+                OTGINT: self.dual_role as u32,
+                SRQIM: self.dual_role as u32
+            );
+
+            // clear pending interrupts
+            write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+
+            // unmask global interrupt
+            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+            Ok(())
+        })
+    }
+
+    /// Switches off the root port and aborts all transfers, before the core is used as a device
+    /// by a dual-role application.
+    pub fn disable(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            modify_hprt(*regs, |r| r & !otg_host::HPRT::PPWR::mask);
+            self.abort_channels(cs, *regs);
+
+            // Only keep watching the connector ID and OTG events
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                CIDSCHGM: self.dual_role as u32,
+                OTGINT: self.dual_role as u32,
+                SRQIM: self.dual_role as u32
+            );
+        });
+    }
+
+    /// Switches the power of the root port on or off.
+    ///
+    /// On most chips VBUS is supplied by an external switch that must be controlled separately,
+    /// but the port power bit still has to be set for the core to detect a connection.
+    pub fn set_port_power(&self, enabled: bool) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_hprt(*regs, |r| {
+                (r & !otg_host::HPRT::PPWR::mask)
+                    | ((enabled as u32) << otg_host::HPRT::PPWR::offset)
+            });
+        });
+    }
+
+    /// Returns `true` if a device is attached to the root port.
+    pub fn is_connected(&self) -> bool {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            read_reg!(otg_host, regs.host(), HPRT, PCSTS) != 0
+        })
+    }
+
+    /// Returns the speed of the attached device, or `None` if the port is not enabled.
+    pub fn port_speed(&self) -> Option<Speed> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let (enabled, speed) = read_reg!(otg_host, regs.host(), HPRT, PENA, PSPD);
+            if enabled != 0 {
+                Some(speed_from_pspd(speed))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Resets the device attached to the root port.
+    ///
+    /// Completion of the reset is reported by [`poll`](Self::poll) with
+    /// [`HostEvent::PortEnabled`] that carries the speed of the device.
+    pub fn reset_port(&self, delay: &mut impl DelayMs<u32>) {
+        self.set_port_reset(true);
+
+        // Root ports must drive reset for at least 50 ms (USB 2.0, 7.1.7.5)
+        delay.delay_ms(50);
+
+        self.set_port_reset(false);
+
+        // Reset recovery time
+        delay.delay_ms(10);
+    }
+
+    /// Starts or stops driving reset on the root port, for applications that can't block in
+    /// [`reset_port`](Self::reset_port).
+    ///
+    /// Root ports must drive reset for at least 50 ms (USB 2.0, 7.1.7.5). The device must be
+    /// given 10 ms of reset recovery time after [`HostEvent::PortEnabled`] was reported.
+    pub fn set_port_reset(&self, reset: bool) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_hprt(*regs, |r| {
+                (r & !otg_host::HPRT::PRST::mask) | ((reset as u32) << otg_host::HPRT::PRST::offset)
+            });
+        });
+    }
+
+    /// Processes port and channel events. Should be called regularly or from the interrupt
+    /// handler.
+    ///
+    /// Port events are reported first, transfers that completed meanwhile are reported by the
+    /// next call.
+    pub fn poll(&self) -> HostEvent {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if self.dual_role && read_reg!(otg_global, regs.global(), GINTSTS, CMOD) == 0 {
+                return HostEvent::None;
+            }
+
+            let (disconnect, port, rxflvl, hcint, sof) = read_reg!(
+                otg_global,
+                regs.global(),
+                GINTSTS,
+                DISCINT,
+                HPRTINT,
+                RXFLVL,
+                HCINT,
+                SOF
+            );
+
+            // RXFLVL & HCINT flags are read-only, there is no need to clear them
+            if rxflvl != 0 {
+                self.handle_rx_fifo(cs, *regs);
+            }
+            // Completions are kept until no port event takes precedence over them
+            let completed = self.completed.borrow(cs);
+            if hcint != 0 {
+                completed.set(completed.get() | self.handle_channels(cs, *regs));
+            }
+            if sof != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+                self.handle_sof(cs, *regs);
+            }
+
+            if disconnect != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, DISCINT: 1);
+
+                self.abort_channels(cs, *regs);
+
+                HostEvent::Disconnected
+            } else if port != 0 {
+                // HPRTINT is read-only, it is cleared together with the HPRT change bits
+                let hprt = read_reg!(otg_host, regs.host(), HPRT);
+                let preserved = hprt & !HPRT_W1C_MASK;
+
+                if hprt & otg_host::HPRT::PCDET::mask != 0 {
+                    write_reg!(
+                        otg_host,
+                        regs.host(),
+                        HPRT,
+                        preserved | otg_host::HPRT::PCDET::mask
+                    );
+
+                    HostEvent::Connected
+                } else if hprt & otg_host::HPRT::PENCHNG::mask != 0 {
+                    write_reg!(
+                        otg_host,
+                        regs.host(),
+                        HPRT,
+                        preserved | otg_host::HPRT::PENCHNG::mask
+                    );
+
+                    if hprt & otg_host::HPRT::PENA::mask != 0 {
+                        let speed = speed_from_pspd(
+                            (hprt & otg_host::HPRT::PSPD::mask) >> otg_host::HPRT::PSPD::offset,
+                        );
+                        if self.configure_frame_timing(*regs, speed) {
+                            // The PHY clock was changed, the port has to be reset again
+                            HostEvent::Connected
+                        } else {
+                            HostEvent::PortEnabled(speed)
+                        }
+                    } else {
+                        HostEvent::PortDisabled
+                    }
+                } else if hprt & otg_host::HPRT::POCCHNG::mask != 0 {
+                    write_reg!(
+                        otg_host,
+                        regs.host(),
+                        HPRT,
+                        preserved | otg_host::HPRT::POCCHNG::mask
+                    );
+
+                    HostEvent::Overcurrent(hprt & otg_host::HPRT::POCA::mask != 0)
+                } else {
+                    take_completed(completed)
+                }
+            } else {
+                take_completed(completed)
+            }
+        })
+    }
+
+    fn uses_fs_phy(&self) -> bool {
+        !USB::HIGH_SPEED || self.peripheral.phy_type() == PhyType::InternalFullSpeed
+    }
+
+    /// Programs the PHY clock and the frame interval for the enumerated speed.
+    ///
+    /// Returns `true` if the PHY clock selection was changed.
+    fn configure_frame_timing(&self, regs: UsbRegisters, speed: Speed) -> bool {
+        if self.uses_fs_phy() {
+            let (fslspcs, frame_interval) = match speed {
+                // 6 MHz PHY clock
+                Speed::Low => (0b10, 6000),
+                // 48 MHz PHY clock
+                _ => (0b01, 48000),
+            };
+            write_reg!(otg_host, regs.host(), HFIR, FRIVL: frame_interval);
+
+            let changed = read_reg!(otg_host, regs.host(), HCFG, FSLSPCS) != fslspcs;
+            if changed {
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: fslspcs);
+            }
+            changed
+        } else {
+            // 60 MHz UTMI/ULPI clock
+            let frame_interval = match speed {
+                Speed::High => 7500,
+                _ => 60000,
+            };
+            write_reg!(otg_host, regs.host(), HFIR, FRIVL: frame_interval);
+            false
+        }
+    }
+}
+
+/// Modifies HPRT without touching the write-1-to-clear bits.
+fn modify_hprt(regs: UsbRegisters, f: impl FnOnce(u32) -> u32) {
+    let hprt = read_reg!(otg_host, regs.host(), HPRT) & !HPRT_W1C_MASK;
+    write_reg!(otg_host, regs.host(), HPRT, f(hprt) & !HPRT_W1C_MASK);
+}
+
+/// Reports the pending transfer completions.
+fn take_completed(completed: &Cell<u16>) -> HostEvent {
+    match completed.replace(0) {
+        0 => HostEvent::None,
+        channels => HostEvent::TransferComplete { channels },
+    }
+}
+
+fn speed_from_pspd(pspd: u32) -> Speed {
+    match pspd {
+        0b00 => Speed::High,
+        0b01 => Speed::Full,
+        _ => Speed::Low,
+    }
+}
@@ -0,0 +1,203 @@
+//! Scheduling of interrupt and isochronous pipes.
+
+use super::channel::{Channel, ChannelState, MAX_CHANNELS};
+use super::{HostError, Pipe, Result, Speed, UsbHost};
+use crate::ral::{modify_reg, otg_global, otg_host, read_reg};
+use crate::target::UsbRegisters;
+use crate::UsbPeripheral;
+use critical_section::CriticalSection;
+use usb_device::endpoint::EndpointType;
+
+/// HFNUM.FRNUM wraps around at this value
+pub(super) const FRAME_NUMBER_MODULO: u16 = 0x4000;
+
+/// Returns the signed distance from frame `b` to frame `a`, taking wrap-around into account.
+pub(super) fn frame_diff(a: u16, b: u16) -> i16 {
+    // Shift the 14-bit difference into the upper bits to get the sign right
+    (a.wrapping_sub(b) << 2) as i16 >> 2
+}
+
+/// Delay counted in (micro)frames of the root port.
+///
+/// The frame number only advances while the root port is enabled, and the timer must be checked
+/// at least once per wrap-around of the frame number (16384 frames).
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub(super) struct FrameTimer {
+    start: u16,
+    frames: u16,
+}
+
+impl FrameTimer {
+    /// Starts a delay of `ms` milliseconds.
+    pub(super) fn start<USB: UsbPeripheral>(host: &UsbHost<USB>, ms: u16) -> Self {
+        // A high-speed root port counts microframes
+        let frames = if host.port_speed() == Some(Speed::High) {
+            ms.saturating_mul(8)
+        } else {
+            ms
+        };
+        Self {
+            start: host.frame_number(),
+            frames: frames.min(FRAME_NUMBER_MODULO / 2 - 1),
+        }
+    }
+
+    /// Returns `true` once the delay has elapsed.
+    pub(super) fn expired<USB: UsbPeripheral>(&self, host: &UsbHost<USB>) -> bool {
+        frame_diff(host.frame_number(), self.start) >= self.frames as i16
+    }
+}
+
+impl<USB: UsbPeripheral> UsbHost<USB> {
+    /// Returns the number of the current (micro)frame of the root port.
+    ///
+    /// High-speed ports count microframes, the number wraps around at 16384.
+    pub fn frame_number(&self) -> u16 {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            read_reg!(otg_host, regs.host(), HFNUM, FRNUM) as u16 % FRAME_NUMBER_MODULO
+        })
+    }
+
+    /// Schedules a periodic pipe to be serviced automatically at its interval.
+    ///
+    /// A transaction is started in every (micro)frame the pipe is due. OUT pipes only send the
+    /// packets queued with [`write_periodic`](Self::write_periodic). NAKed transactions are
+    /// retried in the next interval, completed transactions and errors are reported by
+    /// [`poll`](Self::poll) with [`HostEvent::TransferComplete`](super::HostEvent) and read with
+    /// [`poll_transfer`](Self::poll_transfer). The pipe is not serviced again until the previous
+    /// result has been read.
+    ///
+    /// Isochronous pipes of a full-speed device behind a transaction translator are limited to
+    /// 188 bytes per packet, larger ones are rejected with [`HostError::InvalidPipe`].
+    pub fn schedule_periodic(&self, pipe: &Pipe) -> Result<()> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            let channel = &mut channels[pipe.channel() as usize];
+
+            let config = channel.config.ok_or(HostError::InvalidPipe)?;
+            if !config.is_periodic() {
+                return Err(HostError::InvalidPipe);
+            }
+            #[cfg(feature = "hs")]
+            channel.check_periodic_split()?;
+
+            let root_port_high_speed = read_reg!(otg_host, regs.host(), HPRT, PSPD) == 0b00;
+            let mut interval = match config.ep_type {
+                // 2^(bInterval-1) microframes for high-speed, frames for full-speed
+                EndpointType::Isochronous { .. } => 1 << (config.interval.clamp(1, 16) - 1),
+                _ if config.speed == Speed::High => 1 << (config.interval.clamp(1, 16) - 1),
+                // bInterval frames
+                _ => config.interval.max(1) as u32,
+            };
+            if root_port_high_speed && config.speed != Speed::High {
+                // The frame number counts microframes
+                interval *= 8;
+            }
+
+            channel.interval = interval.min(FRAME_NUMBER_MODULO as u32 / 2) as u16;
+            channel.next_frame = next_frame_number(*regs);
+
+            self.update_sof_mask(*regs, &channels);
+            Ok(())
+        })
+    }
+
+    /// Queues a packet to be sent by a scheduled OUT pipe in its next interval.
+    ///
+    /// Returns [`HostError::WouldBlock`] while the previous packet has not been sent or its
+    /// result has not been read.
+    pub fn write_periodic(&self, pipe: &Pipe, data: &[u8]) -> Result<()> {
+        critical_section::with(|cs| {
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            let channel = &mut channels[pipe.channel() as usize];
+
+            let config = channel.config.ok_or(HostError::InvalidPipe)?;
+            if channel.interval == 0 || config.endpoint.is_in() {
+                return Err(HostError::InvalidPipe);
+            }
+            if channel.out_queued || channel.state != ChannelState::Idle {
+                return Err(HostError::WouldBlock);
+            }
+            if data.len() > config.max_packet_size as usize {
+                return Err(HostError::BufferOverflow);
+            }
+
+            channel.queue_out(data);
+            Ok(())
+        })
+    }
+
+    /// Stops polling a pipe scheduled with [`schedule_periodic`](Self::schedule_periodic).
+    ///
+    /// A transaction in progress is not aborted, its outcome is still reported.
+    pub fn unschedule_periodic(&self, pipe: &Pipe) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let mut channels = self.channels.borrow_ref_mut(cs);
+            channels[pipe.channel() as usize].interval = 0;
+
+            self.update_sof_mask(*regs, &channels);
+        });
+    }
+
+    /// Enables the SOF interrupt while there are scheduled pipes or deferred complete-splits.
+    pub(super) fn update_sof_mask(&self, regs: UsbRegisters, channels: &[Channel; MAX_CHANNELS]) {
+        let scheduled = channels[..USB::HOST_CHANNEL_COUNT].iter().any(|ch| {
+            #[cfg(feature = "hs")]
+            if ch.complete_split_frame.is_some() {
+                return true;
+            }
+            ch.interval != 0
+        });
+        modify_reg!(otg_global, regs.global(), GINTMSK, SOFM: scheduled as u32);
+    }
+
+    /// Starts the transactions of the scheduled pipes that are due in the next (micro)frame.
+    pub(crate) fn handle_sof(&self, cs: CriticalSection<'_>, regs: UsbRegisters) {
+        let mut channels = self.channels.borrow_ref_mut(cs);
+        let frame = next_frame_number(regs);
+
+        for (index, channel) in channels[..USB::HOST_CHANNEL_COUNT].iter_mut().enumerate() {
+            #[cfg(feature = "hs")]
+            if channel.complete_split_due(frame) {
+                channel.enable(regs, index);
+                continue;
+            }
+            if channel.interval == 0 || frame_diff(frame, channel.next_frame) < 0 {
+                continue;
+            }
+            // Wait for the previous result to be read, or for a packet to send
+            if channel.state != ChannelState::Idle {
+                continue;
+            }
+            let is_in = channel.config.is_some_and(|config| config.endpoint.is_in());
+            if !is_in && !channel.out_queued {
+                continue;
+            }
+
+            // Without room in the periodic Tx FIFO or request queue the pipe is retried on the
+            // next SOF
+            let started = if is_in {
+                channel.start(regs, index, true, None, &[])
+            } else {
+                channel.start_queued(regs, index)
+            };
+            if started.is_ok() {
+                channel.next_frame =
+                    channel.next_frame.wrapping_add(channel.interval) % FRAME_NUMBER_MODULO;
+                if frame_diff(channel.next_frame, frame) <= 0 {
+                    // Fell behind, resynchronize with the current frame
+                    channel.next_frame = frame.wrapping_add(channel.interval) % FRAME_NUMBER_MODULO;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the number of the (micro)frame that follows the current one.
+fn next_frame_number(regs: UsbRegisters) -> u16 {
+    let frame = read_reg!(otg_host, regs.host(), HFNUM, FRNUM) as u16;
+    (frame + 1) % FRAME_NUMBER_MODULO
+}
@@ -0,0 +1,231 @@
+//! Split transactions for full-speed and low-speed devices behind a high-speed hub.
+//!
+//! Every transaction starts with a start-split that the hub acknowledges, followed by
+//! complete-splits until the hub returns the outcome of the transaction on the downstream bus.
+//! The complete-splits of scheduled periodic pipes are issued from the SOF interrupt, starting
+//! two microframes after the start-split. Isochronous OUT transactions have no complete-split.
+
+use super::channel::{Channel, ChannelState, HostError, Result};
+use super::periodic::{frame_diff, FRAME_NUMBER_MODULO};
+use super::Speed;
+use crate::ral::{host_channel, write_reg};
+use crate::target::UsbRegisters;
+use usb_device::endpoint::EndpointType;
+
+/// HCSPLT.XACTPOS: the whole payload is sent with a single start-split
+const XACTPOS_ALL: u32 = 0b11;
+
+/// Full-speed bytes that fit into a microframe, the largest isochronous payload of a single
+/// start-split or complete-split (USB 2.0, 11.18.4)
+const MAX_SPLIT_ISOCHRONOUS_SIZE: u16 = 188;
+
+impl Channel {
+    /// Returns `true` if the transactions of the channel are split by a transaction translator.
+    pub(super) fn is_split(&self) -> bool {
+        match self.config {
+            Some(config) => config.hub_address != 0 && config.speed != Speed::High,
+            None => false,
+        }
+    }
+
+    /// Checks that a periodic pipe can be scheduled through the transaction translator.
+    ///
+    /// Isochronous payloads that span several microframes would need BEGIN/MID/END
+    /// start-splits or several complete-splits, which are not supported.
+    pub(super) fn check_periodic_split(&self) -> Result<()> {
+        match self.config {
+            Some(config)
+                if self.is_split()
+                    && matches!(config.ep_type, EndpointType::Isochronous { .. })
+                    && config.max_packet_size > MAX_SPLIT_ISOCHRONOUS_SIZE =>
+            {
+                Err(HostError::InvalidPipe)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Programs HCSPLT for the current stage of the transaction.
+    pub(super) fn write_split(&self, regs: UsbRegisters, index: usize) {
+        let ch_regs = regs.host_channel(index);
+        match self.config {
+            Some(config) if self.is_split() => {
+                write_reg!(host_channel, ch_regs, HCSPLT,
+                    PRTADDR: config.hub_port as u32,
+                    HUBADDR: config.hub_address as u32,
+                    XACTPOS: XACTPOS_ALL,
+                    COMPLSPLT: self.complete_split as u32,
+                    SPLITEN: 1
+                );
+            }
+            _ => write_reg!(host_channel, ch_regs, HCSPLT, 0),
+        }
+    }
+
+    /// Handles the handshakes of split transactions, `frame` is the current microframe.
+    ///
+    /// Returns `true` if the channel has to be halted and re-enabled to issue a complete-split:
+    /// after the start-split was acknowledged (ACK), or when the hub has not finished the
+    /// transaction yet (NYET). Scheduled periodic pipes re-enable the channel in the microframe
+    /// stored in `complete_split_frame`.
+    pub(super) fn handle_split(&mut self, hcint: u32, frame: u16) -> bool {
+        let config = match self.config {
+            Some(config) if self.is_split() => config,
+            _ => return false,
+        };
+        let isochronous_out =
+            matches!(config.ep_type, EndpointType::Isochronous { .. }) && !config.endpoint.is_in();
+
+        let delay = if !self.complete_split
+            && !isochronous_out
+            && hcint & host_channel::HCINT::ACK::mask != 0
+        {
+            self.complete_split = true;
+            // The hub runs the transaction in the microframe after the start-split
+            2
+        } else if self.complete_split && hcint & host_channel::HCINT::NYET::mask != 0 {
+            1
+        } else {
+            return false;
+        };
+
+        if self.interval != 0 {
+            self.complete_split_frame = Some((frame + delay) % FRAME_NUMBER_MODULO);
+        }
+        true
+    }
+
+    /// Returns `true` if the deferred complete-split is due in microframe `frame`.
+    pub(super) fn complete_split_due(&mut self, frame: u16) -> bool {
+        match self.complete_split_frame {
+            Some(due) if self.state == ChannelState::Busy && frame_diff(frame, due) >= 0 => {
+                self.complete_split_frame = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::PipeConfig;
+    use usb_device::endpoint::{
+        EndpointAddress, IsochronousSynchronizationType, IsochronousUsageType,
+    };
+    use usb_device::UsbDirection;
+
+    const ACK: u32 = host_channel::HCINT::ACK::mask;
+    const NYET: u32 = host_channel::HCINT::NYET::mask;
+
+    fn channel(ep_type: EndpointType, direction: UsbDirection, hub_address: u8) -> Channel {
+        let mut channel = Channel::default();
+        channel.config = Some(PipeConfig {
+            device_address: 2,
+            endpoint: EndpointAddress::from_parts(1, direction),
+            ep_type,
+            max_packet_size: 64,
+            speed: Speed::Full,
+            interval: 1,
+            hub_address,
+            hub_port: 3,
+        });
+        channel.state = ChannelState::Busy;
+        channel
+    }
+
+    fn isochronous() -> EndpointType {
+        EndpointType::Isochronous {
+            synchronization: IsochronousSynchronizationType::NoSynchronization,
+            usage: IsochronousUsageType::Data,
+        }
+    }
+
+    #[test]
+    fn only_devices_behind_a_hub_are_split() {
+        assert!(channel(EndpointType::Bulk, UsbDirection::In, 1).is_split());
+        assert!(!channel(EndpointType::Bulk, UsbDirection::In, 0).is_split());
+
+        let mut high_speed = channel(EndpointType::Bulk, UsbDirection::In, 1);
+        high_speed.config.as_mut().unwrap().speed = Speed::High;
+        assert!(!high_speed.is_split());
+        assert!(!high_speed.handle_split(ACK, 0));
+    }
+
+    #[test]
+    fn complete_split_follows_ack() {
+        let mut ch = channel(EndpointType::Bulk, UsbDirection::In, 1);
+        // NYET is only expected for complete-splits
+        assert!(!ch.handle_split(NYET, 0));
+
+        assert!(ch.handle_split(ACK, 0));
+        assert!(ch.complete_split);
+        assert!(ch.handle_split(NYET, 0));
+        assert!(ch.complete_split);
+        assert!(!ch.handle_split(ACK, 0));
+        // Non-periodic complete-splits are issued right away
+        assert_eq!(ch.complete_split_frame, None);
+    }
+
+    #[test]
+    fn periodic_complete_split_is_scheduled() {
+        let mut ch = channel(EndpointType::Interrupt, UsbDirection::In, 1);
+        ch.interval = 8;
+
+        assert!(ch.handle_split(ACK, 100));
+        assert_eq!(ch.complete_split_frame, Some(102));
+        assert!(!ch.complete_split_due(101));
+        assert!(ch.complete_split_due(102));
+        assert_eq!(ch.complete_split_frame, None);
+
+        // The hub has not finished the transaction, retry in the next microframe
+        assert!(ch.handle_split(NYET, 102));
+        assert_eq!(ch.complete_split_frame, Some(103));
+        assert!(ch.complete_split_due(104));
+
+        // Wrap-around of the frame number
+        ch.complete_split = false;
+        assert!(ch.handle_split(ACK, FRAME_NUMBER_MODULO - 1));
+        assert_eq!(ch.complete_split_frame, Some(1));
+        assert!(!ch.complete_split_due(FRAME_NUMBER_MODULO - 1));
+        assert!(ch.complete_split_due(1));
+    }
+
+    #[test]
+    fn aborted_complete_split_is_dropped() {
+        let mut ch = channel(EndpointType::Interrupt, UsbDirection::In, 1);
+        ch.interval = 8;
+        assert!(ch.handle_split(ACK, 10));
+
+        ch.state = ChannelState::Done(Err(HostError::Disconnected));
+        assert!(!ch.complete_split_due(12));
+    }
+
+    #[test]
+    fn isochronous_out_has_no_complete_split() {
+        let mut ch = channel(isochronous(), UsbDirection::Out, 1);
+        ch.interval = 8;
+        assert!(!ch.handle_split(ACK, 0));
+        assert!(!ch.complete_split);
+        assert_eq!(ch.complete_split_frame, None);
+    }
+
+    #[test]
+    fn large_isochronous_splits_are_rejected() {
+        for direction in [UsbDirection::In, UsbDirection::Out] {
+            let mut ch = channel(isochronous(), direction, 1);
+            assert_eq!(ch.check_periodic_split(), Ok(()));
+
+            ch.config.as_mut().unwrap().max_packet_size = 189;
+            assert_eq!(ch.check_periodic_split(), Err(HostError::InvalidPipe));
+
+            // Without a transaction translator the packet is sent as a whole
+            ch.config.as_mut().unwrap().hub_address = 0;
+            assert_eq!(ch.check_periodic_split(), Ok(()));
+        }
+        let mut ch = channel(EndpointType::Interrupt, UsbDirection::Out, 1);
+        ch.config.as_mut().unwrap().max_packet_size = 1023;
+        assert_eq!(ch.check_periodic_split(), Ok(()));
+    }
+}
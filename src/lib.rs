@@ -8,6 +8,15 @@ compile_error!("choose only one USB mode");
 #[cfg(not(any(feature = "fs", feature = "hs")))]
 compile_error!("select USB mode feature (fs/hs)");
 
+#[cfg(all(feature = "dma", not(feature = "hs")))]
+compile_error!("internal DMA is only available on HS peripherals");
+
+#[cfg(all(feature = "sim", feature = "dma"))]
+compile_error!("internal DMA is not available on the simulated core");
+
+#[cfg(feature = "sim")]
+extern crate std;
+
 mod endpoint;
 mod endpoint_memory;
 
@@ -18,6 +27,22 @@ pub mod bus;
 
 pub use crate::bus::UsbBus;
 
+/// USB host driver.
+pub mod host;
+
+pub use crate::host::UsbHost;
+
+/// USB OTG dual-role support.
+pub mod otg;
+
+/// `embassy-usb` driver.
+#[cfg(feature = "embassy")]
+pub mod embassy;
+
+/// Simulated OTG core for host-side testing.
+#[cfg(feature = "sim")]
+pub mod sim;
+
 mod ral;
 mod transition;
 
@@ -37,6 +62,9 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     /// Number of (bidirectional) endpoints
     const ENDPOINT_COUNT: usize;
 
+    /// Number of host channels
+    const HOST_CHANNEL_COUNT: usize = if Self::HIGH_SPEED { 12 } else { 8 };
+
     /// Enables USB device on its peripheral bus
     fn enable();
 
@@ -52,6 +80,52 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     ///
     /// This function should turn on LDO and PLL and wait for PHY clock to become stable.
     fn setup_internal_hs_phy(&self) {}
+
+    /// Called once the PHY clock is stopped on suspend, when low-power suspend is enabled
+    ///
+    /// The HAL can lower the clocks or prepare the MCU to enter STOP mode here, the USB wakeup
+    /// interrupt brings it back.
+    fn suspend_low_power(&self) {}
+
+    /// Called once the PHY clock is restarted on resume, when low-power suspend is enabled
+    ///
+    /// The HAL must restore the clocks required by the peripheral here, e.g. after STOP mode.
+    /// The hook is called outside of any critical section, before the driver call that
+    /// restarted the PHY clock returns.
+    fn resume_low_power(&self) {}
+}
+
+/// Allows the device and the host drivers of a dual-role application to share the peripheral.
+unsafe impl<USB: UsbPeripheral> UsbPeripheral for &USB {
+    const REGISTERS: *const () = USB::REGISTERS;
+    const HIGH_SPEED: bool = USB::HIGH_SPEED;
+    const FIFO_DEPTH_WORDS: usize = USB::FIFO_DEPTH_WORDS;
+    const ENDPOINT_COUNT: usize = USB::ENDPOINT_COUNT;
+    const HOST_CHANNEL_COUNT: usize = USB::HOST_CHANNEL_COUNT;
+
+    fn enable() {
+        USB::enable()
+    }
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        (**self).ahb_frequency_hz()
+    }
+
+    fn phy_type(&self) -> PhyType {
+        (**self).phy_type()
+    }
+
+    fn setup_internal_hs_phy(&self) {
+        (**self).setup_internal_hs_phy()
+    }
+
+    fn suspend_low_power(&self) {
+        (**self).suspend_low_power()
+    }
+
+    fn resume_low_power(&self) {
+        (**self).resume_low_power()
+    }
 }
 
 /// USB PHY type
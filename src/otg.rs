@@ -0,0 +1,223 @@
+use crate::ral::{modify_reg, otg_global, read_reg, write_reg};
+use crate::target::UsbRegisters;
+use crate::UsbPeripheral;
+use core::marker::PhantomData;
+
+/// Role of a dual-role core.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// B-device: the ID pin is floating, the core operates as a device.
+    Device,
+    /// A-device: the ID pin is grounded, the core operates as a host.
+    Host,
+}
+
+/// OTG protocol event reported by [`DualRole::poll_otg`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OtgEvent {
+    /// Nothing happened.
+    None,
+    /// VBUS dropped below the B-session valid threshold, the session ended.
+    SessionEnd,
+    /// A session request started with [`DualRole::request_session`] finished.
+    SessionRequestDone {
+        /// `true` if the A-device supplied VBUS in response to the request
+        success: bool,
+    },
+    /// Host negotiation started with [`DualRole::request_host_role`] finished.
+    HostNegotiationDone {
+        /// `true` if the roles were swapped
+        success: bool,
+    },
+    /// The B-device requested to become the host, the roles are being swapped.
+    HostNegotiationDetected,
+    /// The A-device timed out waiting for the B-device to connect.
+    ADeviceTimeout,
+    /// The connection of a B-device is debounced, the port can be reset.
+    DebounceDone,
+    /// A B-device requested a session, VBUS should be switched on.
+    SessionRequest,
+}
+
+/// Watches the connector ID of a dual-role core.
+///
+/// Dual-role applications create both drivers with `new_dual_role`, sharing the peripheral by
+/// reference, and bring up the driver for the role reported by [`poll`](Self::poll):
+///
+/// ```ignore
+/// let usb: &'static USB = /* ... */;
+/// let usb_bus = UsbBus::new_dual_role(usb, ep_memory);
+/// let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, vid_pid).build();
+/// let mut host = UsbHost::new_dual_role(usb, host_memory);
+/// let otg = DualRole::new(usb);
+/// let mut host_enabled = false;
+///
+/// loop {
+///     match otg.poll() {
+///         Some(Role::Host) => {
+///             usb_dev.bus().deactivate();
+///             // Retried below until the core has switched to host mode
+///             host_enabled = false;
+///         }
+///         Some(Role::Device) => {
+///             host.disable();
+///             usb_dev.bus().activate();
+///         }
+///         None => {}
+///     }
+///     match otg.role() {
+///         Role::Device => { usb_dev.poll(&mut [&mut class]); }
+///         Role::Host if !host_enabled => {
+///             if host.enable().is_ok() {
+///                 host.set_port_power(true);
+///                 host_enabled = true;
+///             }
+///         }
+///         Role::Host => { host.poll(); }
+///     }
+/// }
+/// ```
+pub struct DualRole<USB> {
+    regs: UsbRegisters,
+    _marker: PhantomData<USB>,
+}
+
+impl<USB: UsbPeripheral> DualRole<USB> {
+    /// Creates a connector ID watcher for the given peripheral.
+    pub fn new(_peripheral: &USB) -> Self {
+        Self {
+            regs: UsbRegisters::new::<USB>(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the role selected by the ID pin.
+    pub fn role(&self) -> Role {
+        if read_reg!(otg_global, self.regs.global(), GOTGCTL, CIDSTS) == 0 {
+            Role::Host
+        } else {
+            Role::Device
+        }
+    }
+
+    /// Enables the Session Request Protocol and the Host Negotiation Protocol capabilities.
+    ///
+    /// HNP requires SRP. Both protocols rely on VBUS sensing, the session valid overrides used
+    /// by some cores without VBUS sensing defeat them.
+    pub fn set_otg_capabilities(&self, srp: bool, hnp: bool) {
+        critical_section::with(|_| {
+            modify_reg!(otg_global, self.regs.global(), GUSBCFG,
+                SRPCAP: srp as u32,
+                HNPCAP: (srp && hnp) as u32
+            );
+        });
+    }
+
+    /// Returns `true` if VBUS is above the B-session valid threshold.
+    pub fn b_session_valid(&self) -> bool {
+        read_reg!(otg_global, self.regs.global(), GOTGCTL, BSVLD) != 0
+    }
+
+    /// Requests a session from the A-device with data-line and VBUS pulsing (B-device).
+    ///
+    /// The outcome is reported with [`OtgEvent::SessionRequestDone`].
+    pub fn request_session(&self) {
+        critical_section::with(|_| {
+            modify_reg!(otg_global, self.regs.global(), GOTGCTL, SRQ: 1);
+        });
+    }
+
+    /// Records that the host enabled HNP on this device with SET_FEATURE(b_hnp_enable)
+    /// (B-device).
+    pub fn set_device_hnp_enabled(&self, enabled: bool) {
+        critical_section::with(|_| {
+            modify_reg!(otg_global, self.regs.global(), GOTGCTL, DHNPEN: enabled as u32);
+        });
+    }
+
+    /// Requests the host role once the A-device has suspended the bus (B-device).
+    ///
+    /// HNP must have been enabled with [`set_device_hnp_enabled`](Self::set_device_hnp_enabled).
+    /// The outcome is reported with [`OtgEvent::HostNegotiationDone`].
+    pub fn request_host_role(&self) {
+        critical_section::with(|_| {
+            modify_reg!(otg_global, self.regs.global(), GOTGCTL, HNPRQ: 1);
+        });
+    }
+
+    /// Accepts a host role swap requested by the B-device, after the host enabled HNP on the
+    /// device with SET_FEATURE(b_hnp_enable) (A-device).
+    pub fn set_host_hnp_enabled(&self, enabled: bool) {
+        critical_section::with(|_| {
+            modify_reg!(otg_global, self.regs.global(), GOTGCTL, HSHNPEN: enabled as u32);
+        });
+    }
+
+    /// Returns the next pending OTG protocol event.
+    ///
+    /// The OTG interrupts are enabled by the drivers created with `new_dual_role`.
+    pub fn poll_otg(&self) -> OtgEvent {
+        critical_section::with(|_| {
+            let regs = self.regs;
+
+            if read_reg!(otg_global, regs.global(), GINTSTS, SRQINT) != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, SRQINT: 1);
+                return OtgEvent::SessionRequest;
+            }
+
+            // GOTGINT bits are cleared by writing 1, report one event at a time
+            let gotgint = read_reg!(otg_global, regs.global(), GOTGINT);
+            if gotgint & otg_global::GOTGINT::SEDET::mask != 0 {
+                write_reg!(otg_global, regs.global(), GOTGINT, SEDET: 1);
+                OtgEvent::SessionEnd
+            } else if gotgint & otg_global::GOTGINT::SRSSCHG::mask != 0 {
+                write_reg!(otg_global, regs.global(), GOTGINT, SRSSCHG: 1);
+                let success = read_reg!(otg_global, regs.global(), GOTGCTL, SRQSCS) != 0;
+                modify_reg!(otg_global, regs.global(), GOTGCTL, SRQ: 0);
+                OtgEvent::SessionRequestDone { success }
+            } else if gotgint & otg_global::GOTGINT::HNSSCHG::mask != 0 {
+                write_reg!(otg_global, regs.global(), GOTGINT, HNSSCHG: 1);
+                let success = read_reg!(otg_global, regs.global(), GOTGCTL, HNGSCS) != 0;
+                modify_reg!(otg_global, regs.global(), GOTGCTL, HNPRQ: 0);
+                OtgEvent::HostNegotiationDone { success }
+            } else if gotgint & otg_global::GOTGINT::HNGDET::mask != 0 {
+                write_reg!(otg_global, regs.global(), GOTGINT, HNGDET: 1);
+                OtgEvent::HostNegotiationDetected
+            } else if gotgint & otg_global::GOTGINT::ADTOCHG::mask != 0 {
+                write_reg!(otg_global, regs.global(), GOTGINT, ADTOCHG: 1);
+                OtgEvent::ADeviceTimeout
+            } else if gotgint & otg_global::GOTGINT::DBCDNE::mask != 0 {
+                write_reg!(otg_global, regs.global(), GOTGINT, DBCDNE: 1);
+                OtgEvent::DebounceDone
+            } else {
+                OtgEvent::None
+            }
+        })
+    }
+
+    /// Returns the role the core currently operates in.
+    ///
+    /// The core switches modes some time after the ID pin changed.
+    pub fn current_mode(&self) -> Role {
+        if read_reg!(otg_global, self.regs.global(), GINTSTS, CMOD) != 0 {
+            Role::Host
+        } else {
+            Role::Device
+        }
+    }
+
+    /// Returns the new role if the ID pin changed since the last call.
+    ///
+    /// The driver of the previous role must be deactivated and the driver of the new role
+    /// enabled. The ID change interrupt is enabled by the drivers created with `new_dual_role`.
+    pub fn poll(&self) -> Option<Role> {
+        critical_section::with(|_| {
+            if read_reg!(otg_global, self.regs.global(), GINTSTS, CIDSCHG) != 0 {
+                write_reg!(otg_global, self.regs.global(), GINTSTS, CIDSCHG: 1);
+                Some(self.role())
+            } else {
+                None
+            }
+        })
+    }
+}
@@ -4,7 +4,10 @@ pub mod instances;
 pub mod peripherals;
 pub mod stm32f429;
 
+#[cfg(not(feature = "sim"))]
 pub use ral_registers as register;
+#[cfg(feature = "sim")]
+pub use crate::sim::register;
 pub use ral_registers::{modify_reg, read_reg, write_reg};
 
 pub mod otg_global {
@@ -29,6 +32,17 @@ pub mod otg_device {
     pub use super::stm32f429::otg_hs_device::*;
 }
 
+pub mod otg_host {
+    #[cfg(feature = "fs")]
+    pub use super::stm32f429::otg_fs_host::OTG_FS_HOST as OTG_HOST;
+    #[cfg(feature = "fs")]
+    pub use super::stm32f429::otg_fs_host::*;
+    #[cfg(feature = "hs")]
+    pub use super::stm32f429::otg_hs_host::OTG_HS_HOST as OTG_HOST;
+    #[cfg(feature = "hs")]
+    pub use super::stm32f429::otg_hs_host::*;
+}
+
 pub mod otg_pwrclk {
     #[cfg(feature = "fs")]
     pub use super::stm32f429::otg_s_pwrclk::OTG_FS_PWRCLK as OTG_PWRCLK;
@@ -45,11 +59,115 @@ pub mod otg_global_dieptxfx {
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_fs_global::DIEPTXF1 as DIEPTXFx;
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DIEPTXFx: RWRegister<u32>,
     }
 }
 
+/// Link Power Management registers of cores with ID 0x2000 and above, missing from the
+/// STM32F429 register definitions.
+#[cfg(feature = "lpm")]
+#[allow(unused, non_upper_case_globals)]
+pub mod otg_global_lpm {
+    use super::register::RWRegister;
+
+    pub mod GINTSTS {
+        /// LPM interrupt
+        pub mod LPMINT {
+            pub const offset: u32 = 27;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+    }
+
+    pub mod GINTMSK {
+        /// LPM interrupt mask
+        pub mod LPMINTM {
+            pub const offset: u32 = 27;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+    }
+
+    pub mod GLPMCFG {
+        /// LPM support enable
+        pub mod LPMEN {
+            pub const offset: u32 = 0;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// LPM token acknowledge enable
+        pub mod LPMACK {
+            pub const offset: u32 = 1;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// Best effort service latency of the last LPM token
+        pub mod BESL {
+            pub const offset: u32 = 2;
+            pub const mask: u32 = 0xf << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// bRemoteWake value of the last LPM token
+        pub mod REMWAKE {
+            pub const offset: u32 = 6;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// L1 shallow sleep enable
+        pub mod L1SSEN {
+            pub const offset: u32 = 7;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// L1 deep sleep enable
+        pub mod L1DSEN {
+            pub const offset: u32 = 12;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// Port sleep status
+        pub mod SLPSTS {
+            pub const offset: u32 = 15;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+        /// Sleep state resume OK
+        pub mod L1RSMOK {
+            pub const offset: u32 = 16;
+            pub const mask: u32 = 1 << offset;
+            pub mod R {}
+            pub mod W {}
+            pub mod RW {}
+        }
+    }
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        _reserved0: [u32; 21],
+        pub GLPMCFG: RWRegister<u32>,
+    }
+}
+
 pub mod endpoint_in {
     use super::register::RWRegister;
 
@@ -63,13 +181,17 @@ pub mod endpoint_in {
         DIEPCTL1 as DIEPCTL, DIEPINT1 as DIEPINT, DIEPTSIZ1 as DIEPTSIZ, DTXFSTS1 as DTXFSTS,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DIEPCTL: RWRegister<u32>,
         _reserved0: u32,
         pub DIEPINT: RWRegister<u32>,
         _reserved1: u32,
         pub DIEPTSIZ: RWRegister<u32>,
+        #[cfg(feature = "fs")]
         _reserved2: u32,
+        #[cfg(feature = "hs")]
+        pub DIEPDMA: RWRegister<u32>,
         pub DTXFSTS: RWRegister<u32>,
         _reserved3: u32,
     }
@@ -84,13 +206,19 @@ pub mod endpoint0_out {
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_hs_device::{DOEPCTL0, DOEPINT0, DOEPTSIZ0};
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL0: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT0: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ0: RWRegister<u32>,
+        #[cfg(feature = "fs")]
         _reserved2: [u32; 3],
+        #[cfg(feature = "hs")]
+        pub DOEPDMA0: RWRegister<u32>,
+        #[cfg(feature = "hs")]
+        _reserved2: [u32; 2],
     }
 }
 
@@ -107,12 +235,48 @@ pub mod endpoint_out {
         DOEPCTL1 as DOEPCTL, DOEPINT1 as DOEPINT, DOEPTSIZ1 as DOEPTSIZ,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ: RWRegister<u32>,
+        #[cfg(feature = "fs")]
         _reserved2: [u32; 3],
+        #[cfg(feature = "hs")]
+        pub DOEPDMA: RWRegister<u32>,
+        #[cfg(feature = "hs")]
+        _reserved2: [u32; 2],
+    }
+}
+
+pub mod host_channel {
+    use super::register::RWRegister;
+
+    #[cfg(feature = "fs")]
+    pub use super::stm32f429::otg_fs_host::{HCINT0 as HCINT, HCTSIZ0 as HCTSIZ};
+
+    #[cfg(feature = "hs")]
+    pub use super::stm32f429::otg_hs_host::{
+        HCCHAR0 as HCCHAR, HCINT0 as HCINT, HCSPLT0 as HCSPLT, HCTSIZ0 as HCTSIZ,
+    };
+
+    // The multi count field is called MCNT on FS cores and MC on HS cores
+    #[cfg(feature = "fs")]
+    pub mod HCCHAR {
+        pub use super::super::stm32f429::otg_fs_host::HCCHAR0::MCNT as MC;
+        pub use super::super::stm32f429::otg_fs_host::HCCHAR0::*;
+    }
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        pub HCCHAR: RWRegister<u32>,
+        pub HCSPLT: RWRegister<u32>,
+        pub HCINT: RWRegister<u32>,
+        pub HCINTMSK: RWRegister<u32>,
+        pub HCTSIZ: RWRegister<u32>,
+        pub HCDMA: RWRegister<u32>,
+        _reserved0: [u32; 2],
     }
 }
@@ -0,0 +1,328 @@
+//! USB host issuing transfers to a simulated core.
+
+use std::vec::Vec;
+
+use super::{Handshake, SimCore};
+
+const GET_DESCRIPTOR: u8 = 0x06;
+const SET_ADDRESS: u8 = 0x05;
+const SET_CONFIGURATION: u8 = 0x09;
+
+const DEVICE_DESCRIPTOR: u8 = 0x01;
+const CONFIGURATION_DESCRIPTOR: u8 = 0x02;
+const ENDPOINT_DESCRIPTOR: u8 = 0x05;
+
+/// Error of a transfer issued by [`SimHost`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransferError {
+    /// The endpoint answered with a STALL handshake
+    Stall,
+    /// The endpoint NAKed more times than the NAK limit of the host
+    Timeout,
+}
+
+/// Descriptors read by [`SimHost::enumerate`].
+#[derive(Clone, Debug)]
+pub struct Enumeration {
+    /// Device descriptor
+    pub device_descriptor: Vec<u8>,
+    /// Configuration descriptor, with the interface and endpoint descriptors that follow it
+    pub configuration_descriptor: Vec<u8>,
+}
+
+/// USB host playing the transfers of a host controller against a [`SimCore`].
+///
+/// The device is run between the transactions by a `device` closure, which must poll the
+/// `UsbDevice` and its classes. The host retries NAKed transactions, polling the device before
+/// each retry.
+///
+/// ```
+/// use synopsys_usb_otg::sim::{SimCore, SimHost, SimPeripheral};
+/// use synopsys_usb_otg::UsbBus;
+/// use usb_device::prelude::*;
+///
+/// let core = SimCore::new(SimCore::F446);
+/// let ep_memory = Box::leak(vec![0; 1024].into_boxed_slice());
+/// let usb_bus = UsbBus::new(SimPeripheral::new(&core), ep_memory);
+/// let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd)).build();
+///
+/// let mut host = SimHost::new(&core);
+/// let mut device = || {
+///     usb_dev.poll(&mut []);
+/// };
+/// let enumeration = host.enumerate(&mut device, 1).unwrap();
+/// assert_eq!(enumeration.device_descriptor[8..10], [0xc0, 0x16]);
+/// ```
+pub struct SimHost {
+    core: SimCore,
+    address: u8,
+    max_packet_size_0: usize,
+    /// Max packet sizes of the OUT and IN endpoints, from the configuration descriptor
+    max_packet_sizes: [[usize; 16]; 2],
+    nak_limit: usize,
+}
+
+impl SimHost {
+    /// Creates a host attached to `core`.
+    pub fn new(core: &SimCore) -> SimHost {
+        SimHost {
+            core: core.clone(),
+            address: 0,
+            max_packet_size_0: 8,
+            max_packet_sizes: [[64; 16]; 2],
+            nak_limit: 100,
+        }
+    }
+
+    /// Returns the simulated core the host is attached to.
+    pub fn core(&self) -> &SimCore {
+        &self.core
+    }
+
+    /// Returns the address assigned to the device.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Sets the number of NAKs after which a transaction fails with
+    /// [`Timeout`](TransferError::Timeout).
+    pub fn set_nak_limit(&mut self, nak_limit: usize) {
+        self.nak_limit = nak_limit;
+    }
+
+    /// Builds a SETUP packet.
+    pub fn setup_packet(
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> [u8; 8] {
+        let [value_lo, value_hi] = value.to_le_bytes();
+        let [index_lo, index_hi] = index.to_le_bytes();
+        let [length_lo, length_hi] = length.to_le_bytes();
+        [
+            request_type,
+            request,
+            value_lo,
+            value_hi,
+            index_lo,
+            index_hi,
+            length_lo,
+            length_hi,
+        ]
+    }
+
+    /// Resets the bus, the device goes back to the default address.
+    pub fn reset(&mut self, device: &mut impl FnMut()) {
+        self.core.bus_reset();
+        device();
+        self.address = 0;
+        self.max_packet_size_0 = 8;
+    }
+
+    /// Enumerates the device: resets the bus, assigns `address` to the device, reads its
+    /// descriptors and selects its first configuration.
+    pub fn enumerate(
+        &mut self,
+        device: &mut impl FnMut(),
+        address: u8,
+    ) -> Result<Enumeration, TransferError> {
+        self.reset(device);
+
+        // The max packet size of EP0 is in the first 8 bytes
+        let header = self.get_descriptor(device, DEVICE_DESCRIPTOR, 0, 0, 8)?;
+        self.max_packet_size_0 = header[7] as usize;
+
+        self.set_address(device, address)?;
+
+        let device_descriptor = self.get_descriptor(device, DEVICE_DESCRIPTOR, 0, 0, 18)?;
+        let header = self.get_descriptor(device, CONFIGURATION_DESCRIPTOR, 0, 0, 9)?;
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let configuration_descriptor =
+            self.get_descriptor(device, CONFIGURATION_DESCRIPTOR, 0, 0, total_length)?;
+        self.parse_endpoints(&configuration_descriptor);
+
+        self.set_configuration(device, configuration_descriptor[5])?;
+
+        Ok(Enumeration {
+            device_descriptor,
+            configuration_descriptor,
+        })
+    }
+
+    /// Reads a descriptor with a GET_DESCRIPTOR request.
+    pub fn get_descriptor(
+        &mut self,
+        device: &mut impl FnMut(),
+        descriptor_type: u8,
+        index: u8,
+        language_id: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let value = (descriptor_type as u16) << 8 | index as u16;
+        let setup = Self::setup_packet(0x80, GET_DESCRIPTOR, value, language_id, length);
+        self.control_in(device, &setup)
+    }
+
+    /// Assigns `address` to the device with a SET_ADDRESS request.
+    pub fn set_address(
+        &mut self,
+        device: &mut impl FnMut(),
+        address: u8,
+    ) -> Result<(), TransferError> {
+        let setup = Self::setup_packet(0x00, SET_ADDRESS, address as u16, 0, 0);
+        self.control_out(device, &setup, &[])?;
+        self.address = address;
+        Ok(())
+    }
+
+    /// Selects a configuration with a SET_CONFIGURATION request.
+    pub fn set_configuration(
+        &mut self,
+        device: &mut impl FnMut(),
+        configuration: u8,
+    ) -> Result<(), TransferError> {
+        let setup = Self::setup_packet(0x00, SET_CONFIGURATION, configuration as u16, 0, 0);
+        self.control_out(device, &setup, &[])
+    }
+
+    /// Performs a control transfer with an IN data stage on EP0.
+    ///
+    /// The data stage ends with a short packet or after the length requested in `setup`.
+    pub fn control_in(
+        &mut self,
+        device: &mut impl FnMut(),
+        setup: &[u8; 8],
+    ) -> Result<Vec<u8>, TransferError> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+        self.transaction(device, |core| core.setup_token(0, setup))?;
+
+        let mut data = Vec::new();
+        while data.len() < length {
+            let packet = self.in_packet(device, 0)?;
+            let short = packet.len() < self.max_packet_size_0;
+            data.extend(packet);
+            if short {
+                break;
+            }
+        }
+
+        self.out_packet(device, 0, &[])?;
+        Ok(data)
+    }
+
+    /// Performs a control transfer with an optional OUT data stage on EP0.
+    pub fn control_out(
+        &mut self,
+        device: &mut impl FnMut(),
+        setup: &[u8; 8],
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        self.transaction(device, |core| core.setup_token(0, setup))?;
+
+        for packet in data.chunks(self.max_packet_size_0) {
+            self.out_packet(device, 0, packet)?;
+        }
+
+        let status = self.in_packet(device, 0)?;
+        assert!(status.is_empty(), "status stage with data");
+        Ok(())
+    }
+
+    /// Reads a transfer from IN endpoint `ep`, until a short packet or `max_length` bytes.
+    ///
+    /// At least one packet is read, a zero-length packet ends an empty transfer.
+    pub fn in_transfer(
+        &mut self,
+        device: &mut impl FnMut(),
+        ep: usize,
+        max_length: usize,
+    ) -> Result<Vec<u8>, TransferError> {
+        let max_packet_size = self.max_packet_sizes[1][ep];
+        let mut data = Vec::new();
+        loop {
+            let packet = self.in_packet(device, ep)?;
+            let short = packet.len() < max_packet_size;
+            data.extend(packet);
+            if short || data.len() >= max_length {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Writes a transfer to OUT endpoint `ep`, ended by a short packet.
+    ///
+    /// A zero-length packet is sent after data filling the last packet.
+    pub fn out_transfer(
+        &mut self,
+        device: &mut impl FnMut(),
+        ep: usize,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let max_packet_size = self.max_packet_sizes[0][ep];
+        let mut short = false;
+        for packet in data.chunks(max_packet_size) {
+            self.out_packet(device, ep, packet)?;
+            short = packet.len() < max_packet_size;
+        }
+        if !short {
+            self.out_packet(device, ep, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Reads a packet from IN endpoint `ep`.
+    pub fn in_packet(
+        &mut self,
+        device: &mut impl FnMut(),
+        ep: usize,
+    ) -> Result<Vec<u8>, TransferError> {
+        self.transaction(device, |core| core.in_token(ep))
+    }
+
+    /// Writes a packet to OUT endpoint `ep`.
+    pub fn out_packet(
+        &mut self,
+        device: &mut impl FnMut(),
+        ep: usize,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        self.transaction(device, |core| core.out_token(ep, data))
+    }
+
+    /// Issues a token until the device accepts it, running the device after each attempt.
+    fn transaction<T>(
+        &self,
+        device: &mut impl FnMut(),
+        mut token: impl FnMut(&SimCore) -> Result<T, Handshake>,
+    ) -> Result<T, TransferError> {
+        for _ in 0..=self.nak_limit {
+            let result = token(&self.core);
+            device();
+            match result {
+                Ok(value) => return Ok(value),
+                Err(Handshake::Stall) => return Err(TransferError::Stall),
+                Err(Handshake::Nak) => {}
+            }
+        }
+        Err(TransferError::Timeout)
+    }
+
+    fn parse_endpoints(&mut self, configuration_descriptor: &[u8]) {
+        let mut descriptors = configuration_descriptor;
+        while descriptors.len() >= 2 && descriptors[0] as usize <= descriptors.len() {
+            let (descriptor, rest) = descriptors.split_at(descriptors[0] as usize);
+            if descriptor.len() >= 7 && descriptor[1] == ENDPOINT_DESCRIPTOR {
+                let direction = (descriptor[2] >> 7) as usize;
+                let ep = (descriptor[2] & 0x0f) as usize;
+                let max_packet_size = u16::from_le_bytes([descriptor[4], descriptor[5]]);
+                self.max_packet_sizes[direction][ep] = (max_packet_size & 0x7ff) as usize;
+            }
+            if descriptor.is_empty() {
+                break;
+            }
+            descriptors = rest;
+        }
+    }
+}
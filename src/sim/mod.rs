@@ -0,0 +1,207 @@
+//! With the `sim` feature, the register blocks of the driver are backed by a model of a
+//! full-speed core instead of memory-mapped registers. The model implements the semantics the
+//! driver relies on: the Rx FIFO and its GRXSTSR/GRXSTSP status queue, the Tx FIFOs, the
+//! write-1-to-clear interrupt registers and the endpoint enable and NAK state.
+//!
+//! Tests play the role of the host by issuing tokens to a [`SimCore`], and drive the device
+//! through a [`UsbBus`](crate::UsbBus) created from a [`SimPeripheral`]:
+//!
+//! ```
+//! use synopsys_usb_otg::sim::{SimCore, SimPeripheral};
+//! use synopsys_usb_otg::UsbBus;
+//!
+//! let core = SimCore::new(SimCore::F446);
+//! let ep_memory = Box::leak(vec![0; 1024].into_boxed_slice());
+//! let usb_bus = UsbBus::new(SimPeripheral::new(&core), ep_memory);
+//! ```
+//!
+//! [`SimHost`] builds complete transfers on top of the tokens, to enumerate and exercise a
+//! `UsbDevice` and its classes from ordinary tests.
+//!
+//! Register accesses are routed to the core attached to the current thread, so that tests can
+//! run in parallel.
+
+use core::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use crate::UsbPeripheral;
+
+mod host;
+mod model;
+pub mod register;
+
+pub use self::host::{Enumeration, SimHost, TransferError};
+use self::model::Model;
+
+/// Size of the register space of a core, up to the FIFO of channel #15
+const REGISTER_SPACE_SIZE: usize = 0x11000;
+
+/// Backing memory of the register blocks, which only provides their addresses
+static REGISTER_SPACE: [u32; REGISTER_SPACE_SIZE / 4] = [0; REGISTER_SPACE_SIZE / 4];
+
+std::thread_local! {
+    static CURRENT_CORE: RefCell<Option<SimCore>> = const { RefCell::new(None) };
+}
+
+fn with_current_core<R>(address: usize, f: impl FnOnce(&mut Model, usize) -> R) -> R {
+    let offset = address - REGISTER_SPACE.as_ptr() as usize;
+    CURRENT_CORE.with(|current| {
+        let current = current.borrow();
+        let core = current
+            .as_ref()
+            .expect("no simulated core is attached to this thread");
+        let mut model = core.model.lock().unwrap();
+        f(&mut model, offset)
+    })
+}
+
+fn read(address: usize) -> u32 {
+    with_current_core(address, |model, offset| model.read(offset))
+}
+
+fn write(address: usize, value: u32) {
+    with_current_core(address, |model, offset| model.write(offset, value))
+}
+
+/// Handshake of the device refusing a transaction.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Handshake {
+    /// The endpoint is not ready, or the packet doesn't fit in the FIFO
+    Nak,
+    /// The endpoint is halted
+    Stall,
+}
+
+/// Simulated OTG core, seen from the USB host side.
+///
+/// Cloning the core returns another handle to the same model.
+#[derive(Clone)]
+pub struct SimCore {
+    model: Arc<Mutex<Model>>,
+}
+
+impl SimCore {
+    /// Core ID of F429-like cores, which re-enable OUT endpoints when the transfer completes
+    pub const F429: u32 = 0x0000_1200;
+
+    /// Core ID of F446-like cores, which re-enable OUT endpoints once the packet is read
+    pub const F446: u32 = 0x0000_2000;
+
+    /// Creates a core reporting `core_id` in its CID register.
+    pub fn new(core_id: u32) -> SimCore {
+        SimCore {
+            model: Arc::new(Mutex::new(Model::new(core_id))),
+        }
+    }
+
+    /// Routes the register accesses of the current thread to this core.
+    pub fn attach(&self) {
+        CURRENT_CORE.with(|current| *current.borrow_mut() = Some(self.clone()));
+    }
+
+    /// Returns the raw value of the register at `offset`, without side effects.
+    pub fn register(&self, offset: usize) -> u32 {
+        self.model.lock().unwrap().register(offset)
+    }
+
+    /// Returns `true` if the core is in device mode and not soft-disconnected.
+    pub fn is_connected(&self) -> bool {
+        self.model.lock().unwrap().is_connected()
+    }
+
+    /// Returns the address assigned to the device in DCFG.
+    pub fn device_address(&self) -> u8 {
+        self.model.lock().unwrap().device_address()
+    }
+
+    /// Returns `true` if an unmasked interrupt is pending and the global interrupt is enabled.
+    pub fn is_interrupt_pending(&self) -> bool {
+        self.model.lock().unwrap().is_interrupt_pending()
+    }
+
+    /// Signals a bus reset, followed by the enumeration of a full-speed device.
+    pub fn bus_reset(&self) {
+        self.model.lock().unwrap().bus_reset()
+    }
+
+    /// Signals a suspend of the bus.
+    pub fn suspend(&self) {
+        self.model.lock().unwrap().suspend()
+    }
+
+    /// Signals resume signaling from the host.
+    pub fn resume(&self) {
+        self.model.lock().unwrap().resume()
+    }
+
+    /// Sends an LPM token putting the link into L1 sleep.
+    ///
+    /// The core STALLs the token unless the driver enabled and acknowledges LPM in GLPMCFG.
+    pub fn lpm_token(&self, besl: u8, remote_wakeup: bool) -> Result<(), Handshake> {
+        self.model.lock().unwrap().lpm_token(besl, remote_wakeup)
+    }
+
+    /// Signals resume signaling from L1 sleep.
+    pub fn lpm_resume(&self) {
+        self.model.lock().unwrap().lpm_resume()
+    }
+
+    /// Starts a new frame.
+    pub fn start_of_frame(&self) {
+        self.model.lock().unwrap().start_of_frame()
+    }
+
+    /// Sends a SETUP packet to control endpoint `ep`.
+    ///
+    /// The core NAKs the following data and status stages until the driver enables the endpoint
+    /// again.
+    pub fn setup_token(&self, ep: usize, packet: &[u8; 8]) -> Result<(), Handshake> {
+        self.model.lock().unwrap().setup_token(ep, packet)
+    }
+
+    /// Sends an OUT packet to endpoint `ep`.
+    pub fn out_token(&self, ep: usize, data: &[u8]) -> Result<(), Handshake> {
+        self.model.lock().unwrap().out_token(ep, data)
+    }
+
+    /// Requests an IN packet from endpoint `ep`.
+    pub fn in_token(&self, ep: usize) -> Result<Vec<u8>, Handshake> {
+        self.model.lock().unwrap().in_token(ep)
+    }
+}
+
+/// Peripheral of a simulated core.
+///
+/// The core is a full-speed core with 6 endpoints and 1.25 KiB of FIFO memory, like the OTG_FS
+/// peripheral of STM32F446.
+pub struct SimPeripheral {
+    core: SimCore,
+}
+
+impl SimPeripheral {
+    /// Creates the peripheral of `core`, and attaches the core to the current thread.
+    pub fn new(core: &SimCore) -> SimPeripheral {
+        core.attach();
+        SimPeripheral { core: core.clone() }
+    }
+
+    /// Returns the simulated core of the peripheral.
+    pub fn core(&self) -> &SimCore {
+        &self.core
+    }
+}
+
+unsafe impl UsbPeripheral for SimPeripheral {
+    const REGISTERS: *const () = &REGISTER_SPACE as *const _ as *const ();
+
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 6;
+
+    fn enable() {}
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        48_000_000
+    }
+}
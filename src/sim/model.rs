@@ -0,0 +1,478 @@
+//! Model of the device-mode registers and FIFOs of an OTG core.
+
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use super::Handshake;
+
+const GOTGINT: usize = 0x004;
+const GAHBCFG: usize = 0x008;
+const GUSBCFG: usize = 0x00c;
+const GRSTCTL: usize = 0x010;
+const GINTSTS: usize = 0x014;
+const GINTMSK: usize = 0x018;
+const GRXSTSR: usize = 0x01c;
+const GRXSTSP: usize = 0x020;
+const GRXFSIZ: usize = 0x024;
+const DIEPTXF0: usize = 0x028;
+const CID: usize = 0x03c;
+const GLPMCFG: usize = 0x054;
+const DIEPTXF1: usize = 0x104;
+const DCFG: usize = 0x800;
+const DCTL: usize = 0x804;
+const DSTS: usize = 0x808;
+const DIEPMSK: usize = 0x810;
+const DOEPMSK: usize = 0x814;
+const DAINT: usize = 0x818;
+const DAINTMSK: usize = 0x81c;
+const DIEPEMPMSK: usize = 0x834;
+const ENDPOINT_IN: usize = 0x900;
+const ENDPOINT_OUT: usize = 0xb00;
+const ENDPOINT_END: usize = 0xd00;
+const FIFO: usize = 0x1000;
+const FIFO_END: usize = 0x11000;
+
+// Registers of an endpoint, relative to its block
+const EPCTL: usize = 0x00;
+const EPINT: usize = 0x08;
+const EPTSIZ: usize = 0x10;
+const DTXFSTS: usize = 0x18;
+
+const GUSBCFG_FDMOD: u32 = 1 << 30;
+const GRSTCTL_CSRST: u32 = 1 << 0;
+const GRSTCTL_RXFFLSH: u32 = 1 << 4;
+const GRSTCTL_TXFFLSH: u32 = 1 << 5;
+const GRSTCTL_AHBIDL: u32 = 1 << 31;
+const GINTSTS_SOF: u32 = 1 << 3;
+const GINTSTS_RXFLVL: u32 = 1 << 4;
+const GINTSTS_USBSUSP: u32 = 1 << 11;
+const GINTSTS_USBRST: u32 = 1 << 12;
+const GINTSTS_ENUMDNE: u32 = 1 << 13;
+const GINTSTS_IEPINT: u32 = 1 << 18;
+const GINTSTS_OEPINT: u32 = 1 << 19;
+const GINTSTS_LPMINT: u32 = 1 << 27;
+const GINTSTS_WKUPINT: u32 = 1 << 31;
+const GAHBCFG_GINT: u32 = 1 << 0;
+const GLPMCFG_LPMEN: u32 = 1 << 0;
+const GLPMCFG_LPMACK: u32 = 1 << 1;
+const GLPMCFG_BESL_OFFSET: u32 = 2;
+const GLPMCFG_REMWAKE: u32 = 1 << 6;
+const GLPMCFG_SLPSTS: u32 = 1 << 15;
+/// Bits of GLPMCFG updated by the core from the last LPM token
+const GLPMCFG_STATUS: u32 = (0xf << GLPMCFG_BESL_OFFSET) | GLPMCFG_REMWAKE | GLPMCFG_SLPSTS;
+const DCTL_SDIS: u32 = 1 << 1;
+const DSTS_SUSPSTS: u32 = 1 << 0;
+const DSTS_ENUMSPD_FS: u32 = 0b11 << 1;
+const EPCTL_NAKSTS: u32 = 1 << 17;
+const EPCTL_STALL: u32 = 1 << 21;
+const EPCTL_CNAK: u32 = 1 << 26;
+const EPCTL_SNAK: u32 = 1 << 27;
+const EPCTL_SD0PID: u32 = 1 << 28;
+const EPCTL_SODDFRM: u32 = 1 << 29;
+const EPCTL_EPDIS: u32 = 1 << 30;
+const EPCTL_EPENA: u32 = 1 << 31;
+const EPINT_XFRC: u32 = 1 << 0;
+const EPINT_EPDISD: u32 = 1 << 1;
+const EPINT_STUP: u32 = 1 << 3;
+const EPINT_TXFE: u32 = 1 << 7;
+
+const PKTSTS_OUT: u32 = 0x02;
+const PKTSTS_OUT_COMPLETE: u32 = 0x03;
+const PKTSTS_SETUP_COMPLETE: u32 = 0x04;
+const PKTSTS_SETUP: u32 = 0x06;
+
+const ENDPOINTS: usize = 16;
+
+/// Entry of the Rx FIFO: the status word read from GRXSTSP, followed by the packet data.
+struct RxEntry {
+    status: u32,
+    data: Vec<u32>,
+}
+
+pub(crate) struct Model {
+    registers: Vec<u32>,
+    /// Latched GINTSTS bits, the other ones reflect the state of the FIFOs and endpoints
+    interrupts: u32,
+    rx_fifo: VecDeque<RxEntry>,
+    /// Data of the entry popped from GRXSTSP, read from the FIFO
+    rx_data: VecDeque<u32>,
+    tx_fifos: Vec<VecDeque<u32>>,
+    core_id: u32,
+    suspended: bool,
+    frame_number: u16,
+}
+
+impl Model {
+    pub fn new(core_id: u32) -> Self {
+        let mut model = Model {
+            registers: std::vec![0; FIFO / 4],
+            interrupts: 0,
+            rx_fifo: VecDeque::new(),
+            rx_data: VecDeque::new(),
+            tx_fifos: (0..ENDPOINTS).map(|_| VecDeque::new()).collect(),
+            core_id,
+            suspended: false,
+            frame_number: 0,
+        };
+        model.registers[CID / 4] = core_id;
+        model
+    }
+
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            FIFO..FIFO_END => self.rx_data.pop_front().unwrap_or(0),
+            GRSTCTL => self.reg(GRSTCTL) | GRSTCTL_AHBIDL,
+            GINTSTS => self.interrupt_status(),
+            GRXSTSR => self.rx_fifo.front().map_or(0, |entry| entry.status),
+            GRXSTSP => match self.rx_fifo.pop_front() {
+                Some(entry) => {
+                    self.pop_rx_entry(&entry);
+                    self.rx_data.extend(entry.data);
+                    entry.status
+                }
+                None => 0,
+            },
+            CID => self.core_id,
+            DAINT => self.endpoint_interrupts(),
+            DSTS => {
+                let suspended = if self.suspended { DSTS_SUSPSTS } else { 0 };
+                DSTS_ENUMSPD_FS | ((self.frame_number as u32) << 8) | suspended
+            }
+            ENDPOINT_IN..ENDPOINT_END => {
+                let (ep, register) = endpoint_register(offset);
+                match (offset < ENDPOINT_OUT, register) {
+                    (true, EPINT) if self.tx_fifos[ep].is_empty() => self.reg(offset) | EPINT_TXFE,
+                    (true, DTXFSTS) => self.tx_fifo_space_words(ep),
+                    _ => self.reg(offset),
+                }
+            }
+            _ => self.reg(offset),
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            FIFO..FIFO_END => {
+                let channel = offset / FIFO - 1;
+                self.tx_fifos[channel].push_back(value);
+            }
+            GRSTCTL => {
+                if value & GRSTCTL_CSRST != 0 {
+                    self.interrupts = 0;
+                    self.flush_rx_fifo();
+                    self.tx_fifos.iter_mut().for_each(VecDeque::clear);
+                }
+                if value & GRSTCTL_RXFFLSH != 0 {
+                    self.flush_rx_fifo();
+                }
+                if value & GRSTCTL_TXFFLSH != 0 {
+                    match ((value >> 6) & 0x1f) as usize {
+                        0x10 => self.tx_fifos.iter_mut().for_each(VecDeque::clear),
+                        channel => self.tx_fifos[channel].clear(),
+                    }
+                }
+                // The reset and flush bits are cleared by the core once done
+                let value = value & !(GRSTCTL_CSRST | GRSTCTL_RXFFLSH | GRSTCTL_TXFFLSH);
+                self.set_reg(GRSTCTL, value);
+            }
+            GINTSTS => self.interrupts &= !value,
+            GOTGINT => self.set_reg(GOTGINT, self.reg(GOTGINT) & !value),
+            GLPMCFG => {
+                let status = self.reg(GLPMCFG) & GLPMCFG_STATUS;
+                self.set_reg(GLPMCFG, (value & !GLPMCFG_STATUS) | status);
+            }
+            GRXSTSR | GRXSTSP | CID | DAINT | DSTS => {}
+            ENDPOINT_IN..ENDPOINT_END => {
+                let (_, register) = endpoint_register(offset);
+                match register {
+                    EPCTL => self.write_endpoint_control(offset, value),
+                    EPINT => self.set_reg(offset, self.reg(offset) & !value),
+                    DTXFSTS if offset < ENDPOINT_OUT => {}
+                    _ => self.set_reg(offset, value),
+                }
+            }
+            _ => self.set_reg(offset, value),
+        }
+    }
+
+    pub fn register(&self, offset: usize) -> u32 {
+        self.reg(offset)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.reg(GUSBCFG) & GUSBCFG_FDMOD != 0 && self.reg(DCTL) & DCTL_SDIS == 0
+    }
+
+    pub fn device_address(&self) -> u8 {
+        ((self.reg(DCFG) >> 4) & 0x7f) as u8
+    }
+
+    pub fn is_interrupt_pending(&self) -> bool {
+        self.reg(GAHBCFG) & GAHBCFG_GINT != 0 && self.interrupt_status() & self.reg(GINTMSK) != 0
+    }
+
+    pub fn bus_reset(&mut self) {
+        self.suspended = false;
+        self.interrupts |= GINTSTS_USBRST | GINTSTS_ENUMDNE;
+    }
+
+    pub fn suspend(&mut self) {
+        if !self.suspended {
+            self.suspended = true;
+            self.interrupts |= GINTSTS_USBSUSP;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.suspended {
+            self.suspended = false;
+            self.interrupts |= GINTSTS_WKUPINT;
+        }
+    }
+
+    pub fn lpm_token(&mut self, besl: u8, remote_wakeup: bool) -> Result<(), Handshake> {
+        let config = self.reg(GLPMCFG);
+        if config & (GLPMCFG_LPMEN | GLPMCFG_LPMACK) != GLPMCFG_LPMEN | GLPMCFG_LPMACK {
+            return Err(Handshake::Stall);
+        }
+
+        let remote_wakeup = if remote_wakeup { GLPMCFG_REMWAKE } else { 0 };
+        let status = ((besl as u32 & 0xf) << GLPMCFG_BESL_OFFSET) | remote_wakeup | GLPMCFG_SLPSTS;
+        self.set_reg(GLPMCFG, (config & !GLPMCFG_STATUS) | status);
+        self.interrupts |= GINTSTS_LPMINT;
+        Ok(())
+    }
+
+    pub fn lpm_resume(&mut self) {
+        if self.reg(GLPMCFG) & GLPMCFG_SLPSTS != 0 {
+            self.set_reg(GLPMCFG, self.reg(GLPMCFG) & !GLPMCFG_SLPSTS);
+            self.interrupts |= GINTSTS_WKUPINT;
+        }
+    }
+
+    pub fn start_of_frame(&mut self) {
+        self.frame_number = (self.frame_number + 1) & 0x3fff;
+        self.interrupts |= GINTSTS_SOF;
+    }
+
+    pub fn setup_token(&mut self, ep: usize, packet: &[u8; 8]) -> Result<(), Handshake> {
+        // A real core drops the SETUP packet without room in the Rx FIFO instead of NAKing it
+        if !self.rx_fifo_fits(1 + 2 + 1) {
+            return Err(Handshake::Nak);
+        }
+        self.push_rx_entry(ep, PKTSTS_SETUP, packet);
+        self.push_rx_entry(ep, PKTSTS_SETUP_COMPLETE, &[]);
+
+        // The core NAKs the data stage until the endpoint is enabled again
+        let ctl_in = ENDPOINT_IN + 0x20 * ep;
+        let ctl_out = ENDPOINT_OUT + 0x20 * ep;
+        self.set_reg(ctl_in, (self.reg(ctl_in) & !EPCTL_STALL) | EPCTL_NAKSTS);
+        self.set_reg(
+            ctl_out,
+            (self.reg(ctl_out) & !(EPCTL_STALL | EPCTL_EPENA)) | EPCTL_NAKSTS,
+        );
+        Ok(())
+    }
+
+    pub fn out_token(&mut self, ep: usize, data: &[u8]) -> Result<(), Handshake> {
+        let ctl = ENDPOINT_OUT + 0x20 * ep;
+        self.check_endpoint(ctl)?;
+        if !self.rx_fifo_fits(1 + data.len().div_ceil(4) + 1) {
+            return Err(Handshake::Nak);
+        }
+        self.push_rx_entry(ep, PKTSTS_OUT, data);
+
+        // Multi-packet transfers end with the last packet or a short one
+        let tsiz = ctl + EPTSIZ;
+        let (size, packets) = self.transfer_size(ep, tsiz);
+        let max_packet_size = self.max_packet_size(ep, ctl);
+        if packets > 1 && data.len() == max_packet_size {
+            self.set_transfer_size(ep, tsiz, size.saturating_sub(data.len()), packets - 1);
+        } else {
+            self.set_transfer_size(ep, tsiz, size.saturating_sub(data.len()), 0);
+            self.push_rx_entry(ep, PKTSTS_OUT_COMPLETE, &[]);
+            self.set_reg(ctl, self.reg(ctl) & !EPCTL_EPENA);
+            self.set_reg(ctl + EPINT, self.reg(ctl + EPINT) | EPINT_XFRC);
+        }
+        Ok(())
+    }
+
+    pub fn in_token(&mut self, ep: usize) -> Result<Vec<u8>, Handshake> {
+        let ctl = ENDPOINT_IN + 0x20 * ep;
+        self.check_endpoint(ctl)?;
+
+        let tsiz = ctl + EPTSIZ;
+        let (size, packets) = self.transfer_size(ep, tsiz);
+        let packet_size = size.min(self.max_packet_size(ep, ctl));
+        let words = packet_size.div_ceil(4);
+        // The packet is not completely in the Tx FIFO yet
+        if self.tx_fifos[ep].len() < words {
+            return Err(Handshake::Nak);
+        }
+        let mut data: Vec<u8> = self.tx_fifos[ep]
+            .drain(..words)
+            .flat_map(u32::to_le_bytes)
+            .collect();
+        data.truncate(packet_size);
+
+        let packets = packets.saturating_sub(1);
+        self.set_transfer_size(ep, tsiz, size - packet_size, packets);
+        if packets == 0 {
+            self.set_reg(ctl, self.reg(ctl) & !EPCTL_EPENA);
+            self.set_reg(ctl + EPINT, self.reg(ctl + EPINT) | EPINT_XFRC);
+        }
+        Ok(data)
+    }
+
+    fn reg(&self, offset: usize) -> u32 {
+        self.registers[offset / 4]
+    }
+
+    fn set_reg(&mut self, offset: usize, value: u32) {
+        self.registers[offset / 4] = value;
+    }
+
+    fn interrupt_status(&self) -> u32 {
+        let mut status = self.interrupts;
+        if !self.rx_fifo.is_empty() {
+            status |= GINTSTS_RXFLVL;
+        }
+        let daint = self.endpoint_interrupts() & self.reg(DAINTMSK);
+        if daint & 0xffff != 0 {
+            status |= GINTSTS_IEPINT;
+        }
+        if daint >> 16 != 0 {
+            status |= GINTSTS_OEPINT;
+        }
+        status
+    }
+
+    fn endpoint_interrupts(&self) -> u32 {
+        let empty_mask = self.reg(DIEPEMPMSK);
+        (0..ENDPOINTS).fold(0, |daint, ep| {
+            let mut diepint = self.reg(ENDPOINT_IN + 0x20 * ep + EPINT) & self.reg(DIEPMSK);
+            if empty_mask & (1 << ep) != 0 && self.tx_fifos[ep].is_empty() {
+                diepint |= EPINT_TXFE;
+            }
+            let doepint = self.reg(ENDPOINT_OUT + 0x20 * ep + EPINT) & self.reg(DOEPMSK);
+            daint | ((diepint != 0) as u32) << ep | ((doepint != 0) as u32) << (ep + 16)
+        })
+    }
+
+    fn write_endpoint_control(&mut self, offset: usize, value: u32) {
+        let old = self.reg(offset);
+        // EPENA is only cleared by the core, NAKSTS and the data PID are read-only
+        let read_only = EPCTL_EPENA | EPCTL_NAKSTS | (1 << 16);
+        let write_only = EPCTL_CNAK | EPCTL_SNAK | EPCTL_SD0PID | EPCTL_SODDFRM | EPCTL_EPDIS;
+        let mut ctl =
+            (old & read_only) | (value & !(read_only | write_only)) | (value & EPCTL_EPENA);
+        if value & EPCTL_SNAK != 0 {
+            ctl |= EPCTL_NAKSTS;
+        }
+        if value & EPCTL_CNAK != 0 {
+            ctl &= !EPCTL_NAKSTS;
+        }
+        if value & EPCTL_EPDIS != 0 && ctl & EPCTL_EPENA != 0 {
+            ctl &= !EPCTL_EPENA;
+            self.set_reg(offset + EPINT, self.reg(offset + EPINT) | EPINT_EPDISD);
+        }
+        self.set_reg(offset, ctl);
+    }
+
+    fn check_endpoint(&self, ctl: usize) -> Result<(), Handshake> {
+        let ctl = self.reg(ctl);
+        if ctl & EPCTL_STALL != 0 {
+            Err(Handshake::Stall)
+        } else if ctl & EPCTL_EPENA == 0 || ctl & EPCTL_NAKSTS != 0 {
+            Err(Handshake::Nak)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn max_packet_size(&self, ep: usize, ctl: usize) -> usize {
+        let mpsiz = self.reg(ctl) & 0x7ff;
+        if ep == 0 {
+            64 >> (mpsiz & 0b11)
+        } else {
+            // Additional transactions of high-bandwidth endpoints are not simulated
+            mpsiz as usize
+        }
+    }
+
+    /// Transfer size and packet count fields of DIEPTSIZx/DOEPTSIZx, which are narrower for
+    /// EP0.
+    fn transfer_size_masks(ep: usize, tsiz: usize) -> (u32, u32) {
+        match (ep, tsiz >= ENDPOINT_OUT) {
+            (0, false) => (0x7f, 0x3),
+            (0, true) => (0x7f, 0x1),
+            _ => (0x7ffff, 0x3ff),
+        }
+    }
+
+    fn transfer_size(&self, ep: usize, tsiz: usize) -> (usize, usize) {
+        let (size_mask, packets_mask) = Self::transfer_size_masks(ep, tsiz);
+        let value = self.reg(tsiz);
+        (
+            (value & size_mask) as usize,
+            ((value >> 19) & packets_mask) as usize,
+        )
+    }
+
+    fn set_transfer_size(&mut self, ep: usize, tsiz: usize, size: usize, packets: usize) {
+        let (size_mask, packets_mask) = Self::transfer_size_masks(ep, tsiz);
+        let value = self.reg(tsiz) & !(size_mask | (packets_mask << 19));
+        self.set_reg(tsiz, value | size as u32 | (packets as u32) << 19);
+    }
+
+    fn tx_fifo_space_words(&self, ep: usize) -> u32 {
+        let txf = if ep == 0 {
+            DIEPTXF0
+        } else {
+            DIEPTXF1 + 4 * (ep - 1)
+        };
+        let depth = self.reg(txf) >> 16;
+        depth.saturating_sub(self.tx_fifos[ep].len() as u32)
+    }
+
+    fn rx_fifo_fits(&self, words: usize) -> bool {
+        let used = self
+            .rx_fifo
+            .iter()
+            .map(|entry| 1 + entry.data.len())
+            .sum::<usize>()
+            + self.rx_data.len();
+        used + words <= (self.reg(GRXFSIZ) & 0xffff) as usize
+    }
+
+    fn push_rx_entry(&mut self, ep: usize, status: u32, data: &[u8]) {
+        let status = ep as u32 | (data.len() as u32) << 4 | status << 17;
+        let data = data
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            })
+            .collect();
+        self.rx_fifo.push_back(RxEntry { status, data });
+    }
+
+    fn pop_rx_entry(&mut self, entry: &RxEntry) {
+        // SETUP stage done interrupt
+        if (entry.status >> 17) & 0xf == PKTSTS_SETUP_COMPLETE {
+            let doepint = ENDPOINT_OUT + 0x20 * (entry.status & 0xf) as usize + EPINT;
+            self.set_reg(doepint, self.reg(doepint) | EPINT_STUP);
+        }
+    }
+
+    fn flush_rx_fifo(&mut self) {
+        self.rx_fifo.clear();
+        self.rx_data.clear();
+    }
+}
+
+fn endpoint_register(offset: usize) -> (usize, usize) {
+    let offset = (offset - ENDPOINT_IN) % (ENDPOINT_OUT - ENDPOINT_IN);
+    (offset / 0x20, offset % 0x20)
+}
@@ -0,0 +1,60 @@
+//! Register types of the simulated core.
+//!
+//! These replace the `ral-registers` types in the register blocks when the `sim` feature is
+//! enabled: they have the same layout and methods, but every access is handed to the core model
+//! attached to the current thread instead of touching memory.
+
+/// Read-write register
+#[repr(transparent)]
+pub struct RWRegister<T> {
+    _storage: T,
+}
+
+impl RWRegister<u32> {
+    #[inline(always)]
+    pub fn read(&self) -> u32 {
+        super::read(self as *const _ as usize)
+    }
+
+    #[inline(always)]
+    pub fn write(&self, val: u32) {
+        super::write(self as *const _ as usize, val)
+    }
+}
+
+/// Read-write register with unsafe accessors
+#[repr(transparent)]
+pub struct UnsafeRWRegister<T> {
+    _storage: T,
+}
+
+impl UnsafeRWRegister<u32> {
+    /// # Safety
+    ///
+    /// Reading the register may have side effects on the core.
+    #[inline(always)]
+    pub unsafe fn read(&self) -> u32 {
+        super::read(self as *const _ as usize)
+    }
+
+    /// # Safety
+    ///
+    /// Writing the register may have side effects on the core.
+    #[inline(always)]
+    pub unsafe fn write(&self, val: u32) {
+        super::write(self as *const _ as usize, val)
+    }
+}
+
+/// Read-only register
+#[repr(transparent)]
+pub struct RORegister<T> {
+    _storage: T,
+}
+
+impl RORegister<u32> {
+    #[inline(always)]
+    pub fn read(&self) -> u32 {
+        super::read(self as *const _ as usize)
+    }
+}
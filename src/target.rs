@@ -2,12 +2,15 @@
 
 use vcell::VolatileCell;
 
+#[cfg(feature = "lpm")]
+use crate::ral::otg_global_lpm;
 use crate::ral::register::RWRegister;
 use crate::ral::{
-    endpoint0_out, endpoint_in, endpoint_out, otg_device, otg_global, otg_global_dieptxfx,
-    otg_pwrclk,
+    endpoint0_out, endpoint_in, endpoint_out, host_channel, otg_device, otg_global,
+    otg_global_dieptxfx, otg_host, otg_pwrclk,
 };
-use crate::UsbPeripheral;
+use crate::ral::{modify_reg, read_reg};
+use crate::{PhyType, UsbPeripheral};
 
 pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8]) {
     let fifo = usb.fifo(channel.into());
@@ -25,6 +28,20 @@ pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8])
     }
 }
 
+pub fn fifo_read(usb: UsbRegisters, buf: &mut [u8]) {
+    let fifo = usb.fifo(0);
+
+    let mut chunks = buf.chunks_exact_mut(4);
+    for chunk in &mut chunks {
+        chunk.copy_from_slice(&fifo.read().to_ne_bytes());
+    }
+    let rest = chunks.into_remainder();
+    if !rest.is_empty() {
+        let u32_bytes = fifo.read().to_ne_bytes();
+        rest.copy_from_slice(&u32_bytes[..rest.len()]);
+    }
+}
+
 pub fn fifo_read_into(usb: UsbRegisters, buf: &[VolatileCell<u32>]) {
     let fifo = usb.fifo(0);
 
@@ -34,6 +51,83 @@ pub fn fifo_read_into(usb: UsbRegisters, buf: &[VolatileCell<u32>]) {
     }
 }
 
+pub fn fifo_discard(usb: UsbRegisters, words: usize) {
+    let fifo = usb.fifo(0);
+
+    for _ in 0..words {
+        fifo.read();
+    }
+}
+
+/// Configures the PHY selected by the peripheral and performs a core soft-reset.
+///
+/// This is shared by all the operating modes of the core and must be called after the mode
+/// (device or host) was selected in `GUSBCFG`.
+pub fn init_phy<USB: UsbPeripheral>(peripheral: &USB, regs: UsbRegisters) {
+    #[cfg(feature = "hs")]
+    match peripheral.phy_type() {
+        PhyType::InternalFullSpeed => {
+            // Select FS Embedded PHY
+            modify_reg!(otg_global, regs.global(), GUSBCFG, PHYSEL: 1);
+        }
+        PhyType::InternalHighSpeed => {
+            // Turn off PHY
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+            // Init The UTMI Interface
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TSDPS: 0,
+                ULPIFSLS: 0,
+                PHYSEL: 0 // ULPI or UTMI
+            );
+
+            // Select VBUS source
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                ULPIEVBUSD: 0,
+                ULPIEVBUSI: 0
+            );
+
+            // Select UTMI Interace
+            //modify_reg!(otg_global, regs.global(), GUSBCFG, ULPISEL: 0);
+            modify_reg!(otg_global, regs.global(), GUSBCFG, |r| r & !(1 << 4));
+
+            // This is a secret bit from ST that is not mentioned anywhere except
+            // the driver code shipped with STM32CubeIDE.
+            //modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
+            modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 23));
+
+            peripheral.setup_internal_hs_phy();
+        }
+        PhyType::ExternalHighSpeed => {
+            // Turn off embedded PHY
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+            // Init The ULPI Interface
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TSDPS: 0,
+                ULPIFSLS: 0,
+                PHYSEL: 0 // ULPI or UTMI
+            );
+
+            // Select VBUS source
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                ULPIEVBUSD: 0,
+                ULPIEVBUSI: 0
+            );
+        }
+    }
+
+    // Perform core soft-reset
+    while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+    modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
+    while read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 1 {}
+
+    if peripheral.phy_type() == PhyType::InternalFullSpeed {
+        // Activate the USB Transceiver
+        modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+    }
+}
+
 /// Wrapper around device-specific peripheral that provides unified register interface
 #[derive(Copy, Clone)]
 pub struct UsbRegisters(usize);
@@ -54,6 +148,11 @@ impl UsbRegisters {
         unsafe { &*((self.0 + 0x800) as *const _) }
     }
 
+    #[inline(always)]
+    pub fn host(&self) -> &'static otg_host::RegisterBlock {
+        unsafe { &*((self.0 + 0x400) as *const _) }
+    }
+
     #[inline(always)]
     pub fn pwrclk(&self) -> &'static otg_pwrclk::RegisterBlock {
         unsafe { &*((self.0 + 0xe00) as *const _) }
@@ -66,6 +165,12 @@ impl UsbRegisters {
         unsafe { &*(address as *const RWRegister<u32>) }
     }
 
+    #[cfg(feature = "lpm")]
+    #[inline(always)]
+    pub fn global_lpm(&self) -> &'static otg_global_lpm::RegisterBlock {
+        unsafe { &*(self.0 as *const _) }
+    }
+
     #[inline(always)]
     pub fn dieptxfx(&self, index: usize) -> &'static otg_global_dieptxfx::RegisterBlock {
         let address = self.0 + 0x100 + 4 * index;
@@ -89,4 +194,10 @@ impl UsbRegisters {
         let address = self.0 + 0xb00 + 0x20 * index;
         unsafe { &*(address as *const _) }
     }
+
+    #[inline(always)]
+    pub fn host_channel(&self, index: usize) -> &'static host_channel::RegisterBlock {
+        let address = self.0 + 0x500 + 0x20 * index;
+        unsafe { &*(address as *const _) }
+    }
 }
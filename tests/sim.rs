@@ -0,0 +1,543 @@
+//! Tests of the device driver against the simulated core.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use embedded_hal::blocking::delay::DelayMs;
+use synopsys_usb_otg::bus::FifoConfig;
+use synopsys_usb_otg::sim::{Handshake, SimCore, SimPeripheral};
+use synopsys_usb_otg::UsbBus;
+use usb_device::bus::{PollResult, UsbBus as _, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType, In, Out};
+use usb_device::prelude::*;
+
+type Bus = UsbBus<SimPeripheral>;
+
+const CORE_IDS: [u32; 2] = [SimCore::F429, SimCore::F446];
+
+const EP0_OUT: u8 = 0x00;
+const EP0_IN: u8 = 0x80;
+
+const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+const SET_ADDRESS: [u8; 8] = [0x00, 0x05, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+const DCTL: usize = 0x804;
+const DCTL_RWUSIG: u32 = 1 << 0;
+const PCGCCTL: usize = 0xe00;
+const DOEPCTL0: usize = 0xb00;
+const DOEPCTL1: usize = 0xb20;
+const EPENA: u32 = 1 << 31;
+
+fn allocator(core: &SimCore, fifo_config: FifoConfig) -> UsbBusAllocator<Bus> {
+    let ep_memory = Box::leak(vec![0; 1024].into_boxed_slice());
+    UsbBus::new_with_fifo_config(SimPeripheral::new(core), ep_memory, fifo_config)
+}
+
+fn device(alloc: &UsbBusAllocator<Bus>) -> UsbDevice<'_, Bus> {
+    UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x27dd))
+        .max_packet_size_0(64)
+        .unwrap()
+        .build()
+}
+
+fn bus_reset(core: &SimCore, bus: &Bus) {
+    core.bus_reset();
+    assert!(matches!(bus.poll(), PollResult::Reset));
+    bus.reset();
+}
+
+/// Polls the bus until the received packets are out of the Rx FIFO, returning the endpoint
+/// events.
+///
+/// Packets waiting in the endpoint buffers are reported by every poll.
+fn poll_data(bus: &Bus) -> (u16, u16, u16) {
+    let (mut out, mut in_complete, mut setup) = (0, 0, 0);
+    for _ in 0..4 {
+        match bus.poll() {
+            PollResult::None => break,
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            } => {
+                out |= ep_out;
+                in_complete |= ep_in_complete;
+                setup |= ep_setup;
+            }
+            _ => panic!("unexpected bus event"),
+        }
+    }
+    (out, in_complete, setup)
+}
+
+struct NoDelay;
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+/// Counts the wakeups of the task polling a future.
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl CountingWaker {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn read(bus: &Bus, address: u8) -> Vec<u8> {
+    let mut buf = [0; 64];
+    let size = bus.read(EndpointAddress::from(address), &mut buf).unwrap();
+    buf[..size].to_vec()
+}
+
+#[test]
+fn reset_enables_control_endpoint() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new());
+        let device = device(&alloc);
+        let bus = device.bus();
+        assert!(core.is_connected());
+
+        bus_reset(&core, bus);
+        assert_ne!(core.register(DOEPCTL0) & EPENA, 0);
+        assert!(matches!(bus.poll(), PollResult::None));
+    }
+}
+
+#[test]
+fn control_read_and_set_address() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new());
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        // Data stage
+        core.setup_token(0, &GET_DEVICE_DESCRIPTOR).unwrap();
+        assert_eq!(core.in_token(0), Err(Handshake::Nak));
+        assert_eq!(poll_data(bus), (0, 0, 1));
+        assert_eq!(read(bus, EP0_OUT), GET_DEVICE_DESCRIPTOR);
+
+        let descriptor: Vec<u8> = (0..18).collect();
+        bus.write(EndpointAddress::from(EP0_IN), &descriptor)
+            .unwrap();
+        assert_eq!(core.in_token(0).unwrap(), descriptor);
+        assert_eq!(poll_data(bus), (0, 1, 0));
+
+        // Status stage, the endpoint was enabled again after the SETUP packet
+        core.out_token(0, &[]).unwrap();
+        assert_eq!(poll_data(bus), (1, 0, 0));
+        assert_eq!(read(bus, EP0_OUT), []);
+
+        core.setup_token(0, &SET_ADDRESS).unwrap();
+        assert_eq!(poll_data(bus), (0, 0, 1));
+        assert_eq!(read(bus, EP0_OUT), SET_ADDRESS);
+        bus.write(EndpointAddress::from(EP0_IN), &[]).unwrap();
+        assert_eq!(core.in_token(0).unwrap(), []);
+        assert_eq!(poll_data(bus), (0, 1, 0));
+        bus.set_device_address(0x2a);
+        assert_eq!(core.device_address(), 0x2a);
+    }
+}
+
+#[test]
+fn bulk_out_naks_while_buffer_is_full() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new());
+        let ep_out = alloc.bulk::<Out>(64);
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        core.out_token(1, &[1; 64]).unwrap();
+        assert_eq!(poll_data(bus), (0b10, 0, 0));
+        assert_eq!(core.out_token(1, &[2; 10]), Err(Handshake::Nak));
+
+        let mut buf = [0; 64];
+        assert_eq!(ep_out.read(&mut buf), Ok(64));
+        assert_eq!(buf, [1; 64]);
+        assert_eq!(ep_out.read(&mut buf), Err(UsbError::WouldBlock));
+
+        core.out_token(1, &[2; 10]).unwrap();
+        assert_eq!(poll_data(bus), (0b10, 0, 0));
+        assert_eq!(ep_out.read(&mut buf), Ok(10));
+        assert_eq!(buf[..10], [2; 10]);
+    }
+}
+
+#[test]
+fn bulk_out_packets_fill_slots() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new().out_packet_slots(1, 3));
+        let ep_out = alloc.bulk::<Out>(64);
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        for i in 0..3 {
+            core.out_token(1, &[i; 8]).unwrap();
+            assert_eq!(poll_data(bus), (0b10, 0, 0));
+        }
+        assert_eq!(core.out_token(1, &[3; 8]), Err(Handshake::Nak));
+
+        let mut buf = [0; 64];
+        for i in 0..3 {
+            assert_eq!(ep_out.read(&mut buf), Ok(8));
+            assert_eq!(buf[..8], [i; 8]);
+        }
+        core.out_token(1, &[3; 8]).unwrap();
+    }
+}
+
+#[test]
+fn bulk_out_transfer_into_buffer() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new());
+        let ep_out = alloc.bulk::<Out>(64);
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        // The endpoint is armed for a single packet until the transfer is started
+        let address = ep_out.address();
+        assert_ne!(core.register(DOEPCTL1) & EPENA, 0);
+        let buf = Box::leak(vec![0; 200].into_boxed_slice());
+        bus.read_transfer(address, buf).unwrap();
+
+        // The packets go straight to the buffer, only the end of the transfer is reported
+        for i in 0..3 {
+            core.out_token(1, &[i; 64]).unwrap();
+            assert!(matches!(bus.poll(), PollResult::None));
+        }
+        assert_eq!(poll_data(bus), (0b10, 0, 0));
+        let (buf, size) = bus.take_transfer(address).unwrap();
+        assert_eq!(size, 192);
+        assert_eq!(buf[..192], [[0; 64], [1; 64], [2; 64]].concat());
+    }
+}
+
+#[test]
+fn bulk_in_packets_are_queued() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new().tx_fifo_packets(1, 2));
+        let ep_in = alloc.bulk::<In>(64);
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        assert_eq!(core.in_token(1), Err(Handshake::Nak));
+        assert_eq!(ep_in.write(&[1; 64]), Ok(64));
+        assert_eq!(ep_in.write(&[2; 64]), Ok(64));
+        assert_eq!(ep_in.write(&[3; 5]), Err(UsbError::WouldBlock));
+
+        assert_eq!(core.in_token(1).unwrap(), [1; 64]);
+        assert_eq!(poll_data(bus), (0, 0b10, 0));
+        assert_eq!(ep_in.write(&[3; 5]), Ok(5));
+        assert_eq!(core.in_token(1).unwrap(), [2; 64]);
+        assert_eq!(poll_data(bus), (0, 0b10, 0));
+        assert_eq!(core.in_token(1).unwrap(), [3; 5]);
+        assert_eq!(poll_data(bus), (0, 0b10, 0));
+        assert_eq!(core.in_token(1), Err(Handshake::Nak));
+    }
+}
+
+#[test]
+fn packet_futures_are_woken() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new());
+        let ep_out = alloc.bulk::<Out>(64);
+        let ep_in = alloc.bulk::<In>(64);
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Woken by the reception of a packet
+        let mut buf = [0; 64];
+        {
+            let mut read = pin!(bus.read_packet(ep_out.address(), &mut buf));
+            assert!(read.as_mut().poll(&mut cx).is_pending());
+            core.out_token(1, &[1; 8]).unwrap();
+            assert_eq!(poll_data(bus), (0b10, 0, 0));
+            assert_eq!(wakes.count(), 1);
+            assert_eq!(read.as_mut().poll(&mut cx), Poll::Ready(Ok(8)));
+        }
+        assert_eq!(buf[..8], [1; 8]);
+
+        // Woken by the completion of the previous packet
+        assert_eq!(ep_in.write(&[2; 64]), Ok(64));
+        let mut write = pin!(bus.write_packet(ep_in.address(), &[3; 8]));
+        assert!(write.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(core.in_token(1).unwrap(), [2; 64]);
+        assert_eq!(poll_data(bus), (0, 0b10, 0));
+        assert_eq!(wakes.count(), 2);
+        assert_eq!(write.as_mut().poll(&mut cx), Poll::Ready(Ok(8)));
+        assert_eq!(core.in_token(1).unwrap(), [3; 8]);
+    }
+}
+
+#[test]
+fn fifo_config_is_checked_on_allocation() {
+    let core = SimCore::new(SimCore::F446);
+
+    // An explicit Tx FIFO depth only has to hold the packet
+    let alloc = allocator(&core, FifoConfig::new().tx_fifo_size_words(1, 2));
+    assert!(alloc
+        .alloc::<In>(None, EndpointType::Interrupt, 8, 1)
+        .is_ok());
+
+    let alloc = allocator(&core, FifoConfig::new().rx_fifo_size_words(16));
+    assert_eq!(
+        alloc.alloc::<Out>(None, EndpointType::Bulk, 64, 0).err(),
+        Some(UsbError::BufferOverflow)
+    );
+
+    // The Rx FIFO grows with the OUT endpoints
+    let alloc = allocator(&core, FifoConfig::new().reserved_words(300));
+    assert_eq!(
+        alloc.alloc::<Out>(None, EndpointType::Bulk, 64, 0).err(),
+        Some(UsbError::EndpointMemoryOverflow)
+    );
+}
+
+#[test]
+fn reset_drops_pending_packets() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let _ep_out = alloc.bulk::<Out>(64);
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    core.out_token(1, &[1; 64]).unwrap();
+    bus_reset(&core, bus);
+    assert_eq!(poll_data(bus), (0, 0, 0));
+}
+
+#[test]
+fn stall_is_cleared_by_setup() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    bus.set_stalled(EndpointAddress::from(EP0_IN), true);
+    assert_eq!(core.in_token(0), Err(Handshake::Stall));
+    core.setup_token(0, &GET_DEVICE_DESCRIPTOR).unwrap();
+    assert!(!bus.is_stalled(EndpointAddress::from(EP0_IN)));
+}
+
+#[test]
+fn suspend_and_resume() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    core.suspend();
+    assert!(matches!(bus.poll(), PollResult::Suspend));
+    bus.suspend();
+    core.resume();
+    assert!(matches!(bus.poll(), PollResult::Resume));
+    bus.resume();
+    assert!(matches!(bus.poll(), PollResult::None));
+}
+
+#[cfg(feature = "lpm")]
+#[test]
+fn lpm_sleep_and_resume() {
+    use synopsys_usb_otg::bus::LpmEvent;
+
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    core.lpm_token(4, true).unwrap();
+    assert!(matches!(bus.poll(), PollResult::None));
+    let sleep = LpmEvent::Sleep {
+        besl: 4,
+        remote_wakeup: true,
+    };
+    assert_eq!(bus.poll_lpm(), Some(sleep));
+
+    // The resume of the reported sleep is kept until the next sleep is polled
+    core.lpm_resume();
+    assert!(matches!(bus.poll(), PollResult::Resume));
+    core.lpm_token(4, true).unwrap();
+    assert!(matches!(bus.poll(), PollResult::None));
+    assert_eq!(bus.poll_lpm(), Some(LpmEvent::Resume));
+    assert_eq!(bus.poll_lpm(), Some(sleep));
+    assert_eq!(bus.poll_lpm(), None);
+}
+
+#[cfg(feature = "lpm")]
+#[test]
+fn lpm_resume_drops_unreported_sleep() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    core.lpm_token(2, false).unwrap();
+    assert!(matches!(bus.poll(), PollResult::None));
+    core.lpm_resume();
+    assert!(matches!(bus.poll(), PollResult::Resume));
+    assert_eq!(bus.poll_lpm(), None);
+}
+
+#[cfg(feature = "lpm")]
+#[test]
+fn lpm_is_only_acknowledged_by_newer_cores() {
+    let core = SimCore::new(SimCore::F429);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    bus_reset(&core, device.bus());
+
+    assert_eq!(core.lpm_token(4, false), Err(Handshake::Stall));
+}
+
+#[test]
+fn start_of_frame_events() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    // Frames are counted, but not reported while the interrupt is disabled
+    core.start_of_frame();
+    assert_eq!(bus.frame_number(), 1);
+    assert_eq!(bus.poll_sof(), None);
+
+    bus.set_sof_interrupt(true);
+    assert_eq!(bus.poll_sof(), None);
+    core.start_of_frame();
+    assert!(core.is_interrupt_pending());
+    assert_eq!(bus.poll_sof(), Some(2));
+    assert_eq!(bus.poll_sof(), None);
+    assert!(!core.is_interrupt_pending());
+
+    // Latched by the interrupt handler
+    core.start_of_frame();
+    bus.on_interrupt();
+    assert!(!core.is_interrupt_pending());
+    assert_eq!(bus.poll_sof(), Some(3));
+    assert_eq!(bus.poll_sof(), None);
+
+    bus.set_sof_interrupt(false);
+    core.start_of_frame();
+    bus.on_interrupt();
+    assert_eq!(bus.poll_sof(), None);
+    assert_eq!(bus.frame_number(), 4);
+}
+
+#[test]
+fn interrupt_handler_latches_data() {
+    for core_id in CORE_IDS {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core, FifoConfig::new());
+        let ep_out = alloc.bulk::<Out>(64);
+        let ep_in = alloc.bulk::<In>(64);
+        let device = device(&alloc);
+        let bus = device.bus();
+        bus_reset(&core, bus);
+
+        core.out_token(1, &[1; 8]).unwrap();
+        ep_in.write(&[2; 8]).unwrap();
+        assert_eq!(core.in_token(1).unwrap(), [2; 8]);
+        // The handler runs again as long as an interrupt is pending, like on the real core
+        for _ in 0..4 {
+            if !core.is_interrupt_pending() {
+                break;
+            }
+            bus.on_interrupt();
+        }
+        assert!(!core.is_interrupt_pending());
+
+        // The events are kept until they are polled, the packet as long as it is buffered
+        assert!(matches!(
+            bus.poll(),
+            PollResult::Data {
+                ep_out: 0b10,
+                ep_in_complete: 0b10,
+                ep_setup: 0,
+            }
+        ));
+        assert_eq!(poll_data(bus), (0b10, 0, 0));
+
+        let mut buf = [0; 64];
+        assert_eq!(ep_out.read(&mut buf), Ok(8));
+        assert_eq!(buf[..8], [1; 8]);
+        assert!(matches!(bus.poll(), PollResult::None));
+    }
+}
+
+#[test]
+fn remote_wakeup_requires_suspend() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+
+    assert_eq!(bus.start_remote_wakeup(), Err(UsbError::InvalidState));
+    assert_eq!(bus.remote_wakeup(&mut NoDelay), Err(UsbError::InvalidState));
+    assert_eq!(core.register(DCTL) & DCTL_RWUSIG, 0);
+
+    core.suspend();
+    assert!(matches!(bus.poll(), PollResult::Suspend));
+    bus.suspend();
+    bus.start_remote_wakeup().unwrap();
+    assert_ne!(core.register(DCTL) & DCTL_RWUSIG, 0);
+    bus.stop_remote_wakeup();
+    assert_eq!(core.register(DCTL) & DCTL_RWUSIG, 0);
+
+    bus.remote_wakeup(&mut NoDelay).unwrap();
+    assert_eq!(core.register(DCTL) & DCTL_RWUSIG, 0);
+}
+
+#[test]
+fn remote_wakeup_restarts_clocks() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core, FifoConfig::new());
+    let device = device(&alloc);
+    let bus = device.bus();
+    bus_reset(&core, bus);
+    bus.set_low_power_suspend(true);
+
+    core.suspend();
+    assert!(matches!(bus.poll(), PollResult::Suspend));
+    bus.suspend();
+    assert_eq!(core.register(PCGCCTL) & 0b11, 0b11);
+
+    bus.start_remote_wakeup().unwrap();
+    assert_eq!(core.register(PCGCCTL) & 0b11, 0);
+    assert_ne!(core.register(DCTL) & DCTL_RWUSIG, 0);
+    bus.stop_remote_wakeup();
+}
@@ -0,0 +1,266 @@
+//! End-to-end tests of the `usb-device` test class against the simulated core.
+
+use embedded_hal::blocking::delay::DelayMs;
+use synopsys_usb_otg::bus::TestMode;
+use synopsys_usb_otg::sim::{SimCore, SimHost, SimPeripheral, TransferError};
+use synopsys_usb_otg::UsbBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::{ControlOut, UsbClass};
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::device::UsbDeviceState;
+use usb_device::test_class::{self, TestClass};
+
+type Bus = UsbBus<SimPeripheral>;
+
+const ADDRESS: u8 = 5;
+
+const EP_BULK: usize = 1;
+const EP_INTERRUPT: usize = 2;
+
+const DCTL: usize = 0x804;
+const DCTL_TCTL: u32 = 0b111 << 4;
+const TEST_MODE: u16 = 2;
+
+struct NoDelay;
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+/// Accepts SET_FEATURE(TEST_MODE), the application then hands the test mode to the driver.
+#[derive(Default)]
+struct TestModeClass {
+    requested: Option<TestMode>,
+}
+
+impl UsbClass<Bus> for TestModeClass {
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::SET_FEATURE
+            && req.value == TEST_MODE
+        {
+            if let Some(mode) = TestMode::from_selector((req.index >> 8) as u8) {
+                self.requested = Some(mode);
+                xfer.accept().unwrap();
+            }
+        }
+    }
+}
+
+fn allocator(core: &SimCore) -> UsbBusAllocator<Bus> {
+    let ep_memory = Box::leak(vec![0; 1024].into_boxed_slice());
+    UsbBus::new(SimPeripheral::new(core), ep_memory)
+}
+
+fn vendor_in(request: u8, length: u16) -> [u8; 8] {
+    SimHost::setup_packet(0xc0, request, 0, 0, length)
+}
+
+fn vendor_out(request: u8, length: u16) -> [u8; 8] {
+    SimHost::setup_packet(0x40, request, 0, 0, length)
+}
+
+fn utf16_string(descriptor: &[u8]) -> String {
+    let units: Vec<u16> = descriptor[2..]
+        .chunks(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16(&units).unwrap()
+}
+
+#[test]
+fn enumeration() {
+    for core_id in [SimCore::F429, SimCore::F446] {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core);
+        let mut test_class = TestClass::new(&alloc);
+        let mut usb_dev = test_class.make_device(&alloc);
+        let mut device = || {
+            usb_dev.poll(&mut [&mut test_class]);
+            test_class.poll();
+        };
+
+        let mut host = SimHost::new(&core);
+        let enumeration = host.enumerate(&mut device, ADDRESS).unwrap();
+        let descriptor = enumeration.device_descriptor;
+        assert_eq!(descriptor[7], 8);
+        assert_eq!(descriptor[8..10], test_class::VID.to_le_bytes());
+        assert_eq!(descriptor[10..12], test_class::PID.to_le_bytes());
+        assert_eq!(enumeration.configuration_descriptor[1], 0x02);
+
+        let product = host.get_descriptor(&mut device, 0x03, descriptor[15], 0x0409, 255);
+        assert_eq!(utf16_string(&product.unwrap()), test_class::PRODUCT);
+
+        assert_eq!(core.device_address(), ADDRESS);
+        assert_eq!(usb_dev.state(), UsbDeviceState::Configured);
+    }
+}
+
+#[test]
+fn vendor_control_requests() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core);
+    let mut test_class = TestClass::new(&alloc);
+    let mut usb_dev = test_class.make_device(&alloc);
+    let mut device = || {
+        usb_dev.poll(&mut [&mut test_class]);
+        test_class.poll();
+    };
+
+    let mut host = SimHost::new(&core);
+    host.enumerate(&mut device, ADDRESS).unwrap();
+
+    let data: Vec<u8> = (0..20).collect();
+    let setup = vendor_out(test_class::REQ_WRITE_BUFFER, data.len() as u16);
+    host.control_out(&mut device, &setup, &data).unwrap();
+    let setup = vendor_in(test_class::REQ_READ_BUFFER, data.len() as u16);
+    assert_eq!(host.control_in(&mut device, &setup).unwrap(), data);
+
+    let setup = vendor_in(test_class::REQ_READ_LONG_DATA, 1000);
+    assert_eq!(
+        host.control_in(&mut device, &setup).unwrap(),
+        test_class::LONG_DATA
+    );
+
+    // The next SETUP packet clears the halt of EP0
+    let setup = vendor_in(test_class::REQ_UNKNOWN, 8);
+    assert_eq!(
+        host.control_in(&mut device, &setup),
+        Err(TransferError::Stall)
+    );
+    let setup = vendor_in(test_class::REQ_READ_BUFFER, 4);
+    assert_eq!(host.control_in(&mut device, &setup).unwrap(), data[..4]);
+}
+
+#[test]
+fn bulk_loopback() {
+    for core_id in [SimCore::F429, SimCore::F446] {
+        let core = SimCore::new(core_id);
+        let alloc = allocator(&core);
+        let mut test_class = TestClass::new(&alloc);
+        let mut usb_dev = test_class.make_device(&alloc);
+        let mut device = || {
+            usb_dev.poll(&mut [&mut test_class]);
+            test_class.poll();
+        };
+
+        let mut host = SimHost::new(&core);
+        host.enumerate(&mut device, ADDRESS).unwrap();
+
+        for length in [0, 1, 63, 64, 65, 200] {
+            let data: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            host.out_transfer(&mut device, EP_BULK, &data).unwrap();
+            let echo = host.in_transfer(&mut device, EP_BULK, length).unwrap();
+            assert_eq!(echo, data);
+        }
+    }
+}
+
+#[test]
+fn interrupt_loopback() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core);
+    let mut test_class = TestClass::new(&alloc);
+    let mut usb_dev = test_class.make_device(&alloc);
+    let mut device = || {
+        usb_dev.poll(&mut [&mut test_class]);
+        test_class.poll();
+    };
+
+    let mut host = SimHost::new(&core);
+    host.enumerate(&mut device, ADDRESS).unwrap();
+
+    host.out_packet(&mut device, EP_INTERRUPT, &[1, 2, 3])
+        .unwrap();
+    assert_eq!(
+        host.in_packet(&mut device, EP_INTERRUPT).unwrap(),
+        [1, 2, 3]
+    );
+
+    // Nothing else was written by the device
+    host.set_nak_limit(10);
+    assert_eq!(
+        host.in_packet(&mut device, EP_INTERRUPT),
+        Err(TransferError::Timeout)
+    );
+}
+
+#[test]
+fn reset_and_enumerate_again() {
+    let core = SimCore::new(SimCore::F429);
+    let alloc = allocator(&core);
+    let mut test_class = TestClass::new(&alloc);
+    let mut usb_dev = test_class.make_device(&alloc);
+    let mut device = || {
+        usb_dev.poll(&mut [&mut test_class]);
+        test_class.poll();
+    };
+
+    let mut host = SimHost::new(&core);
+    host.enumerate(&mut device, ADDRESS).unwrap();
+    host.out_transfer(&mut device, EP_BULK, &[1; 10]).unwrap();
+
+    host.enumerate(&mut device, ADDRESS + 1).unwrap();
+    assert_eq!(core.device_address(), ADDRESS + 1);
+    host.out_transfer(&mut device, EP_BULK, &[2; 10]).unwrap();
+    assert_eq!(host.in_transfer(&mut device, EP_BULK, 64).unwrap(), [2; 10]);
+}
+
+#[test]
+fn remote_wakeup_enabled_by_host() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core);
+    let mut test_class = TestClass::new(&alloc);
+    let mut usb_dev = test_class
+        .make_device_builder(&alloc)
+        .supports_remote_wakeup(true)
+        .build();
+    let mut device = || {
+        usb_dev.poll(&mut [&mut test_class]);
+        test_class.poll();
+    };
+
+    let mut host = SimHost::new(&core);
+    host.enumerate(&mut device, ADDRESS).unwrap();
+    // SET_FEATURE(DEVICE_REMOTE_WAKEUP)
+    let set_feature = SimHost::setup_packet(0x00, 0x03, 1, 0, 0);
+    host.control_out(&mut device, &set_feature, &[]).unwrap();
+
+    // The driver leaves checking the feature to the caller
+    assert!(usb_dev.remote_wakeup_enabled());
+    core.suspend();
+    usb_dev.poll(&mut [&mut test_class]);
+    assert_eq!(usb_dev.state(), UsbDeviceState::Suspend);
+    usb_dev.bus().remote_wakeup(&mut NoDelay).unwrap();
+}
+
+#[test]
+fn test_mode_after_status_stage() {
+    let core = SimCore::new(SimCore::F446);
+    let alloc = allocator(&core);
+    let mut test_class = TestClass::new(&alloc);
+    let mut test_mode = TestModeClass::default();
+    let mut usb_dev = test_class.make_device(&alloc);
+    let mut device = || {
+        usb_dev.poll(&mut [&mut test_class, &mut test_mode]);
+        if let Some(mode) = test_mode.requested.take() {
+            usb_dev.bus().set_test_mode_after_status(mode);
+        }
+        test_class.poll();
+    };
+
+    let mut host = SimHost::new(&core);
+    host.enumerate(&mut device, ADDRESS).unwrap();
+
+    // SET_FEATURE(TEST_MODE) with the Test_Packet selector
+    let set_feature = SimHost::setup_packet(0x00, 0x03, TEST_MODE, 4 << 8, 0);
+    core.setup_token(0, &set_feature).unwrap();
+    device();
+    assert_eq!(core.register(DCTL) & DCTL_TCTL, 0);
+
+    // The test mode is entered once the status stage completed
+    assert!(host.in_packet(&mut device, 0).unwrap().is_empty());
+    assert_eq!(core.register(DCTL) & DCTL_TCTL, 0b100 << 4);
+}